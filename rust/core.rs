@@ -1,45 +1,89 @@
 use std::rc::Rc;
-use std::fs::File;
+use std::fs::{self,File};
 use std::io::Read;
 use std::sync::Mutex;
+use std::cmp::Ordering;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use regex::{Regex as Re,Captures};
 
-use types::{MalVal,MalArgs,MalRet,error,func,hash_map,_assoc,_dissoc,atom};
-use types::MalVal::{Nil,Bool,Int,Str,Sym,List,Vector,Hash,Func,MalFunc,Atom};
-use types::MalErr::{ErrMalVal};
-use reader::read_str;
+use types::{MalVal,MalList,MalSet,MalArgs,MalRet,MalErr,Name,error,func,hash_map,hash_set,
+            _assoc,_dissoc,_conj_set,_disj,atom,lazy_seq,lazy_step,lazy_cons};
+use types::MalVal::{Nil,Bool,Int,Bignum,Ratio,Float,Str,Keyword,Sym,Regex,List,Vector,Hash,Set,LazySeq,Func,MalFunc,Atom};
+use types::MalErr::{ErrString,ErrMalVal};
+use reader::{read_str,read_all,read_cst,cst_str,set_tag_reader,set_reader_macro};
 use printer::pr_seq;
+use numeric;
+use json;
+use serialize;
+
+// (op a b c) is (op (op a b) c) and (op x) is (op $id x), so (- x)
+// negates x. With no args, ops that allow it give $id
+macro_rules! fn_num_op {
+  ($name:expr, $fn:path, $id:expr, $nullary:expr) => {{
+    |a:MalArgs| {
+      match a.len() {
+        0 if $nullary => Ok(Int($id)),
+        0 => error(concat!($name, ": wrong number of args (0)")),
+        1 => $fn(&Int($id), &a[0]),
+        _ => a[1..].iter().try_fold(a[0].clone(), |acc, x| $fn(&acc, x)),
+      }
+    }
+  }};
+}
 
-macro_rules! fn_t_int_int {
-  ($ret:ident, $fn:expr) => {{
+// true when each arg compares to the next as one of the orderings given,
+// as in (< a b c)
+macro_rules! fn_num_cmp {
+  ($name:expr, $($ord:ident),*) => {{
     |a:MalArgs| {
-      match (a[0].clone(), a[1].clone()) {
-        (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-        _ => error("expecting (int,int) args"),
+      match a.len() {
+        0 => return error(concat!($name, ": wrong number of args (0)")),
+        1 => { numeric::compare($name, &a[0], &a[0])?; },
+        _ => (),
+      }
+      // every pair is compared, so that an argument that isn't a number
+      // is an error even after the answer is known
+      let mut res = true;
+      for w in a.windows(2) {
+        match numeric::compare($name, &w[0], &w[1])? {
+          $(Some(Ordering::$ord))|* => (),
+          _ => res = false,
+        }
       }
+      Ok(Bool(res))
     }
   }};
 }
 
 macro_rules! fn_is_type {
-  ($($ps:pat),*) => {{
-    |a:MalArgs| { Ok(Bool(match a[0] { $($ps => true,)* _ => false})) }
+  ($name:expr, $($ps:pat),*) => {{
+    |a:MalArgs| {
+      arity($name, &a, 1, 1)?;
+      Ok(Bool(match a[0] { $($ps => true,)* _ => false}))
+    }
   }};
-  ($p:pat if $e:expr) => {{
-    |a:MalArgs| { Ok(Bool(match a[0] { $p if $e => true, _ => false})) }
+  ($name:expr, $p:pat if $e:expr) => {{
+    |a:MalArgs| {
+      arity($name, &a, 1, 1)?;
+      Ok(Bool(match a[0] { $p if $e => true, _ => false}))
+    }
   }};
-  ($p:pat if $e:expr,$($ps:pat),*) => {{
-    |a:MalArgs| { Ok(Bool(match a[0] { $p if $e => true, $($ps => true,)* _ => false})) }
+  ($name:expr, $p:pat if $e:expr,$($ps:pat),*) => {{
+    |a:MalArgs| {
+      arity($name, &a, 1, 1)?;
+      Ok(Bool(match a[0] { $p if $e => true, $($ps => true,)* _ => false}))
+    }
   }};
 }
 
 macro_rules! fn_str {
-  ($fn:expr) => {{
+  ($name:expr, $fn:expr) => {{
     |a:MalArgs| {
+      arity($name, &a, 1, 1)?;
       match a[0].clone() {
         Str(a0) => $fn(a0),
         _ => error("expecting (str) arg"),
@@ -48,9 +92,52 @@ macro_rules! fn_str {
   }};
 }
 
+// an error unless a fn got from min to max args
+fn arity(name: &str, a: &MalArgs, min: usize, max: usize) -> Result<(),MalErr> {
+  if a.len() < min || a.len() > max {
+    return Err(ErrString(format!("{}: wrong number of args ({})", name, a.len())));
+  }
+  Ok(())
+}
+
+// (= a b c) when all the args are equal
+fn equal_q(a: MalArgs) -> MalRet {
+  if a.is_empty() {
+    return error("=: wrong number of args (0)");
+  }
+  Ok(Bool(a.windows(2).all(|w| w[0] == w[1])))
+}
+
+// (identical? a b) when a and b are the same object. Symbols and keywords
+// are interned, so two with the same name always are; nil, booleans,
+// ints, floats and strings are copied around, so they are when equal
+fn identical_q(a: MalArgs) -> MalRet {
+  arity("identical?", &a, 2, 2)?;
+  Ok(Bool(match (&a[0], &a[1]) {
+    (Nil, Nil) => true,
+    (Bool(x), Bool(y)) => x == y,
+    (Int(x), Int(y)) => x == y,
+    (Float(x), Float(y)) => x.to_bits() == y.to_bits(),
+    (Str(x), Str(y)) => x == y,
+    (Keyword(x), Keyword(y)) | (Sym(x), Sym(y)) => x == y,
+    (Bignum(x), Bignum(y)) => Rc::ptr_eq(x, y),
+    (Ratio(x), Ratio(y)) => Rc::ptr_eq(x, y),
+    (Regex(x), Regex(y)) => Rc::ptr_eq(x, y),
+    (List(x,xm), List(y,ym)) | (Vector(x,xm), Vector(y,ym)) =>
+      Rc::ptr_eq(x, y) && Rc::ptr_eq(xm, ym),
+    (Hash(x,xm), Hash(y,ym)) => Rc::ptr_eq(x, y) && Rc::ptr_eq(xm, ym),
+    (Set(x,xm), Set(y,ym)) => Rc::ptr_eq(x, y) && Rc::ptr_eq(xm, ym),
+    (LazySeq(x,xm), LazySeq(y,ym)) => Rc::ptr_eq(x, y) && Rc::ptr_eq(xm, ym),
+    (Func(_,x,xm), Func(_,y,ym)) | (MalFunc{info: x, meta: xm, ..}, MalFunc{info: y, meta: ym, ..}) =>
+      Rc::ptr_eq(x, y) && Rc::ptr_eq(xm, ym),
+    (Atom(x), Atom(y)) => Rc::ptr_eq(x, y),
+    _ => false,
+  }))
+}
+
 fn symbol(a: MalArgs) -> MalRet {
   match a[0] {
-    Str(ref s) => Ok(Sym(s.to_string())),
+    Str(ref s) => Ok(Sym(Name::new(s))),
     _ => error("illegal symbol call")
   }
 }
@@ -74,6 +161,162 @@ fn readline(a: MalArgs) -> MalRet {
   }
 }
 
+fn set_tag_reader_bang(a: MalArgs) -> MalRet {
+  arity("set-tag-reader!", &a, 2, 2)?;
+  match (&a[0], &a[1]) {
+    (Sym(ref tag), f @ Func(..)) | (Sym(ref tag), f @ MalFunc{..}) => {
+      set_tag_reader(tag, f.clone());
+      Ok(Nil)
+    },
+    (Str(ref tag), f @ Func(..)) | (Str(ref tag), f @ MalFunc{..}) => {
+      set_tag_reader(tag, f.clone());
+      Ok(Nil)
+    },
+    _ => error("set-tag-reader!: expecting (tag, fn) args"),
+  }
+}
+
+fn set_reader_macro_bang(a: MalArgs) -> MalRet {
+  arity("set-reader-macro!", &a, 2, 2)?;
+  match (&a[0], &a[1]) {
+    (Str(ref s), f @ Func(..)) | (Str(ref s), f @ MalFunc{..}) |
+    (Str(ref s), f @ Nil) => set_reader_macro(s, f.clone()),
+    _ => error("set-reader-macro!: expecting (str, fn) args"),
+  }
+}
+
+fn regex(p: &str) -> Result<Re,MalErr> {
+  Re::new(p).map_err(|e| ErrString(format!("invalid regex: {}", e)))
+}
+
+// the whole match, or a vector of it and each group when there are groups
+fn re_groups(caps: Captures) -> MalVal {
+  let str_or_nil = |m: Option<::regex::Match>| m.map_or(Nil, |m| Str(m.as_str().to_string()));
+  if caps.len() == 1 {
+    str_or_nil(caps.get(0))
+  } else {
+    vector!(caps.iter().map(str_or_nil).collect())
+  }
+}
+
+fn re_pattern(a: MalArgs) -> MalRet {
+  arity("re-pattern", &a, 1, 1)?;
+  match a[0] {
+    Str(ref p) => Ok(Regex(Rc::new(regex(p)?))),
+    Regex(_)   => Ok(a[0].clone()),
+    _ => error("re-pattern: expecting a str"),
+  }
+}
+
+// the pattern of a regex or str arg, anchored at both ends for re-matches
+fn re_arg(name: &str, p: &MalVal, anchored: bool) -> Result<Rc<Re>,MalErr> {
+  let p = match p {
+    Regex(re) if !anchored => return Ok(re.clone()),
+    Regex(re) => re.as_str(),
+    Str(p)    => p,
+    _ => return Err(ErrString(format!("{}: expecting (regex,str) args", name))),
+  };
+  let re = if anchored { regex(&format!("^(?:{})$", p))? } else { regex(p)? };
+  Ok(Rc::new(re))
+}
+
+fn re_find(a: MalArgs) -> MalRet {
+  arity("re-find", &a, 2, 2)?;
+  let re = re_arg("re-find", &a[0], false)?;
+  match a[1] {
+    Str(ref s) => Ok(re.captures(s).map_or(Nil, re_groups)),
+    _ => error("re-find: expecting (regex,str) args"),
+  }
+}
+
+fn re_matches(a: MalArgs) -> MalRet {
+  arity("re-matches", &a, 2, 2)?;
+  let re = re_arg("re-matches", &a[0], true)?;
+  match a[1] {
+    Str(ref s) => Ok(re.captures(s).map_or(Nil, re_groups)),
+    _ => error("re-matches: expecting (regex,str) args"),
+  }
+}
+
+// printing realizes lazy seqs, which can fail
+fn pr_args(a: &MalArgs, print_readably: bool, join: &str) -> Result<String,MalErr> {
+  for x in a.iter() { x.realize()? }
+  Ok(pr_seq(a, print_readably, "", "", join))
+}
+
+// (pprint-str x) or (pprint-str x width)
+fn pprint_str(name: &str, a: MalArgs) -> Result<String,MalErr> {
+  arity(name, &a, 1, 2)?;
+  for x in a.iter() { x.realize()? }
+  match a.get(1) {
+    None                   => Ok(a[0].pprint(80)),
+    Some(Int(w)) if *w > 0 => Ok(a[0].pprint(*w as usize)),
+    Some(w) => Err(ErrString(format!("{}: width must be a positive int, got {}",
+                                     name, w.pr_str(true)))),
+  }
+}
+
+fn opt_str(s: &Option<String>) -> MalVal {
+  match s {
+    Some(s) => Str(s.to_string()),
+    None    => Nil,
+  }
+}
+
+// the parameters of a function, as a list of one vector
+fn arglists(a: MalArgs) -> MalRet {
+  arity("arglists", &a, 1, 1)?;
+  match a[0] {
+    MalFunc{ref params, ..} => match **params {
+      List(ref l,_) | Vector(ref l,_) => Ok(list!(MalList::unit(vector!((**l).clone())))),
+      _ => Ok(Nil),
+    },
+    _ => a[0].fn_info().map(|_| Nil),
+  }
+}
+
+// (json-decode s) or (json-decode s {:keywordize true})
+fn json_decode(a: MalArgs) -> MalRet {
+  arity("json-decode", &a, 1, 2)?;
+  let keywordize = match a.get(1) {
+    None | Some(Nil) => false,
+    Some(Hash(ref hm,_)) => match hm.get(&Keyword(Name::new("keywordize"))) {
+      None | Some(Nil) | Some(Bool(false)) => false,
+      _ => true,
+    },
+    _ => return error("json-decode: options must be a hash-map"),
+  };
+  match a[0] {
+    Str(ref s) => json::decode(s, keywordize),
+    _ => error("json-decode: argument must be a string"),
+  }
+}
+
+// (serialize x path) writes x to a file, to be read back by (deserialize
+// path)
+fn serialize_to(a: MalArgs) -> MalRet {
+  match (a.get(0), a.get(1)) {
+    (Some(mv), Some(Str(path))) => {
+      let data = serialize::encode(mv)?;
+      match fs::write(path, data) {
+        Ok(_)  => Ok(Nil),
+        Err(e) => error(&format!("serialize: {}: {}", path, e)),
+      }
+    },
+    _ => error("serialize: expected a value and a file path"),
+  }
+}
+
+fn deserialize_from(a: MalArgs) -> MalRet {
+  match a.get(0) {
+    Some(Str(path)) => match fs::read(path) {
+      Ok(data) => serialize::decode(&data),
+      Err(e)   => error(&format!("deserialize: {}: {}", path, e)),
+    },
+    _ => error("deserialize: expected a file path"),
+  }
+}
+
 fn slurp(f: String) -> MalRet {
   let mut s = String::new();
   match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
@@ -94,8 +337,8 @@ fn time_ms(_a: MalArgs) -> MalRet {
 fn get(a: MalArgs) -> MalRet {
   match (a[0].clone(), a[1].clone()) {
     (Nil, _) => Ok(Nil),
-    (Hash(ref hm,_), Str(ref s)) => {
-      match hm.get(s) {
+    (Hash(ref hm,_), ref k) => {
+      match hm.get(k) {
         Some(mv) => Ok(mv.clone()),
         None     => Ok(Nil),
       }
@@ -120,17 +363,80 @@ fn dissoc(a: MalArgs) -> MalRet {
 
 fn contains_q(a: MalArgs) -> MalRet {
   match (a[0].clone(), a[1].clone()) {
-    (Hash(ref hm,_), Str(ref s)) => {
-      Ok(Bool(hm.contains_key(s)))
+    (Hash(ref hm,_), ref k) => {
+      Ok(Bool(hm.contains_key(k)))
     },
+    (Set(ref hs,_), ref k) => Ok(Bool(hs.contains(k))),
     _ => error("illegal get args")
   }
 }
 
+fn set(a: MalArgs) -> MalRet {
+  arity("set", &a, 1, 1)?;
+  match a[0] {
+    List(ref v,_) | Vector(ref v,_) => hash_set(v.iter().cloned().collect()),
+    Set(ref hs,_) => Ok(Set(hs.clone(),Rc::new(Nil))),
+    Nil => hash_set(vec![]),
+    LazySeq(..) => hash_set(a[0].seq_iter().collect::<Result<Vec<MalVal>,MalErr>>()?),
+    _ => error("set: called with non-seq"),
+  }
+}
+
+fn disj(a: MalArgs) -> MalRet {
+  arity("disj", &a, 1, usize::MAX)?;
+  match a[0] {
+    Set(ref hs,_) => _disj((**hs).clone(), a[1..].to_vec()),
+    _ => error("disj on non-Set")
+  }
+}
+
+// the args of union, intersection and difference, which must all be sets
+fn sets<'a>(name: &str, a: &'a MalArgs) -> Result<Vec<&'a MalSet>,MalErr> {
+  a.iter().map(|mv| match mv {
+    Set(ref hs,_) => Ok(&**hs),
+    _ => Err(ErrString(format!("{}: expecting set args, got {}",
+                               name, mv.pr_str(true)))),
+  }).collect()
+}
+
+fn union(a: MalArgs) -> MalRet {
+  let mut res = MalSet::default();
+  for hs in sets("union", &a)? {
+    for x in hs.iter() { res.insert(x.clone()) }
+  }
+  Ok(Set(Rc::new(res),Rc::new(Nil)))
+}
+
+fn intersection(a: MalArgs) -> MalRet {
+  let sets = sets("intersection", &a)?;
+  let (first, rest) = match sets.split_first() {
+    Some(fr) => fr,
+    None     => return error("intersection: wrong number of args (0)"),
+  };
+  let mut res = MalSet::default();
+  for x in first.iter().filter(|x| rest.iter().all(|hs| hs.contains(x))) {
+    res.insert(x.clone());
+  }
+  Ok(Set(Rc::new(res),Rc::new(Nil)))
+}
+
+fn difference(a: MalArgs) -> MalRet {
+  let sets = sets("difference", &a)?;
+  let (first, rest) = match sets.split_first() {
+    Some(fr) => fr,
+    None     => return error("difference: wrong number of args (0)"),
+  };
+  let mut res = (*first).clone();
+  for hs in rest {
+    for x in hs.iter() { res.remove(x) }
+  }
+  Ok(Set(Rc::new(res),Rc::new(Nil)))
+}
+
 fn keys(a: MalArgs) -> MalRet {
   match a[0] {
     Hash(ref hm,_) => {
-      Ok(list!(hm.keys().map(|k|{Str(k.to_string())}).collect()))
+      Ok(list!(hm.keys().cloned().collect()))
     },
     _ => error("keys requires Hash Map")
   }
@@ -148,27 +454,52 @@ fn vals(a: MalArgs) -> MalRet {
 fn cons(a: MalArgs) -> MalRet {
   match a[1].clone() {
     List(v,_) | Vector(v,_) => {
-      let mut new_v = vec![a[0].clone()];
-      new_v.extend_from_slice(&v);
-      Ok(list!(new_v.to_vec()))
+      let mut new_v = (*v).clone();
+      new_v.push_front(a[0].clone());
+      Ok(list!(new_v))
     },
+    LazySeq(..) => Ok(lazy_cons(a[0].clone(), a[1].clone())),
+    Nil => Ok(list!(MalList::unit(a[0].clone()))),
     _ => error("cons expects seq as second arg"),
   }
 }
 
+// lazy if any of the seqs is, so that an endless seq can be concatenated
 fn concat(a: MalArgs) -> MalRet {
-  let mut new_v = vec![];
+  if a.iter().any(|x| match x { LazySeq(..) => true, _ => false }) {
+    return Ok(lazy_concat(a.into()));
+  }
+  let mut new_v = MalList::new();
   for seq in a.iter() {
     match seq {
-      List(v,_) | Vector(v,_) => new_v.extend_from_slice(v),
+      List(v,_) | Vector(v,_) => new_v.append((**v).clone()),
       _ => return error("non-seq passed to concat"),
     }
   }
-  Ok(list!(new_v.to_vec()))
+  Ok(list!(new_v))
+}
+
+fn lazy_concat(mut seqs: MalList) -> MalVal {
+  let s = seqs.pop_front().unwrap_or(Nil);
+  lazy_step(s, move |first| match first {
+    Some((x, rest)) => {
+      let mut seqs = seqs.clone();
+      seqs.push_front(rest);
+      Ok(lazy_cons(x, lazy_concat(seqs)))
+    },
+    None if seqs.is_empty() => Ok(Nil),
+    None => Ok(lazy_concat(seqs.clone())),
+  })
 }
 
 fn nth(a: MalArgs) -> MalRet {
   match (a[0].clone(), a[1].clone()) {
+    (LazySeq(..), Int(idx)) if idx >= 0 => {
+      match a[0].seq_iter().nth(idx as usize) {
+        Some(x) => x,
+        None    => error("nth: index out of range"),
+      }
+    },
     (List(seq,_), Int(idx)) | (Vector(seq,_), Int(idx)) => {
       if seq.len() <= idx as usize { 
         return error("nth: index out of range");
@@ -183,6 +514,7 @@ fn first(a: MalArgs) -> MalRet {
   match a[0].clone() {
     List(ref seq,_) | Vector(ref seq,_) if seq.len() == 0 => Ok(Nil),
     List(ref seq,_) | Vector(ref seq,_) => Ok(seq[0].clone()),
+    LazySeq(..) => Ok(a[0].uncons()?.map_or(Nil, |(x, _)| x)),
     Nil => Ok(Nil),
     _ => error("invalid args to first"),
   }
@@ -192,11 +524,15 @@ fn rest(a: MalArgs) -> MalRet {
   match a[0].clone() {
     List(ref seq,_) | Vector(ref seq,_) => {
       if seq.len() > 1 {
-        Ok(list!(seq[1..].to_vec()))
+        Ok(list!(seq.skip(1)))
       } else {
         Ok(list![])
       }
     },
+    LazySeq(..) => match a[0].uncons()? {
+      Some((_, Nil)) | None => Ok(list![]),
+      Some((_, rest))       => Ok(rest),
+    },
     Nil => Ok(list![]),
     _ => error("invalid args to first"),
   }
@@ -207,7 +543,13 @@ fn apply(a: MalArgs) -> MalRet {
     List(ref v,_) | Vector(ref v,_) => {
       let f = &a[0];
       let mut fargs = a[1..a.len()-1].to_vec();
-      fargs.extend_from_slice(&v);
+      fargs.extend(v.iter().cloned());
+      f.apply(fargs)
+    },
+    LazySeq(..) => {
+      let f = &a[0];
+      let mut fargs = a[1..a.len()-1].to_vec();
+      for x in a[a.len()-1].seq_iter() { fargs.push(x?) }
       f.apply(fargs)
     },
     _ => error("apply called with non-seq"),
@@ -215,90 +557,293 @@ fn apply(a: MalArgs) -> MalRet {
 }
 
 fn map(a: MalArgs) -> MalRet {
-  match a[1] {
-    List(ref v,_) | Vector(ref v,_) => {
-      let mut res = vec![];
+  if let LazySeq(..) = a[1] {
+    // lazily, over a lazy seq
+    return Ok(lazy_map(a[0].clone(), a[1].clone()));
+  }
+  match a[1].elements() {
+    Some(v) => {
+      let mut res = MalList::new();
       for mv in v.iter() {
-        res.push(a[0].apply(vec![mv.clone()])?)
+        res.push_back(a[0].apply(vec![mv.clone()])?)
       }
       Ok(list!(res))
     },
-    _ => error("map called with non-seq"),
+    None => error("map called with non-seq"),
   }
 }
 
+fn lazy_map(f: MalVal, s: MalVal) -> MalVal {
+  lazy_step(s, move |first| match first {
+    Some((x, rest)) => Ok(lazy_cons(f.apply(vec![x])?, lazy_map(f.clone(), rest))),
+    None            => Ok(Nil),
+  })
+}
+
 fn conj(a: MalArgs) -> MalRet {
   match a[0] {
     List(ref v,_) => {
-      let sl = a[1..].iter().rev().map(|a|{a.clone()}).collect::<Vec<MalVal>>();
-      Ok(list!([&sl[..],v].concat()))
+      let mut new_v = (**v).clone();
+      for mv in a[1..].iter() { new_v.push_front(mv.clone()) }
+      Ok(list!(new_v))
+    },
+    Vector(ref v,_) => {
+      let mut new_v = (**v).clone();
+      for mv in a[1..].iter() { new_v.push_back(mv.clone()) }
+      Ok(vector!(new_v))
+    },
+    Set(ref hs,_) => _conj_set((**hs).clone(), a[1..].to_vec()),
+    LazySeq(..) => {
+      Ok(a[1..].iter().fold(a[0].clone(), |s, x| lazy_cons(x.clone(), s)))
     },
-    Vector(ref v,_) => Ok(vector!([v,&a[1..]].concat())),
     _ => error("conj: called with non-seq"),
   }
 }
 
 fn seq(a: MalArgs) -> MalRet {
   match a[0] {
-    List(ref v,_) | Vector(ref v,_) if v.len() == 0 => Ok(Nil),
-    List(ref v,_) | Vector(ref v,_) => Ok(list!(v.to_vec())),
-    Str(ref s) if s.len() == 0 => Ok(Nil),
-    Str(ref s) if !a[0].keyword_q() => {
-      Ok(list!(s.chars().map(|c|{Str(c.to_string())}).collect()))
-    },
+    // realizing only its first element
+    LazySeq(..) if a[0].uncons()?.is_none() => Ok(Nil),
+    LazySeq(..) => Ok(a[0].clone()),
     Nil => Ok(Nil),
-    _ => error("seq: called with non-seq"),
+    _ => match a[0].elements() {
+      Some(ref v) if v.is_empty() => Ok(Nil),
+      Some(v) => Ok(list!(v)),
+      None    => error("seq: called with non-seq"),
+    },
+  }
+}
+
+// (range), (range end), (range start end) or (range start end step): the
+// numbers from start, 0 by default, up to but not including end, by step, 1
+// by default. Without an end it never ends
+fn range(a: MalArgs) -> MalRet {
+  let (start, end, step) = match a.len() {
+    0 => (Int(0), None, Int(1)),
+    1 => (Int(0), Some(a[0].clone()), Int(1)),
+    2 => (a[0].clone(), Some(a[1].clone()), Int(1)),
+    3 => (a[0].clone(), Some(a[1].clone()), a[2].clone()),
+    n => return error(&format!("range: wrong number of args ({})", n)),
+  };
+  if let Some(x) = a.iter().find(|x| !x.number_q()) {
+    return error(&format!("range: expecting numbers, got {}", x.pr_str(true)));
+  }
+  Ok(range_from(start, end, step))
+}
+
+fn range_from(start: MalVal, end: Option<MalVal>, step: MalVal) -> MalVal {
+  lazy_seq(move || {
+    if let Some(ref end) = end {
+      let down = numeric::compare("range", &step, &Int(0))? == Some(Ordering::Less);
+      let done = match numeric::compare("range", &start, end)? {
+        Some(Ordering::Less) => down,
+        Some(Ordering::Greater) => !down,
+        _ => true,
+      };
+      if done { return Ok(Nil) }
+    }
+    let next = numeric::add(&start, &step)?;
+    Ok(lazy_cons(start.clone(), range_from(next, end.clone(), step.clone())))
+  })
+}
+
+// (iterate f x) is x, (f x), (f (f x)) and so on
+fn iterate(a: MalArgs) -> MalRet {
+  match (a.get(0), a.get(1)) {
+    (Some(f), Some(x)) => Ok(iterate_from(f.clone(), x.clone())),
+    _ => error("iterate: expecting a function and a value"),
   }
 }
 
+fn iterate_from(f: MalVal, x: MalVal) -> MalVal {
+  let next = x.clone();
+  lazy_cons(x, lazy_seq(move || {
+    Ok(iterate_from(f.clone(), f.apply(vec![next.clone()])?))
+  }))
+}
+
+// (repeat x) is x without end, (repeat n x) is n of them
+fn repeat(a: MalArgs) -> MalRet {
+  match (a.get(0), a.get(1)) {
+    (Some(x), None)         => Ok(repeat_from(x.clone())),
+    (Some(&Int(n)), Some(x)) => Ok(take_from(n, repeat_from(x.clone()))),
+    _ => error("repeat: expecting a value, or a count and a value"),
+  }
+}
+
+fn repeat_from(x: MalVal) -> MalVal {
+  lazy_seq(move || Ok(lazy_cons(x.clone(), repeat_from(x.clone()))))
+}
+
+// the elements of a seq over and over
+fn cycle(a: MalArgs) -> MalRet {
+  match a.get(0) {
+    Some(s) => Ok(cycle_from(s.clone(), s.clone())),
+    None    => error("cycle: expecting a seq"),
+  }
+}
+
+fn cycle_from(coll: MalVal, s: MalVal) -> MalVal {
+  lazy_step(s, move |first| {
+    let (x, rest) = match first {
+      Some(xr) => xr,
+      None     => match coll.uncons()? {
+        Some(xr) => xr,
+        None     => return Ok(Nil),
+      },
+    };
+    Ok(lazy_cons(x, cycle_from(coll.clone(), rest)))
+  })
+}
+
+fn take(a: MalArgs) -> MalRet {
+  match (a.get(0), a.get(1)) {
+    (Some(&Int(n)), Some(s)) => Ok(take_from(n, s.clone())),
+    _ => error("take: expecting a count and a seq"),
+  }
+}
+
+fn take_from(n: i64, s: MalVal) -> MalVal {
+  if n <= 0 { return lazy_seq(|| Ok(Nil)) }
+  lazy_step(s, move |first| match first {
+    Some((x, rest)) => Ok(lazy_cons(x, take_from(n - 1, rest))),
+    None            => Ok(Nil),
+  })
+}
+
+fn drop(a: MalArgs) -> MalRet {
+  match (a.get(0), a.get(1)) {
+    (Some(&Int(n)), Some(s)) => Ok(drop_from(n, s.clone())),
+    _ => error("drop: expecting a count and a seq"),
+  }
+}
+
+fn drop_from(n: i64, s: MalVal) -> MalVal {
+  if n <= 0 { return lazy_seq(move || Ok(s.clone())) }
+  lazy_step(s, move |first| match first {
+    Some((_, rest)) => Ok(drop_from(n - 1, rest)),
+    None            => Ok(Nil),
+  })
+}
+
+fn take_while(a: MalArgs) -> MalRet {
+  match (a.get(0), a.get(1)) {
+    (Some(pred), Some(s)) => Ok(take_while_from(pred.clone(), s.clone())),
+    _ => error("take-while: expecting a predicate and a seq"),
+  }
+}
+
+fn take_while_from(pred: MalVal, s: MalVal) -> MalVal {
+  lazy_step(s, move |first| match first {
+    Some((x, rest)) => match pred.apply(vec![x.clone()])? {
+      Nil | Bool(false) => Ok(Nil),
+      _ => Ok(lazy_cons(x, take_while_from(pred.clone(), rest))),
+    },
+    None => Ok(Nil),
+  })
+}
+
+fn drop_while(a: MalArgs) -> MalRet {
+  match (a.get(0), a.get(1)) {
+    (Some(pred), Some(s)) => Ok(drop_while_from(pred.clone(), s.clone())),
+    _ => error("drop-while: expecting a predicate and a seq"),
+  }
+}
+
+fn drop_while_from(pred: MalVal, s: MalVal) -> MalVal {
+  lazy_step(s, move |first| match first {
+    Some((x, rest)) => match pred.apply(vec![x.clone()])? {
+      Nil | Bool(false) => Ok(lazy_cons(x, rest)),
+      _ => Ok(drop_while_from(pred.clone(), rest)),
+    },
+    None => Ok(Nil),
+  })
+}
+
 pub fn ns() -> Vec<(&'static str, MalVal)> {
   vec![
-    ("=",        func(|a|{Ok(Bool(a[0] == a[1]))})),
+    ("=",        func(equal_q)),
+    ("identical?", func(identical_q)),
     ("throw",    func(|a|{Err(ErrMalVal(a[0].clone()))})),
 
-    ("nil?",     func(fn_is_type!(Nil))),
-    ("true?",    func(fn_is_type!(Bool(true)))),
-    ("false?",   func(fn_is_type!(Bool(false)))),
+    ("nil?",     func(fn_is_type!("nil?", Nil))),
+    ("true?",    func(fn_is_type!("true?", Bool(true)))),
+    ("false?",   func(fn_is_type!("false?", Bool(false)))),
     ("symbol",   func(symbol)),
-    ("symbol?",  func(fn_is_type!(Sym(_)))),
-    ("string?",  func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}")))),
+    ("symbol?",  func(fn_is_type!("symbol?", Sym(_)))),
+    ("string?",  func(fn_is_type!("string?", Str(_)))),
     ("keyword",  func(|a|{a[0].keyword()})),
-    ("keyword?", func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}")))),
-    ("number?",  func(fn_is_type!(Int(_)))),
-    ("fn?",      func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_)))),
-    ("macro?",   func(fn_is_type!(MalFunc{is_macro,..} if is_macro))),
-
-    ("pr-str",   func(|a|Ok(Str(pr_seq(&a, true, "", "", " "))))),
-    ("str",      func(|a|Ok(Str(pr_seq(&a, false, "", "", ""))))),
-    ("prn",      func(|a|{println!("{}", pr_seq(&a, true, "", "", " ")); Ok(Nil)})),
-    ("println",  func(|a|{println!("{}", pr_seq(&a, false, "", "", " ")); Ok(Nil)})),
-    ("read-string", func(fn_str!(|s|{read_str(s)}))),
+    ("keyword?", func(fn_is_type!("keyword?", Keyword(_)))),
+    ("number?",  func(fn_is_type!("number?", Int(_),Bignum(_),Ratio(_),Float(_)))),
+    ("int?",     func(fn_is_type!("int?", Int(_),Bignum(_)))),
+    ("ratio?",   func(fn_is_type!("ratio?", Ratio(_)))),
+    ("float?",   func(fn_is_type!("float?", Float(_)))),
+    ("fn?",      func(fn_is_type!("fn?", MalFunc{is_macro,..} if !is_macro,Func(..)))),
+    ("macro?",   func(fn_is_type!("macro?", MalFunc{is_macro,..} if is_macro))),
+
+    ("pr-str",   func(|a|Ok(Str(pr_args(&a, true, " ")?)))),
+    ("str",      func(|a|Ok(Str(pr_args(&a, false, "")?)))),
+    ("prn",      func(|a|{println!("{}", pr_args(&a, true, " ")?); Ok(Nil)})),
+    ("println",  func(|a|{println!("{}", pr_args(&a, false, " ")?); Ok(Nil)})),
+    ("pprint",   func(|a|{println!("{}", pprint_str("pprint", a)?); Ok(Nil)})),
+    ("pprint-str", func(|a|Ok(Str(pprint_str("pprint-str", a)?)))),
+    ("json-encode", func(|a|{
+      arity("json-encode", &a, 1, 1)?;
+      Ok(Str(json::encode(&a[0])?))
+    })),
+    ("json-decode", func(json_decode)),
+    ("serialize", func(serialize_to)),
+    ("deserialize", func(deserialize_from)),
+    ("read-string", func(fn_str!("read-string", |s|{read_str(s)}))),
+    ("read-all", func(fn_str!("read-all", |s|{
+      Ok(list!(read_all(s)?.into_iter().map(|(mv,_)|{mv}).collect()))
+    }))),
+    ("read-cst", func(fn_str!("read-cst", |s: String|{
+      Ok(vector!(read_cst(&s)?.iter().map(|c|{c.to_mal()}).collect()))
+    }))),
+    ("cst-str", func(|a|{
+      arity("cst-str", &a, 1, 1)?;
+      Ok(Str(cst_str(&a[0])?))
+    })),
+    ("set-tag-reader!", func(set_tag_reader_bang)),
+    ("set-reader-macro!", func(set_reader_macro_bang)),
     ("readline", func(readline)),
-    ("slurp",    func(fn_str!(|f|{slurp(f)}))),
-
-    ("<",  func(fn_t_int_int!(Bool,|i,j|{i<j}))),
-    ("<=", func(fn_t_int_int!(Bool,|i,j|{i<=j}))),
-    (">",  func(fn_t_int_int!(Bool,|i,j|{i>j}))),
-    (">=", func(fn_t_int_int!(Bool,|i,j|{i>=j}))),
-    ("+",  func(fn_t_int_int!(Int,|i,j|{i+j}))),
-    ("-",  func(fn_t_int_int!(Int,|i,j|{i-j}))),
-    ("*",  func(fn_t_int_int!(Int,|i,j|{i*j}))),
-    ("/",  func(fn_t_int_int!(Int,|i,j|{i/j}))),
+    ("slurp",    func(fn_str!("slurp", |f|{slurp(f)}))),
+
+    ("<",  func(fn_num_cmp!("<", Less))),
+    ("<=", func(fn_num_cmp!("<=", Less, Equal))),
+    (">",  func(fn_num_cmp!(">", Greater))),
+    (">=", func(fn_num_cmp!(">=", Greater, Equal))),
+    ("+",  func(fn_num_op!("+", numeric::add, 0, true))),
+    ("-",  func(fn_num_op!("-", numeric::sub, 0, false))),
+    ("*",  func(fn_num_op!("*", numeric::mul, 1, true))),
+    ("/",  func(fn_num_op!("/", numeric::div, 1, false))),
     ("time-ms", func(time_ms)),
 
-    ("sequential?", func(fn_is_type!(List(_,_),Vector(_,_)))),
-    ("list",     func(|a|{Ok(list!(a))})),
-    ("list?",    func(fn_is_type!(List(_,_)))),
-    ("vector",   func(|a|{Ok(vector!(a))})),
-    ("vector?",  func(fn_is_type!(Vector(_,_)))),
+    ("re-pattern", func(re_pattern)),
+    ("re-find",    func(re_find)),
+    ("re-matches", func(re_matches)),
+
+    ("sequential?", func(fn_is_type!("sequential?", List(_,_),Vector(_,_),LazySeq(_,_)))),
+    ("list",     func(|a|{Ok(list!(a.into()))})),
+    ("list?",    func(fn_is_type!("list?", List(_,_)))),
+    ("vector",   func(|a|{Ok(vector!(a.into()))})),
+    ("vector?",  func(fn_is_type!("vector?", Vector(_,_)))),
     ("hash-map", func(|a|{hash_map(a)})),
-    ("map?",     func(fn_is_type!(Hash(_,_)))),
+    ("map?",     func(fn_is_type!("map?", Hash(_,_)))),
     ("assoc",    func(assoc)),
     ("dissoc",   func(dissoc)),
     ("get",      func(get)),
     ("contains?", func(contains_q)),
     ("keys",     func(keys)),
     ("vals",     func(vals)),
+    ("hash-set", func(|a|{hash_set(a)})),
+    ("set",      func(set)),
+    ("set?",     func(fn_is_type!("set?", Set(_,_)))),
+    ("disj",     func(disj)),
+    ("union",    func(union)),
+    ("intersection", func(intersection)),
+    ("difference", func(difference)),
 
     ("cons",   func(cons)),
     ("concat", func(concat)),
@@ -313,14 +858,37 @@ pub fn ns() -> Vec<(&'static str, MalVal)> {
     ("conj",   func(conj)),
     ("seq",    func(seq)),
 
+    ("range",      func(range)),
+    ("iterate",    func(iterate)),
+    ("repeat",     func(repeat)),
+    ("cycle",      func(cycle)),
+    ("take",       func(take)),
+    ("drop",       func(drop)),
+    ("take-while", func(take_while)),
+    ("drop-while", func(drop_while)),
+
     ("meta",   func(|a|{a[0].get_meta()})),
     ("with-meta", func(|a|{a[0].clone().with_meta(&a[1])})),
     ("atom",   func(|a|{Ok(atom(&a[0]))})),
-    ("atom?",  func(fn_is_type!(Atom(_)))),
+    ("atom?",  func(fn_is_type!("atom?", Atom(_)))),
     ("deref",  func(|a|{a[0].deref()})),
     ("reset!", func(|a|{a[0].reset_bang(&a[1])})),
     ("swap!",  func(|a|{a[0].swap_bang(&a[1..].to_vec())})),
-  ]
+
+    ("fn-name",  func(|a|{
+      arity("fn-name", &a, 1, 1)?;
+      Ok(opt_str(&a[0].fn_info()?.name))
+    })),
+    ("arglists", func(arglists)),
+    ("doc",      func(|a|{
+      arity("doc", &a, 1, 1)?;
+      Ok(opt_str(&a[0].fn_info()?.doc))
+    })),
+    ("source",   func(|a|{
+      arity("source", &a, 1, 1)?;
+      Ok(opt_str(&a[0].fn_info()?.source))
+    })),
+  ].into_iter().map(|(k, f)| (k, f.named(k))).collect()
 }
 
 // vim: ts=2:sw=2:expandtab
@@ -3,13 +3,13 @@ use std::cell::RefCell;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
-use types::{MalVal,MalRet,MalErr,error};
+use types::{MalVal,MalRet,MalErr,Name,error};
 use types::MalVal::{Nil,Sym,List,Vector};
 use types::MalErr::{ErrString};
 
 #[derive(Debug)]
 pub struct EnvStruct {
-  data: RefCell<FnvHashMap<String,MalVal>>,
+  data: RefCell<FnvHashMap<Name,MalVal>>,
   pub outer: Option<Env>,
 }
 
@@ -31,7 +31,7 @@ pub fn env_bind(outer: Option<Env>, mbinds: MalVal,
       for (i, b) in binds.iter().enumerate() {
         match b {
           Sym(s) if s == "&" => {
-            env_set(&env, binds[i+1].clone(), list!(exprs[i..].to_vec()))?;
+            env_set(&env, binds[i+1].clone(), list!(exprs[i..].iter().cloned().collect()))?;
             break;
           },
           _ => {
@@ -45,7 +45,7 @@ pub fn env_bind(outer: Option<Env>, mbinds: MalVal,
   }
 }
 
-pub fn env_find(env: &Env, key: &str) -> Option<Env> {
+pub fn env_find(env: &Env, key: &Name) -> Option<Env> {
   match (env.data.borrow().contains_key(key), env.outer.clone()) {
     (true, _)        => Some(env.clone()),
     (false, Some(o)) => env_find(&o, key),
@@ -70,7 +70,7 @@ pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
   match key {
     Sym(ref s) => {
-      env.data.borrow_mut().insert(s.to_string(), val.clone());
+      env.data.borrow_mut().insert(s.clone(), val.clone());
       Ok(val)
     },
       _ => error("Env.set called with non-Str")
@@ -78,7 +78,7 @@ pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
 }
 
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
-  env.data.borrow_mut().insert(key.to_string(), val);
+  env.data.borrow_mut().insert(Name::new(key), val);
 }
 
 // vim: ts=2:sw=2:expandtab
@@ -0,0 +1,337 @@
+// JSON conversion for json-encode and json-decode: strings, numbers,
+// booleans and nil map to themselves, vectors and lists to arrays, and
+// hash-maps to objects
+
+use std::rc::Rc;
+use std::str::Chars;
+use std::iter::Peekable;
+use num_bigint::BigInt;
+
+use types::{MalVal,MalRet,MalErr,MalMap,Name};
+use types::MalVal::{Nil,Bool,Int,Bignum,Ratio,Float,Str,Keyword,Sym,Regex,List,Vector,Hash,Set,LazySeq,Func,MalFunc,Atom};
+use types::MalErr::ErrString;
+use numeric;
+
+// where in a document a value is, such as $.items[2].name
+fn path_str(path: &[String]) -> String {
+  format!("${}", path.concat())
+}
+
+fn key_segment(k: &str) -> String {
+  if !k.is_empty() && k.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+    format!(".{}", k)
+  } else {
+    format!("[{}]", quote(k))
+  }
+}
+
+fn quote(s: &str) -> String {
+  let mut res = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"'  => res.push_str("\\\""),
+      '\\' => res.push_str("\\\\"),
+      '\n' => res.push_str("\\n"),
+      '\r' => res.push_str("\\r"),
+      '\t' => res.push_str("\\t"),
+      '\u{8}' => res.push_str("\\b"),
+      '\u{c}' => res.push_str("\\f"),
+      c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+      c => res.push(c),
+    }
+  }
+  res.push('"');
+  res
+}
+
+pub fn encode(mv: &MalVal) -> Result<String,MalErr> {
+  let mut out = String::new();
+  encode_into(mv, &mut vec![], &mut out)?;
+  Ok(out)
+}
+
+fn encode_into(mv: &MalVal, path: &mut Vec<String>,
+               out: &mut String) -> Result<(),MalErr> {
+  match mv {
+    Nil         => out.push_str("null"),
+    Bool(b)     => out.push_str(if *b { "true" } else { "false" }),
+    Int(i)      => out.push_str(&i.to_string()),
+    Bignum(b)   => out.push_str(&b.to_string()),
+    Float(f) if !f.is_finite() => {
+      return Err(ErrString(format!("json-encode: cannot encode {} at {}",
+                                   f, path_str(path))));
+    },
+    Float(f)    => out.push_str(&format!("{:?}", f)),
+    Str(s)      => out.push_str(&quote(s)),
+    // keywords lose their colon, as JSON has no keywords
+    Keyword(k)  => out.push_str(&quote(k)),
+    List(l,_) | Vector(l,_) => encode_array(l.iter(), path, out)?,
+    // and sets become arrays, as JSON has no sets
+    Set(hs,_)   => encode_array(hs.iter(), path, out)?,
+    LazySeq(..) => {
+      let l = mv.seq_iter().collect::<Result<Vec<MalVal>,MalErr>>()?;
+      encode_array(l.iter(), path, out)?
+    },
+    Hash(hm,_)  => {
+      out.push('{');
+      for (i, (k, v)) in hm.iter().enumerate() {
+        let k: &str = match k {
+          Str(s)     => s,
+          Keyword(k) => k,
+          _ => return Err(ErrString(format!(
+                 "json-encode: cannot encode key {} at {}",
+                 k.pr_str(true), path_str(path)))),
+        };
+        if i > 0 { out.push(',') }
+        out.push_str(&quote(k));
+        out.push(':');
+        path.push(key_segment(k));
+        encode_into(v, path, out)?;
+        path.pop();
+      }
+      out.push('}');
+    },
+    Sym(_) | Ratio(_) | Regex(_) | Func(..) | MalFunc{..} | Atom(_) => {
+      let kind = match mv {
+        Sym(_)   => "symbol",
+        Ratio(_) => "ratio",
+        Regex(_) => "regex",
+        Atom(_)  => "atom",
+        _       => "function",
+      };
+      return Err(ErrString(format!("json-encode: cannot encode {} {} at {}",
+                                   kind, mv.pr_str(true), path_str(path))));
+    },
+  }
+  Ok(())
+}
+
+fn encode_array<'a, I>(items: I, path: &mut Vec<String>,
+                       out: &mut String) -> Result<(),MalErr>
+  where I: Iterator<Item=&'a MalVal> {
+  out.push('[');
+  for (i, x) in items.enumerate() {
+    if i > 0 { out.push(',') }
+    path.push(format!("[{}]", i));
+    encode_into(x, path, out)?;
+    path.pop();
+  }
+  out.push(']');
+  Ok(())
+}
+
+// arrays and objects nested deeper than this are refused rather than
+// decoded recursively until the stack runs out
+const MAX_DEPTH: usize = 512;
+
+struct Decoder<'a> {
+  chars: Peekable<Chars<'a>>,
+  path: Vec<String>,
+  keywordize: bool,
+}
+
+impl<'a> Decoder<'a> {
+  fn error<T>(&self, msg: &str) -> Result<T,MalErr> {
+    Err(ErrString(format!("json-decode: {} at {}", msg, path_str(&self.path))))
+  }
+
+  fn unexpected<T>(&mut self) -> Result<T,MalErr> {
+    match self.chars.peek() {
+      Some(&c) => self.error(&format!("unexpected {:?}", c)),
+      None     => self.error("unexpected end of input"),
+    }
+  }
+
+  fn skip_ws(&mut self) {
+    while let Some(&c) = self.chars.peek() {
+      if c != ' ' && c != '\t' && c != '\n' && c != '\r' { break }
+      self.chars.next();
+    }
+  }
+
+  fn expect(&mut self, word: &str) -> Result<(),MalErr> {
+    for c in word.chars() {
+      if self.chars.peek() != Some(&c) { return self.unexpected() }
+      self.chars.next();
+    }
+    Ok(())
+  }
+
+  fn value(&mut self) -> MalRet {
+    self.skip_ws();
+    match self.chars.peek() {
+      Some('n') => { self.expect("null")?; Ok(Nil) },
+      Some('t') => { self.expect("true")?; Ok(Bool(true)) },
+      Some('f') => { self.expect("false")?; Ok(Bool(false)) },
+      Some('"') => Ok(Str(self.string()?)),
+      Some('[') | Some('{') if self.path.len() >= MAX_DEPTH => {
+        self.error("too deeply nested")
+      },
+      Some('[') => self.array(),
+      Some('{') => self.object(),
+      Some(&c) if c == '-' || c.is_ascii_digit() => self.number(),
+      _ => self.unexpected(),
+    }
+  }
+
+  fn array(&mut self) -> MalRet {
+    self.chars.next();
+    let mut items = vec![];
+    self.skip_ws();
+    if self.chars.peek() == Some(&']') {
+      self.chars.next();
+      return Ok(vector!(items.into()));
+    }
+    loop {
+      self.path.push(format!("[{}]", items.len()));
+      items.push(self.value()?);
+      self.path.pop();
+      self.skip_ws();
+      match self.chars.next() {
+        Some(',') => continue,
+        Some(']') => return Ok(vector!(items.into())),
+        Some(c)   => return self.error(&format!("expected ',' or ']', found {:?}", c)),
+        None      => return self.error("unexpected end of input"),
+      }
+    }
+  }
+
+  fn object(&mut self) -> MalRet {
+    self.chars.next();
+    let mut hm = MalMap::default();
+    self.skip_ws();
+    if self.chars.peek() == Some(&'}') {
+      self.chars.next();
+      return Ok(Hash(Rc::new(hm),Rc::new(Nil)));
+    }
+    loop {
+      self.skip_ws();
+      if self.chars.peek() != Some(&'"') {
+        return match self.chars.peek() {
+          Some(&c) => self.error(&format!("expected a string key, found {:?}", c)),
+          None     => self.error("unexpected end of input"),
+        };
+      }
+      let k = self.string()?;
+      self.skip_ws();
+      if self.chars.next() != Some(':') {
+        return self.error(&format!("expected ':' after key {}", quote(&k)));
+      }
+      self.path.push(key_segment(&k));
+      let v = self.value()?;
+      self.path.pop();
+      let k = if self.keywordize { Keyword(Name::new(&k)) } else { Str(k) };
+      hm.insert(k, v);
+      self.skip_ws();
+      match self.chars.next() {
+        Some(',') => continue,
+        Some('}') => return Ok(Hash(Rc::new(hm),Rc::new(Nil))),
+        Some(c)   => return self.error(&format!("expected ',' or '}}', found {:?}", c)),
+        None      => return self.error("unexpected end of input"),
+      }
+    }
+  }
+
+  fn hex4(&mut self) -> Result<u32,MalErr> {
+    let mut n = 0;
+    for _ in 0..4 {
+      match self.chars.next().and_then(|c| c.to_digit(16)) {
+        Some(d) => n = n * 16 + d,
+        None    => return self.error("invalid \\u escape"),
+      }
+    }
+    Ok(n)
+  }
+
+  fn string(&mut self) -> Result<String,MalErr> {
+    self.chars.next();
+    let mut res = String::new();
+    loop {
+      match self.chars.next() {
+        Some('"')  => return Ok(res),
+        Some('\\') => {
+          let c = match self.chars.next() {
+            Some('"')  => '"',
+            Some('\\') => '\\',
+            Some('/')  => '/',
+            Some('b')  => '\u{8}',
+            Some('f')  => '\u{c}',
+            Some('n')  => '\n',
+            Some('r')  => '\r',
+            Some('t')  => '\t',
+            Some('u')  => {
+              let mut n = self.hex4()?;
+              // a surrogate pair is written as two escapes; a surrogate
+              // on its own is not a character
+              if (0xd800..0xe000).contains(&n) {
+                let lo = if n < 0xdc00 && self.chars.clone().take(2).eq("\\u".chars()) {
+                  self.chars.nth(1);
+                  self.hex4()?
+                } else {
+                  0
+                };
+                if !(0xdc00..0xe000).contains(&lo) {
+                  return self.error(&format!("invalid surrogate \\u{:04x}", n));
+                }
+                n = 0x10000 + ((n - 0xd800) << 10) + (lo - 0xdc00);
+              }
+              match ::std::char::from_u32(n) {
+                Some(c) => c,
+                None    => return self.error("invalid \\u escape"),
+              }
+            },
+            Some(c) => return self.error(&format!("invalid escape \\{}", c)),
+            None    => return self.error("unterminated string"),
+          };
+          res.push(c);
+        },
+        Some(c) if (c as u32) < 0x20 => {
+          return self.error("unescaped control character in string");
+        },
+        Some(c) => res.push(c),
+        None    => return self.error("unterminated string"),
+      }
+    }
+  }
+
+  fn number(&mut self) -> MalRet {
+    let mut s = String::new();
+    while let Some(&c) = self.chars.peek() {
+      if !(c.is_ascii_digit() || c == '-' || c == '+' || c == '.' ||
+           c == 'e' || c == 'E') {
+        break;
+      }
+      s.push(c);
+      self.chars.next();
+    }
+    let digits = s.trim_start_matches('-');
+    if digits.is_empty() || (digits.starts_with('0') &&
+                             digits[1..].starts_with(|c: char| c.is_ascii_digit())) {
+      return self.error(&format!("invalid number {}", s));
+    }
+    if s.contains(&['.', 'e', 'E'][..]) {
+      return match s.parse::<f64>() {
+        Ok(f) if f.is_finite() => Ok(Float(f)),
+        Ok(_)  => self.error(&format!("number {} is out of range", s)),
+        Err(_) => self.error(&format!("invalid number {}", s)),
+      };
+    }
+    match s.parse::<BigInt>() {
+      Ok(b)  => Ok(numeric::from_bigint(b)),
+      Err(_) => self.error(&format!("invalid number {}", s)),
+    }
+  }
+}
+
+pub fn decode(s: &str, keywordize: bool) -> MalRet {
+  let mut d = Decoder{chars: s.chars().peekable(), path: vec![],
+                      keywordize: keywordize};
+  let res = d.value()?;
+  d.skip_ws();
+  match d.chars.peek() {
+    None => Ok(res),
+    Some(_) => d.unexpected(),
+  }
+}
+
+// vim: ts=2:sw=2:expandtab
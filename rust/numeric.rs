@@ -0,0 +1,163 @@
+// the numeric tower: ints become bignums when they overflow, dividing ints
+// that don't divide exactly gives a ratio, and anything with a float in it
+// gives a float. Exact results are brought back down to the smallest type
+// that holds them, so an exact number has only one representation
+
+use std::rc::Rc;
+use std::cmp::Ordering;
+use std::hash::{Hash,Hasher};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{ToPrimitive,Zero};
+
+use types::{MalVal,MalRet,MalErr,error};
+use types::MalVal::{Int,Bignum,Ratio,Float};
+use types::MalErr::ErrString;
+
+enum Num {
+  Int(i64),
+  Exact(BigRational),
+  Float(f64),
+}
+
+impl Num {
+  fn to_exact(&self) -> BigRational {
+    match self {
+      Num::Int(i)   => BigRational::from_integer(BigInt::from(*i)),
+      Num::Exact(r) => r.clone(),
+      Num::Float(_) => unreachable!(),
+    }
+  }
+
+  fn to_f64(&self) -> f64 {
+    match self {
+      Num::Int(i)   => *i as f64,
+      Num::Exact(r) => ratio_to_f64(r),
+      Num::Float(f) => *f,
+    }
+  }
+
+  fn is_zero(&self) -> bool {
+    match self {
+      Num::Int(i)   => *i == 0,
+      Num::Exact(r) => r.is_zero(),
+      Num::Float(f) => *f == 0.0,
+    }
+  }
+}
+
+fn num(op: &str, mv: &MalVal) -> Result<Num,MalErr> {
+  match mv {
+    Int(i)    => Ok(Num::Int(*i)),
+    Bignum(b) => Ok(Num::Exact(BigRational::from_integer((**b).clone()))),
+    Ratio(r)  => Ok(Num::Exact((**r).clone())),
+    Float(f)  => Ok(Num::Float(*f)),
+    _ => Err(ErrString(format!("{}: expecting number args, got {}",
+                               op, mv.pr_str(true)))),
+  }
+}
+
+fn ratio_to_f64(r: &BigRational) -> f64 {
+  let n = r.numer().to_f64().unwrap_or(f64::NAN);
+  let d = r.denom().to_f64().unwrap_or(f64::NAN);
+  n / d
+}
+
+pub fn from_bigint(b: BigInt) -> MalVal {
+  match b.to_i64() {
+    Some(i) => Int(i),
+    None    => Bignum(Rc::new(b)),
+  }
+}
+
+pub fn from_ratio(r: BigRational) -> MalVal {
+  if r.is_integer() {
+    from_bigint(r.to_integer())
+  } else {
+    Ratio(Rc::new(r))
+  }
+}
+
+// hashes a number by its exact value, as compare compares floats with
+// exact numbers, so 2.0 hashes like 2 and 0.5 like 1/2; NaN and the
+// infinities, which equal no exact number, by their bits
+pub fn hash<H: Hasher>(mv: &MalVal, state: &mut H) {
+  match mv {
+    Int(i)    => i.hash(state),
+    Bignum(b) => b.hash(state),
+    Ratio(r)  => r.hash(state),
+    Float(f)  => match BigRational::from_float(*f) {
+      Some(r) => hash(&from_ratio(r), state),
+      None    => f.to_bits().hash(state),
+    },
+    _ => (),
+  }
+}
+
+// ints are tried with `int` first, which gives None on overflow
+fn arith(op: &str, a: &MalVal, b: &MalVal,
+         int: fn(i64, i64) -> Option<i64>,
+         exact: fn(&BigRational, &BigRational) -> BigRational,
+         float: fn(f64, f64) -> f64) -> MalRet {
+  let (x, y) = (num(op, a)?, num(op, b)?);
+  if let (Num::Int(i), Num::Int(j)) = (&x, &y) {
+    if let Some(res) = int(*i, *j) { return Ok(Int(res)) }
+  }
+  match (x, y) {
+    (Num::Float(f), y) => Ok(Float(float(f, y.to_f64()))),
+    (x, Num::Float(f)) => Ok(Float(float(x.to_f64(), f))),
+    (x, y) => Ok(from_ratio(exact(&x.to_exact(), &y.to_exact()))),
+  }
+}
+
+pub fn add(a: &MalVal, b: &MalVal) -> MalRet {
+  arith("+", a, b, i64::checked_add, |x,y| x + y, |x,y| x + y)
+}
+
+pub fn sub(a: &MalVal, b: &MalVal) -> MalRet {
+  arith("-", a, b, i64::checked_sub, |x,y| x - y, |x,y| x - y)
+}
+
+pub fn mul(a: &MalVal, b: &MalVal) -> MalRet {
+  arith("*", a, b, i64::checked_mul, |x,y| x * y, |x,y| x * y)
+}
+
+pub fn div(a: &MalVal, b: &MalVal) -> MalRet {
+  let (x, y) = (num("/", a)?, num("/", b)?);
+  match (x, y) {
+    (Num::Float(f), y) => Ok(Float(f / y.to_f64())),
+    (x, Num::Float(f)) => Ok(Float(x.to_f64() / f)),
+    (_, ref y) if y.is_zero() => error("/: division by zero"),
+    (Num::Int(i), Num::Int(j)) if i.checked_rem(j) == Some(0) => {
+      // only i64::MIN / -1 overflows, and its remainder overflows too
+      Ok(Int(i / j))
+    },
+    (x, y) => Ok(from_ratio(x.to_exact() / y.to_exact())),
+  }
+}
+
+// a float against an exact number, by the exact value of the float rather
+// than by rounding the exact number to a float, so that = stays transitive
+fn compare_float(f: f64, x: &Num) -> Option<Ordering> {
+  if f.is_nan() {
+    None
+  } else if f.is_infinite() {
+    Some(if f > 0.0 { Ordering::Greater } else { Ordering::Less })
+  } else {
+    BigRational::from_float(f).map(|r| r.cmp(&x.to_exact()))
+  }
+}
+
+// None when either number is NaN
+pub fn compare(op: &str, a: &MalVal,
+               b: &MalVal) -> Result<Option<Ordering>,MalErr> {
+  match (num(op, a)?, num(op, b)?) {
+    (Num::Int(i), Num::Int(j)) => Ok(Some(i.cmp(&j))),
+    (Num::Float(f), Num::Float(g)) => Ok(f.partial_cmp(&g)),
+    (Num::Float(f), y) => Ok(compare_float(f, &y)),
+    (x, Num::Float(f)) => Ok(compare_float(f, &x).map(Ordering::reverse)),
+    (x, y) => Ok(Some(x.to_exact().cmp(&y.to_exact()))),
+  }
+}
+
+// vim: ts=2:sw=2:expandtab
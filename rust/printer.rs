@@ -1,53 +1,172 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use fnv::{FnvHashMap,FnvHashSet};
+
 use types::MalVal;
-use types::MalVal::{Nil,Bool,Int,Str,Sym,List,Vector,Hash,Func,MalFunc,Atom};
+use types::MalVal::{Nil,Bool,Int,Bignum,Ratio,Float,Str,Keyword,Sym,Regex,List,Vector,Hash,Set,LazySeq,Func,MalFunc,Atom};
 
 fn escape_str(s: &str) -> String {
   s.chars().map(|c| {
     match c {
       '"' => "\\\"".to_string(),
       '\n' => "\\n".to_string(),
+      '\t' => "\\t".to_string(),
+      '\r' => "\\r".to_string(),
+      '\0' => "\\0".to_string(),
       '\\' => "\\\\".to_string(),
+      c if c.is_control() => format!("\\u{{{:x}}}", c as u32),
       _ => c.to_string(),
     }
   }).collect::<Vec<String>>().join("")
 }
 
+type AtomPtr = *const RefCell<MalVal>;
+
+// atoms that lead back to themselves, numbered as they are first printed,
+// so that a cycle prints as #1=(atom (#1#)) rather than forever
+struct Cycles {
+  cyclic: FnvHashSet<AtomPtr>,
+  labels: FnvHashMap<AtomPtr, usize>,
+}
+
+impl Cycles {
+  fn new(mv: &MalVal) -> Cycles {
+    let mut cyclic = FnvHashSet::default();
+    find_cycles(mv, &mut vec![], &mut FnvHashSet::default(), &mut cyclic);
+    Cycles{cyclic: cyclic, labels: FnvHashMap::default()}
+  }
+
+  // the label of a cyclic atom, and whether this is its first appearance
+  fn label(&mut self, a: &Rc<RefCell<MalVal>>) -> Option<(usize, bool)> {
+    let p = &**a as AtomPtr;
+    if !self.cyclic.contains(&p) {
+      return None;
+    }
+    if let Some(&n) = self.labels.get(&p) {
+      return Some((n, false));
+    }
+    let n = self.labels.len() + 1;
+    self.labels.insert(p, n);
+    Some((n, true))
+  }
+}
+
+fn find_cycles(mv: &MalVal, path: &mut Vec<AtomPtr>,
+               done: &mut FnvHashSet<AtomPtr>,
+               cyclic: &mut FnvHashSet<AtomPtr>) {
+  match mv {
+    List(l,_) | Vector(l,_) => {
+      for x in l.iter() { find_cycles(x, path, done, cyclic) }
+    },
+    Hash(hm,_) => {
+      for x in hm.values() { find_cycles(x, path, done, cyclic) }
+    },
+    // set elements can't hold atoms
+    LazySeq(..) => {
+      for x in mv.seq_iter().filter_map(Result::ok) {
+        find_cycles(&x, path, done, cyclic)
+      }
+    },
+    Atom(a) => {
+      let p = &**a as AtomPtr;
+      if path.contains(&p) {
+        cyclic.insert(p);
+      } else if done.insert(p) {
+        path.push(p);
+        find_cycles(&a.borrow(), path, done, cyclic);
+        path.pop();
+      }
+    },
+    _ => (),
+  }
+}
+
 impl MalVal {
   pub fn pr_str(&self, print_readably: bool) -> String {
+    self.pr(print_readably, &mut Cycles::new(self))
+  }
+
+  fn pr(&self, print_readably: bool, cy: &mut Cycles) -> String {
     match self {
       Nil         => String::from("nil"),
       Bool(true)  => String::from("true"),
       Bool(false) => String::from("false"),
       Int(i)      => format!("{}", i),
-      //Float(f)    => format!("{}", f),
+      Bignum(b)   => format!("{}", b),
+      Ratio(r)    => format!("{}", r),
+      Float(f) if f.is_nan() => String::from("##NaN"),
+      Float(f) if f.is_infinite() => {
+        String::from(if *f > 0.0 { "##Inf" } else { "##-Inf" })
+      },
+      // always with a '.' or an exponent, so it reads back as a float
+      Float(f)    => format!("{:?}", f),
+      Keyword(k)  => format!(":{}", k),
       Str(s)      => {
-        if s.starts_with("\u{29e}") {
-          format!(":{}", &s[2..])
-        } else if print_readably {
+        if print_readably {
           format!("\"{}\"", escape_str(s))
         } else {
           s.clone()
         }
       }
-      Sym(s)      => s.clone(),
-      List(l,_)   => pr_seq(&**l, print_readably, "(", ")", " "),
-      Vector(l,_) => pr_seq(&**l, print_readably, "[", "]", " "),
+      Sym(s)      => s.to_string(),
+      // only a '"' needs escaping in #"...", as the reader takes the rest
+      // of the pattern as written
+      Regex(re)   => {
+        if print_readably {
+          format!("#\"{}\"", re.as_str().replace('"', "\\\""))
+        } else {
+          re.as_str().to_string()
+        }
+      },
+      List(l,_)   => pr_items(l.iter(), print_readably, cy, "(", ")", " "),
+      Vector(l,_) => pr_items(l.iter(), print_readably, cy, "[", "]", " "),
       Hash(hm,_)  => {
         let l: Vec<MalVal> = hm
           .iter()
-          .flat_map(|(k, v)| { vec![Str(k.to_string()), v.clone()] })
+          .flat_map(|(k, v)| { vec![k.clone(), v.clone()] })
           .collect();
-        pr_seq(&l, print_readably, "{", "}", " ")
+        pr_items(&l, print_readably, cy, "{", "}", " ")
       },
-      Func(f,_)   => format!("#<fn {:?}>", f),
-      MalFunc{ast: a, params: p, ..} => {
-        format!("(fn* {} {})", p.pr_str(true), a.pr_str(true))
+      Set(hs,_)   => pr_items(hs.iter(), print_readably, cy, "#{", "}", " "),
+      // as much as is realized without error, which is all of it after
+      // MalVal::realize
+      LazySeq(..) => {
+        let l: Vec<MalVal> = self.seq_iter().filter_map(Result::ok).collect();
+        pr_items(&l, print_readably, cy, "(", ")", " ")
+      },
+      Func(_,info,_) => match info.name {
+        Some(ref name) => format!("#<fn {}>", name),
+        None           => String::from("#<fn>"),
+      },
+      MalFunc{info, params: p, ..} => {
+        let params = match **p {
+          List(ref l,_) | Vector(ref l,_) => pr_items(l.iter(), true, cy, "[", "]", " "),
+          ref p => p.pr(true, cy),
+        };
+        match info.name {
+          Some(ref name) => format!("#<fn {} {}>", name, params),
+          None           => format!("#<fn {}>", params),
+        }
+      },
+      Atom(a)     => match cy.label(a) {
+        Some((n, false)) => format!("#{}#", n),
+        Some((n, true))  => format!("#{}=(atom {})", n, a.borrow().pr(true, cy)),
+        None             => format!("(atom {})", a.borrow().pr(true, cy)),
       },
-      Atom(a)     => format!("(atom {})", a.borrow().pr_str(true)),
     }
   }
 }
 
+fn pr_items<'a, I>(seq: I, print_readably: bool, cy: &mut Cycles,
+                   start: &str, end: &str, join: &str) -> String
+  where I: IntoIterator<Item=&'a MalVal> {
+  let strs: Vec<String> = seq
+    .into_iter()
+    .map(|x| x.pr(print_readably, cy))
+    .collect();
+  format!("{}{}{}", start, strs.join(join), end)
+}
+
 pub fn pr_seq(seq: &Vec<MalVal>, print_readably: bool,
               start: &str, end: &str, join: &str) -> String {
   let strs: Vec<String> = seq
@@ -57,4 +176,179 @@ pub fn pr_seq(seq: &Vec<MalVal>, print_readably: bool,
   format!("{}{}{}", start, strs.join(join), end)
 }
 
+// pretty printing, after Wadler's "A prettier printer": a value becomes a
+// document of groups, and each group is laid out on one line if it fits
+// in the remaining width, or else broken at its lines
+enum Doc {
+  Text(String),
+  // a space, or a newline and indentation if its group is broken
+  Line,
+  Nest(usize, Box<Doc>),
+  // indentation set to the column the doc starts in
+  Align(Box<Doc>),
+  Cat(Vec<Doc>),
+  Group(Box<Doc>),
+}
+
+use self::Doc::{Text,Line,Nest,Align,Cat,Group};
+
+fn text(s: &str) -> Doc {
+  Text(s.to_string())
+}
+
+// docs separated by lines
+fn lines(docs: Vec<Doc>) -> Doc {
+  let mut res = vec![];
+  for (i, d) in docs.into_iter().enumerate() {
+    if i > 0 { res.push(Line) }
+    res.push(d);
+  }
+  Cat(res)
+}
+
+// each pair of docs as a group of its own
+fn pairs(docs: Vec<Doc>) -> Vec<Doc> {
+  let mut res = vec![];
+  let mut it = docs.into_iter();
+  while let Some(a) = it.next() {
+    match it.next() {
+      Some(b) => res.push(Group(Box::new(Cat(vec![a, Line, b])))),
+      None    => res.push(a),
+    }
+  }
+  res
+}
+
+fn seq_doc(open: &str, items: Vec<Doc>, close: &str) -> Doc {
+  Group(Box::new(Cat(vec![text(open), Align(Box::new(lines(items))),
+                          text(close)])))
+}
+
+// (head first ...) with the rest indented by `indent` from the opening
+// paren when broken
+fn form_doc(head: &str, first: Option<Doc>, rest: Vec<Doc>,
+            indent: usize) -> Doc {
+  let mut docs = vec![text("("), text(head)];
+  if let Some(first) = first {
+    docs.push(text(" "));
+    docs.push(first);
+  }
+  if !rest.is_empty() {
+    docs.push(Nest(indent, Box::new(Cat(vec![Line, lines(rest)]))));
+  }
+  docs.push(text(")"));
+  Group(Box::new(Align(Box::new(Cat(docs)))))
+}
+
+fn docs<'a, I>(seq: I, cy: &mut Cycles) -> Vec<Doc>
+  where I: IntoIterator<Item=&'a MalVal> {
+  seq.into_iter().map(|v| v.doc(cy)).collect()
+}
+
+impl MalVal {
+  fn doc(&self, cy: &mut Cycles) -> Doc {
+    match self {
+      List(l,_) => {
+        let head = match l.front() {
+          Some(Sym(s)) => &s[..],
+          _            => return seq_doc("(", docs(l.iter(), cy), ")"),
+        };
+        match head {
+          "let*" | "fn*" | "def!" | "defmacro!" if l.len() > 2 => {
+            let first = match &l[1] {
+              Vector(b,_) if head == "let*" => seq_doc("[", pairs(docs(b.iter(), cy)), "]"),
+              List(b,_)   if head == "let*" => seq_doc("(", pairs(docs(b.iter(), cy)), ")"),
+              v => v.doc(cy),
+            };
+            form_doc(head, Some(first), docs(l.iter().skip(2), cy), 2)
+          },
+          "if" if l.len() > 2 => form_doc(head, Some(l[1].doc(cy)), docs(l.iter().skip(2), cy), 4),
+          "cond" => form_doc(head, None, pairs(docs(l.iter().skip(1), cy)), 2),
+          _      => form_doc(head, None, docs(l.iter().skip(1), cy), 2),
+        }
+      },
+      Vector(l,_) => seq_doc("[", docs(l.iter(), cy), "]"),
+      Hash(hm,_)  => {
+        let kvs: Vec<MalVal> = hm
+          .iter()
+          .flat_map(|(k, v)| { vec![k.clone(), v.clone()] })
+          .collect();
+        seq_doc("{", pairs(docs(&kvs, cy)), "}")
+      },
+      Set(hs,_)   => seq_doc("#{", docs(hs.iter(), cy), "}"),
+      LazySeq(..) => {
+        let l: Vec<MalVal> = self.seq_iter().filter_map(Result::ok).collect();
+        seq_doc("(", docs(&l, cy), ")")
+      },
+      Atom(a) => match cy.label(a) {
+        Some((n, false)) => Text(format!("#{}#", n)),
+        Some((n, true))  => Cat(vec![Text(format!("#{}=", n)),
+                                     form_doc("atom", Some(a.borrow().doc(cy)), vec![], 2)]),
+        None             => form_doc("atom", Some(a.borrow().doc(cy)), vec![], 2),
+      },
+      _ => Text(self.pr_str(true)),
+    }
+  }
+
+  // readable, laid out to fit in `width` columns where possible
+  pub fn pprint(&self, width: usize) -> String {
+    let doc = self.doc(&mut Cycles::new(self));
+    let mut out = String::new();
+    let mut col = 0;
+    // (indentation, flat, doc) still to lay out, last first
+    let mut stack: Vec<(usize, bool, &Doc)> = vec![(0, false, &doc)];
+    while let Some((indent, flat, d)) = stack.pop() {
+      match d {
+        Text(s) => {
+          out.push_str(s);
+          col += s.chars().count();
+        },
+        Line if flat => {
+          out.push(' ');
+          col += 1;
+        },
+        Line => {
+          out.push('\n');
+          out.push_str(&" ".repeat(indent));
+          col = indent;
+        },
+        Nest(i, d) => stack.push((indent + i, flat, d)),
+        Align(d) => stack.push((col, flat, d)),
+        Cat(ds) => {
+          for d in ds.iter().rev() { stack.push((indent, flat, d)) }
+        },
+        Group(d) => {
+          let flat = flat || fits(width as isize - col as isize, d, &stack);
+          stack.push((indent, flat, d));
+        },
+      }
+    }
+    out
+  }
+}
+
+// whether `doc` laid out flat, and what follows it up to the next line
+// break, fits in `room` columns
+fn fits(mut room: isize, doc: &Doc, rest: &[(usize, bool, &Doc)]) -> bool {
+  let mut stack = vec![(true, doc)];
+  let mut rest = rest.iter().rev();
+  loop {
+    let (flat, d) = match stack.pop() {
+      Some(x) => x,
+      None    => match rest.next() {
+        Some(&(_, flat, d)) => (flat, d),
+        None                => return true,
+      },
+    };
+    match d {
+      Text(s)    => room -= s.chars().count() as isize,
+      Line if flat => room -= 1,
+      Line       => return true,
+      Nest(_, d) | Align(d) | Group(d) => stack.push((flat, d)),
+      Cat(ds)    => for d in ds.iter().rev() { stack.push((flat, d)) },
+    }
+    if room < 0 { return false }
+  }
+}
+
 // vim: ts=2:sw=2:expandtab
@@ -1,142 +1,777 @@
 use std::rc::Rc;
-use regex::{Regex,Captures};
+use std::cell::RefCell;
+use std::fmt;
+use std::str::Chars;
+use std::iter::Peekable;
+use regex::Regex;
+use fnv::FnvHashMap;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
 
-use types::{MalVal,MalRet,MalErr,error,hash_map};
-use types::MalVal::{Nil,Bool,Int,Str,Sym,List,Vector};
-use types::MalErr::ErrString;
+use types::{MalVal,MalArgs,MalRet,MalErr,Pos,ReadErr,Name,error,func,hash_map,hash_set};
+use types::MalVal::{Nil,Bool,Int,Float,Str,Keyword,Sym,List,Vector,Hash};
+use types::MalErr::{ErrString,ErrRead};
+use numeric;
+
+// handlers for #tag literals, called with the form following the tag
+thread_local! {
+  static TAG_READERS: RefCell<FnvHashMap<String,MalVal>> = {
+    let mut readers = FnvHashMap::default();
+    readers.insert("inst".to_string(), func(string_tag));
+    readers.insert("uuid".to_string(), func(string_tag));
+    RefCell::new(readers)
+  };
+}
+
+// #inst and #uuid values are kept as their strings
+fn string_tag(a: MalArgs) -> MalRet {
+  match a[0] {
+    Str(_) => Ok(a[0].clone()),
+    _ => error("tagged literal expects a string"),
+  }
+}
+
+pub fn set_tag_reader(tag: &str, f: MalVal) {
+  TAG_READERS.with(|r| r.borrow_mut().insert(tag.to_string(), f));
+}
+
+// user reader macros: a character that, at the start of a token, reads
+// as its handler called with the form following it
+thread_local! {
+  static READER_MACROS: RefCell<FnvHashMap<char,MalVal>> =
+    RefCell::new(FnvHashMap::default());
+}
+
+fn reader_macro(c: char) -> Option<MalVal> {
+  READER_MACROS.with(|m| m.borrow().get(&c).cloned())
+}
+
+// the handler if the token is a reader macro character
+fn macro_token(text: &str) -> Option<MalVal> {
+  let mut chars = text.chars();
+  match (chars.next(), chars.next()) {
+    (Some(c), None) => reader_macro(c),
+    _               => None,
+  }
+}
+
+// a Nil handler removes the macro
+pub fn set_reader_macro(s: &str, f: MalVal) -> MalRet {
+  let mut chars = s.chars();
+  let c = match (chars.next(), chars.next()) {
+    (Some(c), None) => c,
+    _ => return error("reader macro must be a single character"),
+  };
+  if c.is_whitespace() || c.is_digit(10) || "()[]{}\"'`~@^#;,:".contains(c) {
+    return error(&format!("'{}' cannot be a reader macro", c));
+  }
+  READER_MACROS.with(|m| match f {
+    Nil => { m.borrow_mut().remove(&c); },
+    f   => { m.borrow_mut().insert(c, f); },
+  });
+  Ok(Nil)
+}
+
+// "file:line:col", or "line:col" when not read from a file, of a fn* form
+// from the location the reader gave it as metadata
+pub fn fn_source(meta: &MalVal) -> Option<String> {
+  let hm = match meta {
+    Hash(hm,_) => hm,
+    _          => return None,
+  };
+  let (line, col) = match (hm.get(&kw("line")), hm.get(&kw("column"))) {
+    (Some(Int(line)), Some(Int(col))) => (line, col),
+    _ => return None,
+  };
+  match hm.get(&kw("file")) {
+    Some(Str(file)) => Some(format!("{}:{}:{}", file, line, col)),
+    _               => Some(format!("{}:{}", line, col)),
+  }
+}
 
 #[derive(Debug, Clone)]
-struct Reader {
-    tokens: Vec<String>,
-    pos: usize,
+struct Token {
+  text: String,
+  pos: Pos,
 }
 
-impl Reader {
-  fn next(&mut self) -> Result<String,MalErr> {
-    self.pos = self.pos + 1;
-    Ok(self.tokens.get(self.pos-1)
-         .ok_or(ErrString("underflow".to_string()))?.to_string())
+// tokens are lexed as they are needed, so a reader macro defined by one
+// form applies to the forms read after it
+struct Reader<'a> {
+  src: &'a str,
+  // the file being read, if any
+  file: Option<&'a str>,
+  lexer: Lexer<'a>,
+  peeked: Option<Token>,
+}
+
+impl<'a> Reader<'a> {
+  fn new(src: &'a str, file: Option<&'a str>) -> Reader<'a> {
+    let start = Pos { offset: 0, line: 1, col: 1 };
+    Reader {
+      src: src,
+      file: file,
+      lexer: Lexer { src: src, chars: src.chars().peekable(),
+                     pos: start, end: start },
+      peeked: None,
+    }
+  }
+  fn next(&mut self) -> Result<Token,MalErr> {
+    let token = self.peek()?;
+    self.peeked = None;
+    Ok(token)
+  }
+  fn peek(&mut self) -> Result<Token,MalErr> {
+    match self.lookahead()? {
+      Some(t) => Ok(t),
+      None    => Err(eof_error(self.src, "unexpected EOF", self.eof(), None)),
+    }
+  }
+  // the next token, or None at the end of input
+  fn lookahead(&mut self) -> Result<Option<Token>,MalErr> {
+    if self.peeked.is_none() {
+      self.peeked = self.lexer.next_token()?;
+    }
+    Ok(self.peeked.clone())
   }
-  fn peek(&self) -> Result<String,MalErr> {
-    Ok(self.tokens.get(self.pos)
-         .ok_or(ErrString("underflow".to_string()))?.to_string())
+  // EOF errors are reported just past the last token, not after
+  // trailing blanks
+  fn eof(&self) -> Pos {
+    self.lexer.end
   }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
-  lazy_static! {
-      static ref RE: Regex = Regex::new(r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###).unwrap();
+fn read_error(src: &str, msg: &str, pos: Pos,
+              unclosed: Option<&Token>) -> MalErr {
+  ErrRead(Box::new(ReadErr {
+    msg: msg.to_string(),
+    pos: pos,
+    unclosed: unclosed.map(|t| (t.text.clone(), t.pos)),
+    src_line: src.lines().nth(pos.line - 1).unwrap_or("").to_string(),
+    incomplete: false,
+  }))
+}
+
+// like read_error, but for input that ended before the form did
+fn eof_error(src: &str, msg: &str, pos: Pos,
+             unclosed: Option<&Token>) -> MalErr {
+  match read_error(src, msg, pos, unclosed) {
+    ErrRead(re) => ErrRead(Box::new(ReadErr { incomplete: true, ..*re })),
+    e           => e,
   }
+}
 
-  let mut res = vec![];
-  for cap in RE.captures_iter(str) {
-    if cap[1].starts_with(";") { continue }
-    res.push(String::from(&cap[1]));
+struct Lexer<'a> {
+  src: &'a str,
+  chars: Peekable<Chars<'a>>,
+  pos: Pos,
+  // just past the last token
+  end: Pos,
+}
+
+impl<'a> Lexer<'a> {
+  fn peek(&mut self) -> Option<char> {
+    self.chars.peek().cloned()
+  }
+
+  fn bump(&mut self) -> Option<char> {
+    let c = self.chars.next()?;
+    self.pos.offset += c.len_utf8();
+    if c == '\n' {
+      self.pos.line += 1;
+      self.pos.col = 1;
+    } else {
+      self.pos.col += 1;
+    }
+    Some(c)
+  }
+
+  // whitespace, commas and comments
+  fn skip_blank(&mut self) {
+    while let Some(c) = self.peek() {
+      match c {
+        ';' => while self.peek().map_or(false, |c| c != '\n') {
+          self.bump();
+        },
+        c if c.is_whitespace() || c == ',' => { self.bump(); },
+        _ => break,
+      }
+    }
+  }
+
+  fn string(&mut self, start: Pos) -> Result<String,MalErr> {
+    let mut text = String::from("\"");
+    loop {
+      match self.bump() {
+        Some('"')  => break,
+        Some('\\') => {
+          text.push('\\');
+          if let Some(c) = self.bump() {
+            text.push(c);
+          }
+        },
+        Some(c)    => text.push(c),
+        None       => {
+          let opener = Token { text: "\"".to_string(), pos: start };
+          return Err(eof_error(self.src, "expected '\"', got EOF",
+                               self.pos, Some(&opener)));
+        },
+      }
+    }
+    text.push('"');
+    Ok(text)
+  }
+
+  fn next_token(&mut self) -> Result<Option<Token>,MalErr> {
+    self.skip_blank();
+    let start = self.pos;
+    let c = match self.bump() {
+      Some(c) => c,
+      None    => return Ok(None),
+    };
+    let text = match c {
+      '~' if self.peek() == Some('@') => {
+        self.bump();
+        "~@".to_string()
+      },
+      '[' | ']' | '{' | '}' | '(' | ')' | '\'' | '`' | '~' | '^' | '@' => {
+        c.to_string()
+      },
+      '"' => self.string(start)?,
+      c if reader_macro(c).is_some() => c.to_string(),
+      '#' => match self.peek() {
+        Some('{') | Some('_') => {
+          format!("#{}", self.bump().unwrap())
+        },
+        Some('"') => {
+          self.bump();
+          format!("#{}", self.string(start)?)
+        },
+        _ => self.symbol(c),
+      },
+      _   => self.symbol(c),
+    };
+    self.end = self.pos;
+    Ok(Some(Token { text: text, pos: start }))
+  }
+
+  fn symbol(&mut self, first: char) -> String {
+    let mut text = first.to_string();
+    while let Some(c) = self.peek() {
+      if c.is_whitespace() || "[]{}('\"`,;)".contains(c) { break }
+      text.push(c);
+      self.bump();
+    }
+    text
   }
-  res
 }
 
-fn unescape_str(s: &str) -> String {
-  lazy_static! {
-    static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
+// a \u escape's hex digits, of which there must be between 1 and `max`
+fn parse_hex(hex: &str, max: usize) -> Option<u32> {
+  if hex.is_empty() || hex.len() > max ||
+     !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+    return None;
   }
-  RE.replace_all(&s, |caps: &Captures| {
-    format!("{}", if &caps[1] == "n" { "\n" } else { &caps[1] })
-  }).to_string()
+  u32::from_str_radix(hex, 16).ok()
+}
+
+// \uXXXX (joining surrogate pairs) or \u{X...}, after the \u
+fn unicode_escape(chars: &mut Chars) -> Result<char,String> {
+  let rest = chars.as_str();
+  let bad = || format!("invalid unicode escape '\\u{}'",
+                       rest.chars().take(6).collect::<String>());
+  let (code, len) = if rest.starts_with('{') {
+    let end = rest.find('}').ok_or_else(&bad)?;
+    (parse_hex(&rest[1..end], 6).ok_or_else(&bad)?, end + 1)
+  } else {
+    let hi = rest.get(..4).and_then(|h| parse_hex(h, 4)).ok_or_else(&bad)?;
+    let lo = rest.get(4..10).filter(|l| l.starts_with("\\u"))
+                 .and_then(|l| parse_hex(&l[2..], 4));
+    match lo {
+      Some(lo) if hi >= 0xD800 && hi < 0xDC00 && lo >= 0xDC00 && lo < 0xE000 =>
+        (0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00), 10),
+      _ => (hi, 4),
+    }
+  };
+  *chars = rest[len..].chars();
+  ::std::char::from_u32(code).ok_or(format!("invalid code point {:X}", code))
+}
+
+// on error, the message and the byte offset in s of the bad escape
+fn unescape_str(s: &str) -> Result<String,(String,usize)> {
+  let mut res = String::new();
+  let mut chars = s.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      res.push(c);
+      continue;
+    }
+    let at = s.len() - chars.as_str().len() - 1;
+    match chars.next() {
+      Some('n')  => res.push('\n'),
+      Some('t')  => res.push('\t'),
+      Some('r')  => res.push('\r'),
+      Some('0')  => res.push('\0'),
+      Some('\\') => res.push('\\'),
+      Some('"')  => res.push('"'),
+      Some('u')  => res.push(unicode_escape(&mut chars).map_err(|msg| (msg, at))?),
+      Some(c)    => return Err((format!("unknown escape '\\{}'", c), at)),
+      None       => return Err(("unknown escape '\\'".to_string(), at)),
+    }
+  }
+  Ok(res)
+}
+
+// where the end of text is, when it starts at pos
+fn pos_after(mut pos: Pos, text: &str) -> Pos {
+  for c in text.chars() {
+    pos.offset += c.len_utf8();
+    if c == '\n' {
+      pos.line += 1;
+      pos.col = 1;
+    } else {
+      pos.col += 1;
+    }
+  }
+  pos
 }
 
 fn read_atom(rdr: &mut Reader) -> MalRet {
   lazy_static! {
-    static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
+    static ref INT_RE: Regex = Regex::new(r"^[-+]?[0-9]+N?$").unwrap();
+    static ref RATIO_RE: Regex = Regex::new(r"^([-+]?[0-9]+)/([0-9]+)$").unwrap();
+    static ref FLOAT_RE: Regex =
+      Regex::new(r"^[-+]?[0-9]+(\.[0-9]*)?([eE][-+]?[0-9]+)?$").unwrap();
   }
   let token = rdr.next()?;
-  match &token[..] {
+  let text = &token.text;
+  match &text[..] {
     "nil"   => Ok(Nil),
     "false" => Ok(Bool(false)),
     "true"  => Ok(Bool(true)),
+    "##Inf"  => Ok(Float(f64::INFINITY)),
+    "##-Inf" => Ok(Float(f64::NEG_INFINITY)),
+    "##NaN"  => Ok(Float(f64::NAN)),
     _       => {
-      if INT_RE.is_match(&token) {
-        Ok(Int(token.parse().unwrap()))
-      } else if token.starts_with("\"") {
-        if token.ends_with("\"") {
-          Ok(Str(unescape_str(&token[1..token.len()-1])))
-        } else {
-          error("expected '\"', got EOF")
+      if INT_RE.is_match(text) {
+        // the N suffix is optional, ints too big for an i64 being bignums
+        let digits = text.trim_end_matches('N');
+        Ok(numeric::from_bigint(digits.parse::<BigInt>().unwrap()))
+      } else if let Some(caps) = RATIO_RE.captures(text) {
+        let n = caps[1].parse::<BigInt>().unwrap();
+        let d = caps[2].parse::<BigInt>().unwrap();
+        if d.is_zero() {
+          return Err(read_error(rdr.src, "ratio with a zero denominator",
+                                token.pos, None));
+        }
+        Ok(numeric::from_ratio(BigRational::new(n, d)))
+      } else if FLOAT_RE.is_match(text) {
+        match text.parse() {
+          Ok(f)  => Ok(Float(f)),
+          Err(_) => Err(read_error(rdr.src, "invalid float", token.pos, None)),
         }
-      } else if token.starts_with(":") {
-        Ok(Str(format!("\u{29e}{}", &token[1..])))
+      } else if text.starts_with("\"") {
+        match unescape_str(&text[1..text.len()-1]) {
+          Ok(s)          => Ok(Str(s)),
+          Err((msg, at)) => {
+            let pos = pos_after(token.pos, &text[..1 + at]);
+            Err(read_error(rdr.src, &msg, pos, None))
+          },
+        }
+      } else if let Some(name) = text.strip_prefix(':') {
+        Ok(Keyword(Name::new(name)))
       } else {
-        Ok(Sym(token.to_string()))
+        Ok(Sym(Name::new(text)))
       }
     }
   }
 }
 
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
+  let seq = read_items(rdr, end)?;
+  match end {
+    ")" => Ok(list!(seq.into())),
+    "]" => Ok(vector!(seq.into())),
+    "}" => hash_map(seq),
+    _   => error("read_seq unknown end value"),
+  }
+}
+
+// the forms between an opening token and `end`
+fn read_items(rdr: &mut Reader, end: &str) -> Result<Vec<MalVal>,MalErr> {
   let mut seq : Vec<MalVal> = vec![];
-  rdr.next()?;
+  let opener = rdr.next()?;
   loop {
-    let token = match rdr.peek() {
-      Ok(t) => t,
-      Err(_) => return error(&format!("expected '{}', got EOF", end))
+    skip_discarded(rdr)?;
+    let token = match rdr.lookahead()? {
+      Some(t) => t,
+      None    => {
+        let msg = format!("expected '{}', got EOF", end);
+        return Err(eof_error(rdr.src, &msg, rdr.eof(), Some(&opener)));
+      }
     };
-    if token == end { break }
+    if token.text == end { break }
+    if let ")" | "]" | "}" = &token.text[..] {
+      let msg = format!("expected '{}', got '{}'", end, token.text);
+      return Err(read_error(rdr.src, &msg, token.pos, Some(&opener)));
+    }
     seq.push(read_form(rdr)?)
   }
   let _ = rdr.next();
-  match end {
-    ")" => Ok(list!(seq)),
-    "]" => Ok(vector!(seq)),
-    "}" => hash_map(seq),
-    _   => error("read_seq unknown end value"),
+  Ok(seq)
+}
+
+// #_ drops the form after it
+fn skip_discarded(rdr: &mut Reader) -> Result<(),MalErr> {
+  while let Some(token) = rdr.lookahead()? {
+    if token.text != "#_" { break }
+    let _ = rdr.next();
+    read_macro_arg(rdr, &token)?;
+  }
+  Ok(())
+}
+
+fn read_set(rdr: &mut Reader) -> MalRet {
+  let items = read_items(rdr, "}")?;
+  hash_set(items)
+}
+
+// #"..." reads as a compiled regex, so a bad pattern is a read error
+fn read_regex(rdr: &mut Reader) -> MalRet {
+  let token = rdr.next()?;
+  let pattern = token.text[2..token.text.len()-1].replace("\\\"", "\"");
+  match Regex::new(&pattern) {
+    Ok(re) => Ok(MalVal::Regex(Rc::new(re))),
+    Err(e) => {
+      let msg = format!("invalid regex: {}", e);
+      Err(read_error(rdr.src, &msg, token.pos, None))
+    },
+  }
+}
+
+fn read_tagged(rdr: &mut Reader) -> MalRet {
+  let token = rdr.next()?;
+  let tag = &token.text[1..];
+  if tag.is_empty() {
+    return Err(read_error(rdr.src, "expected dispatch after '#'",
+                          token.pos, None));
+  }
+  let form = read_macro_arg(rdr, &token)?;
+  match TAG_READERS.with(|r| r.borrow().get(tag).cloned()) {
+    Some(f) => f.apply(vec![form]),
+    None    => {
+      let msg = format!("no reader function for tag {}", tag);
+      Err(read_error(rdr.src, &msg, token.pos, None))
+    },
+  }
+}
+
+// the form following a reader macro such as ' or @
+fn read_macro_arg(rdr: &mut Reader, mac: &Token) -> MalRet {
+  match rdr.lookahead()? {
+    Some(_) => read_form(rdr),
+    None    => {
+      let msg = format!("expected form after '{}', got EOF", mac.text);
+      Err(eof_error(rdr.src, &msg, rdr.eof(), None))
+    },
   }
 }
 
 fn read_form(rdr: &mut Reader) -> MalRet {
+  skip_discarded(rdr)?;
   let token = rdr.peek()?;
-  match &token[..] {
+  if let Some(f) = macro_token(&token.text) {
+    let _ = rdr.next();
+    return f.apply(vec![read_macro_arg(rdr, &token)?]);
+  }
+  match &token.text[..] {
     "'"  => {
       let _ = rdr.next();
-      Ok(list![Sym("quote".to_string()), read_form(rdr)?])
+      Ok(list![Sym(Name::new("quote")), read_macro_arg(rdr, &token)?])
     },
     "`"  => {
       let _ = rdr.next();
-      Ok(list![Sym("quasiquote".to_string()), read_form(rdr)?])
+      Ok(list![Sym(Name::new("quasiquote")), read_macro_arg(rdr, &token)?])
     },
     "~"  => {
       let _ = rdr.next();
-      Ok(list![Sym("unquote".to_string()), read_form(rdr)?])
+      Ok(list![Sym(Name::new("unquote")), read_macro_arg(rdr, &token)?])
     },
     "~@"  => {
       let _ = rdr.next();
-      Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
+      Ok(list![Sym(Name::new("splice-unquote")), read_macro_arg(rdr, &token)?])
     },
     "^"  => {
       let _ = rdr.next();
-      let meta = read_form(rdr)?;
-      Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+      let meta = read_macro_arg(rdr, &token)?;
+      Ok(list![Sym(Name::new("with-meta")), read_macro_arg(rdr, &token)?, meta])
     },
     "@"  => {
       let _ = rdr.next();
-      Ok(list![Sym("deref".to_string()), read_form(rdr)?])
+      Ok(list![Sym(Name::new("deref")), read_macro_arg(rdr, &token)?])
+    },
+    ")" | "]" | "}" => {
+      let msg = format!("unexpected '{}'", token.text);
+      Err(read_error(rdr.src, &msg, token.pos, None))
+    },
+    "("  => {
+      let mut form = read_seq(rdr, ")")?;
+      let is_fn = match form {
+        List(ref l,_) => match l.get(0) {
+          Some(Sym(ref s)) => s == "fn*",
+          _                => false,
+        },
+        _ => false,
+      };
+      if !is_fn {
+        return Ok(form);
+      }
+      // where it was read, for the function it evaluates to, see fn_source
+      let mut kvs = vec![kw("line"), Int(token.pos.line as i64),
+                         kw("column"), Int(token.pos.col as i64)];
+      if let Some(file) = rdr.file {
+        kvs.extend(vec![kw("file"), Str(file.to_string())]);
+      }
+      form.with_meta(&hash_map(kvs)?)
     },
-    ")"  => error("unexpected ')'"),
-    "("  => read_seq(rdr, ")"),
-    "]"  => error("unexpected ']'"),
     "["  => read_seq(rdr, "]"),
-    "}"  => error("unexpected '}'"),
     "{"  => read_seq(rdr, "}"),
+    "#{" => read_set(rdr),
+    "##Inf" | "##-Inf" | "##NaN" => read_atom(rdr),
+    t if t.starts_with("#\"") => read_regex(rdr),
+    t if t.starts_with("#") => read_tagged(rdr),
     _    => read_atom(rdr),
   }
 }
 
 pub fn read_str(str: String) -> MalRet {
-  let tokens = tokenize(&str);
-  //println!("tokens: {:?}", tokens);
-  if tokens.len() == 0 {
+  let mut rdr = Reader::new(&str, None);
+  if rdr.lookahead()?.is_none() {
     return error("no input");
   }
-  read_form(&mut Reader { pos: 0, tokens: tokens })
+  let form = read_form(&mut rdr)?;
+  skip_discarded(&mut rdr)?;
+  match rdr.lookahead()? {
+    Some(token) => {
+      let msg = format!("unexpected '{}' after the form", token.text);
+      Err(read_error(rdr.src, &msg, token.pos, None))
+    },
+    None        => Ok(form),
+  }
+}
+
+// top-level forms, each with the position it starts at, read one at a
+// time
+pub struct Forms<'a> {
+  rdr: Reader<'a>,
+}
+
+impl<'a> Iterator for Forms<'a> {
+  type Item = Result<(MalVal,Pos),MalErr>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let rdr = &mut self.rdr;
+    match skip_discarded(rdr).and_then(|_| rdr.lookahead()) {
+      Ok(Some(token)) => Some(read_form(rdr).map(|mv| (mv, token.pos))),
+      Ok(None)        => None,
+      Err(e)          => Some(Err(e)),
+    }
+  }
+}
+
+pub fn read_forms<'a>(str: &'a str, file: Option<&'a str>) -> Forms<'a> {
+  Forms { rdr: Reader::new(str, file) }
+}
+
+// every top-level form, with the position it starts at
+pub fn read_all(str: String) -> Result<Vec<(MalVal,Pos)>,MalErr> {
+  read_forms(&str, None).collect()
+}
+
+// lossless syntax tree, for tools that edit source: printing a tree read
+// by read_cst gives back the text it was read from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cst {
+  // whitespace, commas and comments
+  Trivia(String),
+  // an atom as written
+  Atom(String, Pos),
+  // a bracketed sequence, trivia included
+  Seq { open: String, items: Vec<Cst>, close: String, pos: Pos },
+  // a reader macro such as ' or #_, then the trivia and forms it applies to
+  Prefixed { prefix: String, items: Vec<Cst>, pos: Pos },
+}
+
+impl fmt::Display for Cst {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Cst::Trivia(s) | Cst::Atom(s, _) => write!(f, "{}", s),
+      Cst::Seq { open, items, close, .. } => {
+        write!(f, "{}", open)?;
+        for item in items { write!(f, "{}", item)?; }
+        write!(f, "{}", close)
+      },
+      Cst::Prefixed { prefix, items, .. } => {
+        write!(f, "{}", prefix)?;
+        for item in items { write!(f, "{}", item)?; }
+        Ok(())
+      },
+    }
+  }
+}
+
+fn kw(name: &str) -> MalVal {
+  Keyword(Name::new(name))
+}
+
+impl Cst {
+  // the node as mal data for read-cst: a hash-map with its :type (:trivia,
+  // :atom, :seq or :prefixed), its :text, or its :open and :close or its
+  // :prefix with its :items, and the :line and :col it starts at
+  pub fn to_mal(&self) -> MalVal {
+    let items = |items: &Vec<Cst>| vector!(items.iter().map(Cst::to_mal).collect());
+    let at = |pos: &Pos| vec![kw("line"), Int(pos.line as i64),
+                              kw("col"), Int(pos.col as i64)];
+    let kvs = match self {
+      Cst::Trivia(s) => vec![kw("type"), kw("trivia"), kw("text"), Str(s.clone())],
+      Cst::Atom(s, pos) => {
+        let mut kvs = vec![kw("type"), kw("atom"), kw("text"), Str(s.clone())];
+        kvs.extend(at(pos));
+        kvs
+      },
+      Cst::Seq { open, items: xs, close, pos } => {
+        let mut kvs = vec![kw("type"), kw("seq"), kw("open"), Str(open.clone()),
+                           kw("items"), items(xs), kw("close"), Str(close.clone())];
+        kvs.extend(at(pos));
+        kvs
+      },
+      Cst::Prefixed { prefix, items: xs, pos } => {
+        let mut kvs = vec![kw("type"), kw("prefixed"), kw("prefix"), Str(prefix.clone()),
+                           kw("items"), items(xs)];
+        kvs.extend(at(pos));
+        kvs
+      },
+    };
+    hash_map(kvs).unwrap_or(Nil)
+  }
+}
+
+// the source text of a node as read-cst gives it, or of a seq of them, so
+// that mal code can edit the tree and write it back out
+pub fn cst_str(node: &MalVal) -> Result<String,MalErr> {
+  let hm = match node {
+    Hash(hm,_) => hm,
+    _ => {
+      let mut res = String::new();
+      for x in node.seq_iter() { res.push_str(&cst_str(&x?)?) }
+      return Ok(res);
+    },
+  };
+  let text = |k: &str| match hm.get(&kw(k)) {
+    Some(Str(s)) => Ok(s.clone()),
+    Some(x) => Err(ErrString(format!("cst-str: {} must be a string, got {}",
+                                     kw(k).pr_str(true), x.pr_str(true)))),
+    None    => Ok(String::new()),
+  };
+  let mut res = text("text")? + &text("open")? + &text("prefix")?;
+  if let Some(items) = hm.get(&kw("items")) {
+    res.push_str(&cst_str(items)?);
+  }
+  res.push_str(&text("close")?);
+  Ok(res)
+}
+
+struct CstReader<'a> {
+  rdr: Reader<'a>,
+  // offset just past the last token taken
+  end: usize,
+}
+
+impl<'a> CstReader<'a> {
+  // takes the next token, putting the trivia before it in `out`
+  fn next(&mut self, out: &mut Vec<Cst>) -> Result<Token,MalErr> {
+    let token = self.rdr.next()?;
+    self.trivia(token.pos.offset, out);
+    self.end = token.pos.offset + token.text.len();
+    Ok(token)
+  }
+
+  fn trivia(&mut self, upto: usize, out: &mut Vec<Cst>) {
+    if upto > self.end {
+      out.push(Cst::Trivia(self.rdr.src[self.end..upto].to_string()));
+    }
+  }
+
+  fn seq(&mut self, open: Token, end: &str) -> Result<Cst,MalErr> {
+    let mut items = vec![];
+    loop {
+      let token = match self.rdr.lookahead()? {
+        Some(t) => t,
+        None    => {
+          let msg = format!("expected '{}', got EOF", end);
+          return Err(eof_error(self.rdr.src, &msg, self.rdr.eof(), Some(&open)));
+        },
+      };
+      if token.text == end { break }
+      if let ")" | "]" | "}" = &token.text[..] {
+        let msg = format!("expected '{}', got '{}'", end, token.text);
+        return Err(read_error(self.rdr.src, &msg, token.pos, Some(&open)));
+      }
+      self.form(&mut items)?;
+    }
+    let close = self.next(&mut items)?;
+    Ok(Cst::Seq { open: open.text, items: items, close: close.text, pos: open.pos })
+  }
+
+  // the `count` forms a reader macro applies to
+  fn prefixed(&mut self, prefix: Token, count: usize) -> Result<Cst,MalErr> {
+    let mut items = vec![];
+    for _ in 0..count {
+      if self.rdr.lookahead()?.is_none() {
+        let msg = format!("expected form after '{}', got EOF", prefix.text);
+        return Err(eof_error(self.rdr.src, &msg, self.rdr.eof(), None));
+      }
+      self.form(&mut items)?;
+    }
+    Ok(Cst::Prefixed { prefix: prefix.text, items: items, pos: prefix.pos })
+  }
+
+  // reads a form into `out`, after the trivia before it
+  fn form(&mut self, out: &mut Vec<Cst>) -> Result<(),MalErr> {
+    let token = self.next(out)?;
+    let node = match &token.text[..] {
+      "("  => self.seq(token, ")")?,
+      "["  => self.seq(token, "]")?,
+      "{" | "#{" => self.seq(token, "}")?,
+      ")" | "]" | "}" => {
+        let msg = format!("unexpected '{}'", token.text);
+        return Err(read_error(self.rdr.src, &msg, token.pos, None));
+      },
+      "^"  => self.prefixed(token, 2)?,
+      "'" | "`" | "~" | "~@" | "@" | "#_" => self.prefixed(token, 1)?,
+      "##Inf" | "##-Inf" | "##NaN" => Cst::Atom(token.text, token.pos),
+      t if t.starts_with("#") && !t.starts_with("#\"") => {
+        self.prefixed(token, 1)?
+      },
+      t if macro_token(t).is_some() => self.prefixed(token, 1)?,
+      _ => Cst::Atom(token.text, token.pos),
+    };
+    out.push(node);
+    Ok(())
+  }
+}
+
+// every top-level form with the trivia around it, for tools that need the
+// source exactly as written
+pub fn read_cst(str: &str) -> Result<Vec<Cst>,MalErr> {
+  let mut cr = CstReader { rdr: Reader::new(str, None), end: 0 };
+  let mut res = vec![];
+  while cr.rdr.lookahead()?.is_some() {
+    cr.form(&mut res)?;
+  }
+  cr.trivia(str.len(), &mut res);
+  Ok(res)
 }
 
 // vim: ts=2:sw=2:expandtab
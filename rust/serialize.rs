@@ -0,0 +1,395 @@
+// compact binary encoding of mal values, for caching them in files: a tag
+// byte per value, with LEB128 lengths and zigzag integers. Atoms keep their
+// identity, so cycles through them survive, and collections shared by
+// several parents are written once and referred back to after that
+
+use std::rc::Rc;
+use fnv::FnvHashMap;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
+
+use types::{MalVal,MalList,MalLazy,MalRet,MalErr,MalMap,MalSet,Name,error,atom};
+use types::MalVal::{Nil,Bool,Int,Bignum,Ratio,Float,Str,Keyword,Sym,Regex,List,Vector,Hash,Set,LazySeq,Func,MalFunc,Atom};
+use types::MalErr::ErrString;
+use numeric;
+
+const MAGIC: &[u8] = b"mal\x01";
+
+const NIL: u8     = 0;
+const FALSE: u8   = 1;
+const TRUE: u8    = 2;
+const INT: u8     = 3;
+const STR: u8     = 4;
+const KEYWORD: u8 = 5;
+const SYM: u8     = 6;
+const LIST: u8    = 7;
+const VECTOR: u8  = 8;
+const MAP: u8     = 9;
+// an atom, followed by its value
+const ATOM: u8    = 10;
+// the id of an atom or collection written earlier
+const REF: u8     = 11;
+// metadata, followed by the value it belongs to
+const META: u8    = 12;
+// an f64, as 8 little-endian bytes
+const FLOAT: u8   = 13;
+// a length and that many bytes of two's complement, little-endian
+const BIGINT: u8  = 14;
+// two bigints without their tags, the numerator and the denominator
+const RATIO: u8   = 15;
+const SET: u8     = 16;
+// the text of a pattern, compiled again when read
+const REGEX: u8   = 17;
+
+// values nested deeper than this are refused rather than encoded or
+// decoded recursively until the stack runs out
+const MAX_DEPTH: usize = 512;
+
+struct Encoder {
+  out: Vec<u8>,
+  // ids of the atoms and collections written so far, by address and tag.
+  // Atoms are numbered before their value is written, so a cycle back to
+  // them is a reference; collections are numbered once written
+  ids: FnvHashMap<(usize, u8), usize>,
+  next_id: usize,
+  depth: usize,
+}
+
+impl Encoder {
+  fn uint(&mut self, mut n: u64) {
+    loop {
+      let b = (n & 0x7f) as u8;
+      n >>= 7;
+      if n == 0 {
+        self.out.push(b);
+        return;
+      }
+      self.out.push(b | 0x80);
+    }
+  }
+
+  fn bytes(&mut self, tag: u8, s: &str) {
+    self.out.push(tag);
+    self.uint(s.len() as u64);
+    self.out.extend_from_slice(s.as_bytes());
+  }
+
+  fn bigint(&mut self, b: &BigInt) {
+    let bytes = b.to_signed_bytes_le();
+    self.uint(bytes.len() as u64);
+    self.out.extend_from_slice(&bytes);
+  }
+
+  fn new_id(&mut self, key: (usize, u8)) {
+    self.ids.insert(key, self.next_id);
+    self.next_id += 1;
+  }
+
+  // writes a reference instead if the value was written before
+  fn shared(&mut self, key: (usize, u8)) -> bool {
+    match self.ids.get(&key) {
+      Some(&id) => {
+        self.out.push(REF);
+        self.uint(id as u64);
+        true
+      },
+      None => false,
+    }
+  }
+
+  fn value(&mut self, mv: &MalVal) -> Result<(),MalErr> {
+    if self.depth >= MAX_DEPTH {
+      return Err(ErrString("serialize: too deeply nested".to_string()));
+    }
+    self.depth += 1;
+    let res = self.tagged(mv);
+    self.depth -= 1;
+    res
+  }
+
+  fn tagged(&mut self, mv: &MalVal) -> Result<(),MalErr> {
+    match mv {
+      List(_,meta) | Vector(_,meta) | Hash(_,meta) | Set(_,meta) |
+      LazySeq(_,meta) if **meta != Nil => {
+        self.out.push(META);
+        self.value(meta)?;
+      },
+      _ => (),
+    }
+    match mv {
+      Nil         => self.out.push(NIL),
+      Bool(false) => self.out.push(FALSE),
+      Bool(true)  => self.out.push(TRUE),
+      Int(i)      => {
+        self.out.push(INT);
+        self.uint(((i << 1) ^ (i >> 63)) as u64);
+      },
+      Bignum(b)   => {
+        self.out.push(BIGINT);
+        self.bigint(b);
+      },
+      Ratio(r)    => {
+        self.out.push(RATIO);
+        self.bigint(r.numer());
+        self.bigint(r.denom());
+      },
+      Float(f)    => {
+        self.out.push(FLOAT);
+        self.out.extend_from_slice(&f.to_bits().to_le_bytes());
+      },
+      Keyword(k)  => self.bytes(KEYWORD, k),
+      Str(s)      => self.bytes(STR, s),
+      Sym(s)      => self.bytes(SYM, s),
+      Regex(re)   => self.bytes(REGEX, re.as_str()),
+      List(l,_) | Vector(l,_) => {
+        let tag = if let List(..) = mv { LIST } else { VECTOR };
+        let key = (&**l as *const MalList as usize, tag);
+        if self.shared(key) { return Ok(()) }
+        self.out.push(tag);
+        self.uint(l.len() as u64);
+        for x in l.iter() { self.value(x)? }
+        self.new_id(key);
+      },
+      // realized, and read back as a list
+      LazySeq(ls,_) => {
+        let key = (&**ls as *const MalLazy as usize, LIST);
+        if self.shared(key) { return Ok(()) }
+        let l = mv.seq_iter().collect::<Result<Vec<MalVal>,MalErr>>()?;
+        self.out.push(LIST);
+        self.uint(l.len() as u64);
+        for x in l.iter() { self.value(x)? }
+        self.new_id(key);
+      },
+      Hash(hm,_)  => {
+        let key = (&**hm as *const MalMap as usize, MAP);
+        if self.shared(key) { return Ok(()) }
+        self.out.push(MAP);
+        self.uint(hm.len() as u64);
+        for (k, v) in hm.iter() {
+          self.value(k)?;
+          self.value(v)?;
+        }
+        self.new_id(key);
+      },
+      Set(hs,_)   => {
+        let key = (&**hs as *const MalSet as usize, SET);
+        if self.shared(key) { return Ok(()) }
+        self.out.push(SET);
+        self.uint(hs.len() as u64);
+        for x in hs.iter() { self.value(x)? }
+        self.new_id(key);
+      },
+      Atom(a)     => {
+        let key = (&**a as *const _ as usize, ATOM);
+        if self.shared(key) { return Ok(()) }
+        self.out.push(ATOM);
+        self.new_id(key);
+        self.value(&a.borrow())?;
+      },
+      MalFunc{..} => {
+        return Err(ErrString(format!("serialize: cannot serialize closure {}",
+                                     mv.pr_str(true))));
+      },
+      Func(..)    => {
+        return Err(ErrString(format!("serialize: cannot serialize function {}",
+                                     mv.pr_str(true))));
+      },
+    }
+    Ok(())
+  }
+}
+
+pub fn encode(mv: &MalVal) -> Result<Vec<u8>,MalErr> {
+  let mut enc = Encoder{out: MAGIC.to_vec(), ids: FnvHashMap::default(),
+                        next_id: 0, depth: 0};
+  enc.value(mv)?;
+  Ok(enc.out)
+}
+
+struct Decoder<'a> {
+  data: &'a [u8],
+  pos: usize,
+  // atoms and collections by id, numbered as the encoder numbers them
+  table: Vec<MalVal>,
+  // how many values the one being read is inside of
+  depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+  fn error<T>(&self, msg: &str) -> Result<T,MalErr> {
+    Err(ErrString(format!("deserialize: {} at byte {}", msg, self.pos)))
+  }
+
+  fn byte(&mut self) -> Result<u8,MalErr> {
+    match self.data.get(self.pos) {
+      Some(&b) => {
+        self.pos += 1;
+        Ok(b)
+      },
+      None => self.error("unexpected end of data"),
+    }
+  }
+
+  fn uint(&mut self) -> Result<u64,MalErr> {
+    let mut n: u64 = 0;
+    let mut shift = 0;
+    loop {
+      let b = self.byte()?;
+      if shift > 63 { return self.error("integer too long") }
+      n |= ((b & 0x7f) as u64) << shift;
+      if b & 0x80 == 0 { return Ok(n) }
+      shift += 7;
+    }
+  }
+
+  fn len(&mut self) -> Result<usize,MalErr> {
+    let n = self.uint()?;
+    if n > (self.data.len() - self.pos) as u64 {
+      return self.error("length past end of data");
+    }
+    Ok(n as usize)
+  }
+
+  fn string(&mut self) -> Result<String,MalErr> {
+    let n = self.len()?;
+    let bytes = &self.data[self.pos..self.pos + n];
+    match String::from_utf8(bytes.to_vec()) {
+      Ok(s) => {
+        self.pos += n;
+        Ok(s)
+      },
+      Err(_) => self.error("invalid UTF-8 in string"),
+    }
+  }
+
+  fn bigint(&mut self) -> Result<BigInt,MalErr> {
+    let n = self.len()?;
+    let b = BigInt::from_signed_bytes_le(&self.data[self.pos..self.pos + n]);
+    self.pos += n;
+    Ok(b)
+  }
+
+  fn values(&mut self) -> Result<Vec<MalVal>,MalErr> {
+    let n = self.len()?;
+    let mut items = Vec::with_capacity(n);
+    for _ in 0..n { items.push(self.value()?) }
+    Ok(items)
+  }
+
+  fn value(&mut self) -> MalRet {
+    if self.depth >= MAX_DEPTH {
+      return self.error("too deeply nested");
+    }
+    self.depth += 1;
+    let res = self.tagged();
+    self.depth -= 1;
+    res
+  }
+
+  fn tagged(&mut self) -> MalRet {
+    let tag = self.byte()?;
+    let res = match tag {
+      NIL     => Nil,
+      FALSE   => Bool(false),
+      TRUE    => Bool(true),
+      INT     => {
+        let n = self.uint()?;
+        Int(((n >> 1) as i64) ^ -((n & 1) as i64))
+      },
+      BIGINT  => numeric::from_bigint(self.bigint()?),
+      RATIO   => {
+        let n = self.bigint()?;
+        let d = self.bigint()?;
+        if d.is_zero() {
+          return self.error("ratio with a zero denominator");
+        }
+        numeric::from_ratio(BigRational::new(n, d))
+      },
+      FLOAT   => {
+        if self.data.len() - self.pos < 8 {
+          return self.error("unexpected end of data");
+        }
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.data[self.pos..self.pos + 8]);
+        self.pos += 8;
+        Float(f64::from_bits(u64::from_le_bytes(bytes)))
+      },
+      STR     => Str(self.string()?),
+      KEYWORD => Keyword(Name::new(&self.string()?)),
+      SYM     => Sym(Name::new(&self.string()?)),
+      REGEX   => {
+        let p = self.string()?;
+        match ::regex::Regex::new(&p) {
+          Ok(re) => Regex(Rc::new(re)),
+          Err(_) => return self.error("invalid regex"),
+        }
+      },
+      LIST    => list!(self.values()?.into()),
+      VECTOR  => vector!(self.values()?.into()),
+      MAP     => {
+        let n = self.len()?;
+        let mut hm = MalMap::default();
+        for _ in 0..n {
+          let k = self.value()?;
+          if !k.hashable_q() {
+            return self.error("map key is an atom or function");
+          }
+          let v = self.value()?;
+          hm.insert(k, v);
+        }
+        Hash(Rc::new(hm),Rc::new(Nil))
+      },
+      SET     => {
+        let n = self.len()?;
+        let mut hs = MalSet::default();
+        for _ in 0..n {
+          let x = self.value()?;
+          if !x.hashable_q() {
+            return self.error("set element is an atom or function");
+          }
+          hs.insert(x);
+        }
+        Set(Rc::new(hs),Rc::new(Nil))
+      },
+      ATOM    => {
+        let a = atom(&Nil);
+        self.table.push(a.clone());
+        let v = self.value()?;
+        a.reset_bang(&v)?;
+        return Ok(a);
+      },
+      REF     => {
+        let id = self.uint()?;
+        return match self.table.get(id as usize) {
+          Some(mv) => Ok(mv.clone()),
+          None     => self.error(&format!("reference to unknown value {}", id)),
+        };
+      },
+      META    => {
+        let meta = self.value()?;
+        return self.value()?.with_meta(&meta);
+      },
+      _ => return self.error(&format!("unknown tag {}", tag)),
+    };
+    if tag == LIST || tag == VECTOR || tag == MAP || tag == SET {
+      self.table.push(res.clone());
+    }
+    Ok(res)
+  }
+}
+
+pub fn decode(data: &[u8]) -> MalRet {
+  if !data.starts_with(MAGIC) {
+    return error("deserialize: not serialized mal data");
+  }
+  let mut dec = Decoder{data: data, pos: MAGIC.len(), table: vec![],
+                        depth: 0};
+  let res = dec.value()?;
+  if dec.pos != data.len() {
+    return dec.error("trailing data");
+  }
+  Ok(res)
+}
+
+// vim: ts=2:sw=2:expandtab
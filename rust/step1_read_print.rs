@@ -3,6 +3,10 @@ extern crate lazy_static;
 extern crate regex;
 extern crate itertools;
 extern crate fnv;
+extern crate im_rc;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
@@ -12,8 +16,13 @@ use rustyline::Editor;
 #[allow(dead_code)]
 mod types;
 use types::{format_error};
+use types::MalErr::{ErrRead};
+#[allow(dead_code)]
 mod reader;
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
+mod numeric;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
 mod env;
@@ -36,6 +45,7 @@ fn main() {
             Ok(mv) => {
               println!("{}", mv.pr_str(true));
             },
+            Err(ErrRead(re)) => println!("Error: {}\n{}", re, re.caret()),
             Err(e)  => println!("Error: {}", format_error(e)),
           }
         }
@@ -7,6 +7,10 @@ extern crate lazy_static;
 extern crate regex;
 extern crate itertools;
 extern crate fnv;
+extern crate im_rc;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
@@ -15,11 +19,15 @@ use rustyline::Editor;
 #[macro_use]
 #[allow(dead_code)]
 mod types;
-use types::{MalVal,MalArgs,MalRet,MalErr,error,format_error,func};
-use types::MalVal::{Nil,Int,Sym,List,Vector,Hash};
-use types::MalErr::{ErrString};
+use types::{MalVal,MalList,MalArgs,MalRet,MalErr,error,format_error,func,hash_map,hash_set};
+use types::MalVal::{Nil,Int,Sym,List,Vector,Hash,Set};
+use types::MalErr::{ErrString,ErrRead};
+#[allow(dead_code)]
 mod reader;
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
+mod numeric;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
 mod env;
@@ -35,26 +43,32 @@ fn read(str: &str) -> MalRet {
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
   match ast {
     Sym(sym) => {
-      Ok(env.get(sym)
+      Ok(env.get(&**sym)
         .ok_or(ErrString(format!("'{}' not found", sym)))?
         .clone())
     },
     List(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(list!(lst))
     },
     Vector(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(vector!(lst))
     },
     Hash(hm,_) => {
-      let mut new_hm: FnvHashMap<String,MalVal> = FnvHashMap::default();
+      let mut kvs: MalArgs = vec![];
       for (k,v) in hm.iter() {
-        new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+        kvs.push(eval(k.clone(), env.clone())?);
+        kvs.push(eval(v.clone(), env.clone())?);
       }
-      Ok(Hash(Rc::new(new_hm),Rc::new(Nil)))
+      hash_map(kvs)
+    },
+    Set(hs,_) => {
+      let mut xs: MalArgs = vec![];
+      for x in hs.iter() { xs.push(eval(x.clone(), env.clone())?) }
+      hash_set(xs)
     },
     _ => Ok(ast.clone()),
   }
@@ -67,7 +81,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
       match eval_ast(&ast, &env)? {
         List(ref el,_) => {
           let ref f = el[0].clone();
-          f.apply(el[1..].to_vec())
+          f.apply(el.iter().skip(1).cloned().collect())
         },
           _ => {
             error("expected a list")
@@ -118,6 +132,7 @@ fn main() {
         if line.len() > 0 {
           match rep(&line, &repl_env) {
             Ok(out) => println!("{}", out),
+            Err(ErrRead(re)) => println!("Error: {}\n{}", re, re.caret()),
             Err(e)  => println!("Error: {}", format_error(e)),
           }
         }
@@ -1,6 +1,5 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
@@ -8,19 +7,29 @@ extern crate lazy_static;
 extern crate regex;
 extern crate itertools;
 extern crate fnv;
+extern crate im_rc;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 #[macro_use]
+#[allow(dead_code)]
 mod types;
-use types::{MalVal,MalArgs,MalRet,MalErr,error,format_error};
-use types::MalVal::{Nil,Bool,Sym,List,Vector,Hash,MalFunc};
+use types::{MalVal,MalList,MalArgs,MalRet,MalErr,FnInfo,error,format_error,hash_map,hash_set};
+use types::MalErr::{ErrRead};
+use types::MalVal::{Nil,Bool,Sym,List,Vector,Hash,Set,MalFunc};
+#[allow(dead_code)]
 mod reader;
 mod printer;
+mod numeric;
 mod env;
 use env::{Env,env_new,env_get,env_set,env_sets};
+mod json;
+mod serialize;
 #[macro_use]
 mod core;
 
@@ -34,21 +43,27 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
   match ast {
     Sym(_)  => Ok(env_get(&env, &ast)?),
     List(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(list!(lst))
     },
     Vector(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(vector!(lst))
     },
     Hash(hm,_) => {
-      let mut new_hm: FnvHashMap<String,MalVal> = FnvHashMap::default();
+      let mut kvs: MalArgs = vec![];
       for (k,v) in hm.iter() {
-        new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+        kvs.push(eval(k.clone(), env.clone())?);
+        kvs.push(eval(v.clone(), env.clone())?);
       }
-      Ok(Hash(Rc::new(new_hm),Rc::new(Nil)))
+      hash_map(kvs)
+    },
+    Set(hs,_) => {
+      let mut xs: MalArgs = vec![];
+      for x in hs.iter() { xs.push(eval(x.clone(), env.clone())?) }
+      hash_set(xs)
     },
     _ => Ok(ast.clone()),
   }
@@ -87,8 +102,8 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
           eval(a2, let_env)
         },
         Sym(ref a0sym) if a0sym == "do" => {
-          match eval_ast(&list!(l[1..].to_vec()), &env)? {
-            List(el,_) => Ok(el.last().unwrap_or(&Nil).clone()),
+          match eval_ast(&list!(l.skip(1)), &env)? {
+            List(el,_) => Ok(el.back().unwrap_or(&Nil).clone()),
             _ => error("invalid do form"),
           }
         },
@@ -109,13 +124,14 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
           let (a1, a2) = (l[1].clone(), l[2].clone());
           Ok(MalFunc{eval: eval, ast: Rc::new(a2), env: env,
                      params: Rc::new(a1), is_macro: false,
+                     info: Rc::new(FnInfo::default()),
                      meta: Rc::new(Nil)})
         },
         _ => {
           match eval_ast(&ast, &env)? {
             List(ref el,_) => {
               let ref f = el[0].clone();
-              f.apply(el[1..].to_vec())
+              f.apply(el.iter().skip(1).cloned().collect())
             },
             _ => {
               error("expected a list")
@@ -136,6 +152,8 @@ fn print(ast: &MalVal) -> String {
 fn rep(str: &str, env: &Env) -> Result<String,MalErr> {
   let ast = read(str)?;
   let exp = eval(ast, env.clone())?;
+  // before printing, so an error in a lazy seq is reported
+  exp.realize()?;
   Ok(print(&exp))
 }
 
@@ -165,6 +183,7 @@ fn main() {
         if line.len() > 0 {
           match rep(&line, &repl_env) {
             Ok(out) => println!("{}", out),
+            Err(ErrRead(re)) => println!("Error: {}\n{}", re, re.caret()),
             Err(e)  => println!("Error: {}", format_error(e)),
           }
         }
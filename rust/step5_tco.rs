@@ -1,6 +1,5 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
@@ -8,19 +7,29 @@ extern crate lazy_static;
 extern crate regex;
 extern crate itertools;
 extern crate fnv;
+extern crate im_rc;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 #[macro_use]
+#[allow(dead_code)]
 mod types;
-use types::{MalVal,MalArgs,MalRet,MalErr,error,format_error};
-use types::MalVal::{Nil,Bool,Sym,List,Vector,Hash,Func,MalFunc};
+use types::{MalVal,MalList,MalArgs,MalRet,MalErr,FnInfo,error,format_error,hash_map,hash_set};
+use types::MalErr::{ErrRead};
+use types::MalVal::{Nil,Bool,Sym,List,Vector,Hash,Set,Func,MalFunc};
+#[allow(dead_code)]
 mod reader;
 mod printer;
+mod numeric;
 mod env;
 use env::{Env,env_new,env_bind,env_get,env_set,env_sets};
+mod json;
+mod serialize;
 #[macro_use]
 mod core;
 
@@ -34,21 +43,27 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
   match ast {
     Sym(_)  => Ok(env_get(&env, &ast)?),
     List(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(list!(lst))
     },
     Vector(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(vector!(lst))
     },
     Hash(hm,_) => {
-      let mut new_hm: FnvHashMap<String,MalVal> = FnvHashMap::default();
+      let mut kvs: MalArgs = vec![];
       for (k,v) in hm.iter() {
-        new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+        kvs.push(eval(k.clone(), env.clone())?);
+        kvs.push(eval(v.clone(), env.clone())?);
       }
-      Ok(Hash(Rc::new(new_hm),Rc::new(Nil)))
+      hash_map(kvs)
+    },
+    Set(hs,_) => {
+      let mut xs: MalArgs = vec![];
+      for x in hs.iter() { xs.push(eval(x.clone(), env.clone())?) }
+      hash_set(xs)
     },
     _ => Ok(ast.clone()),
   }
@@ -92,9 +107,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
           continue 'tco;
         },
         Sym(ref a0sym) if a0sym == "do" => {
-          match eval_ast(&list!(l[1..l.len()-1].to_vec()), &env)? {
+          match eval_ast(&list!(l.skip(1).take(l.len()-2)), &env)? {
             List(_,_) => {
-              ast = l.last().unwrap_or(&Nil).clone();
+              ast = l.back().unwrap_or(&Nil).clone();
               continue 'tco;
             },
             _ => error("invalid do form"),
@@ -119,15 +134,16 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
           let (a1, a2) = (l[1].clone(), l[2].clone());
           Ok(MalFunc{eval: eval, ast: Rc::new(a2), env: env,
                      params: Rc::new(a1), is_macro: false,
+                     info: Rc::new(FnInfo::default()),
                      meta: Rc::new(Nil)})
         },
         _ => {
           match eval_ast(&ast, &env)? {
             List(ref el,_) => {
               let ref f = el[0].clone();
-              let args = el[1..].to_vec();
+              let args = el.iter().skip(1).cloned().collect();
               match f {
-                Func(_,_) => f.apply(args),
+                Func(..) => f.apply(args),
                 MalFunc{ast: mast, env: menv, params, ..} => {
                   let a = &**mast;
                   let p = &**params;
@@ -163,6 +179,8 @@ fn print(ast: &MalVal) -> String {
 fn rep(str: &str, env: &Env) -> Result<String,MalErr> {
   let ast = read(str)?;
   let exp = eval(ast, env.clone())?;
+  // before printing, so an error in a lazy seq is reported
+  exp.realize()?;
   Ok(print(&exp))
 }
 
@@ -192,6 +210,7 @@ fn main() {
         if line.len() > 0 {
           match rep(&line, &repl_env) {
             Ok(out) => println!("{}", out),
+            Err(ErrRead(re)) => println!("Error: {}\n{}", re, re.caret()),
             Err(e)  => println!("Error: {}", format_error(e)),
           }
         }
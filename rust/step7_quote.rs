@@ -1,6 +1,6 @@
 use std::rc::Rc;
+use std::fs;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
@@ -8,19 +8,29 @@ extern crate lazy_static;
 extern crate regex;
 extern crate itertools;
 extern crate fnv;
+extern crate im_rc;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 #[macro_use]
+#[allow(dead_code)]
 mod types;
-use types::{MalVal,MalArgs,MalRet,MalErr,error,format_error};
-use types::MalVal::{Nil,Bool,Str,Sym,List,Vector,Hash,Func,MalFunc};
+use types::{MalVal,MalList,MalArgs,MalRet,MalErr,FnInfo,Name,error,format_error,hash_map,hash_set};
+use types::MalErr::{ErrString,ErrRead};
+use types::MalVal::{Nil,Bool,Str,Sym,List,Vector,Hash,Set,Func,MalFunc};
+#[allow(dead_code)]
 mod reader;
 mod printer;
+mod numeric;
 mod env;
 use env::{Env,env_new,env_bind,env_get,env_set,env_sets};
+mod json;
+mod serialize;
 #[macro_use]
 mod core;
 
@@ -41,27 +51,27 @@ fn quasiquote(ast: &MalVal) -> MalVal {
             List(ref v0,_) | Vector(ref v0,_) if v0.len() > 0 => {
               match v0[0] {
                 Sym(ref s) if s == "splice-unquote" => {
-                  list![Sym("concat".to_string()),
+                  list![Sym(Name::new("concat")),
                         v0[1].clone(),
-                        quasiquote(&list!(v[1..].to_vec()))]
+                        quasiquote(&list!(v.skip(1)))]
                 },
                 _ => {
-                  list![Sym("cons".to_string()),
+                  list![Sym(Name::new("cons")),
                         quasiquote(a0),
-                        quasiquote(&list!(v[1..].to_vec()))]
+                        quasiquote(&list!(v.skip(1)))]
                 },
               }
             },
             _ => {
-              list![Sym("cons".to_string()),
+              list![Sym(Name::new("cons")),
                     quasiquote(a0),
-                    quasiquote(&list!(v[1..].to_vec()))]
+                    quasiquote(&list!(v.skip(1)))]
             }
           }
         }
       }
     },
-    _ => list![Sym("quote".to_string()), ast.clone()]
+    _ => list![Sym(Name::new("quote")), ast.clone()]
   }
 }
 
@@ -69,21 +79,27 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
   match ast {
     Sym(_)  => Ok(env_get(&env, &ast)?),
     List(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(list!(lst))
     },
     Vector(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(vector!(lst))
     },
     Hash(hm,_) => {
-      let mut new_hm: FnvHashMap<String,MalVal> = FnvHashMap::default();
+      let mut kvs: MalArgs = vec![];
       for (k,v) in hm.iter() {
-        new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+        kvs.push(eval(k.clone(), env.clone())?);
+        kvs.push(eval(v.clone(), env.clone())?);
       }
-      Ok(Hash(Rc::new(new_hm),Rc::new(Nil)))
+      hash_map(kvs)
+    },
+    Set(hs,_) => {
+      let mut xs: MalArgs = vec![];
+      for x in hs.iter() { xs.push(eval(x.clone(), env.clone())?) }
+      hash_set(xs)
     },
     _ => Ok(ast.clone()),
   }
@@ -134,9 +150,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
           continue 'tco;
         },
         Sym(ref a0sym) if a0sym == "do" => {
-          match eval_ast(&list!(l[1..l.len()-1].to_vec()), &env)? {
+          match eval_ast(&list!(l.skip(1).take(l.len()-2)), &env)? {
             List(_,_) => {
-              ast = l.last().unwrap_or(&Nil).clone();
+              ast = l.back().unwrap_or(&Nil).clone();
               continue 'tco;
             },
             _ => error("invalid do form"),
@@ -161,8 +177,19 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
           let (a1, a2) = (l[1].clone(), l[2].clone());
           Ok(MalFunc{eval: eval, ast: Rc::new(a2), env: env,
                      params: Rc::new(a1), is_macro: false,
+                     info: Rc::new(FnInfo::default()),
                      meta: Rc::new(Nil)})
         },
+        Sym(ref a0sym) if a0sym == "load-file" => {
+          if l.len() != 2 {
+            return error(&format!("load-file: wrong number of args ({})", l.len() - 1));
+          }
+          let f = eval(l[1].clone(), env.clone())?;
+          while let Some(ref e) = env.clone().outer {
+            env = e.clone();
+          }
+          load_file(&f, &env)
+        },
         Sym(ref a0sym) if a0sym == "eval" => {
           ast = eval(l[1].clone(), env.clone())?;
           while let Some(ref e) = env.clone().outer {
@@ -174,9 +201,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
           match eval_ast(&ast, &env)? {
             List(ref el,_) => {
               let ref f = el[0].clone();
-              let args = el[1..].to_vec();
+              let args = el.iter().skip(1).cloned().collect();
               match f {
-                Func(_,_) => f.apply(args),
+                Func(..) => f.apply(args),
                 MalFunc{ast: mast, env: menv, params, ..} => {
                   let a = &**mast;
                   let p = &**params;
@@ -204,6 +231,32 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
   ret
 }
 
+// evaluates the forms of a file one by one, reporting where errors
+// happen
+fn load_file(f: &MalVal, env: &Env) -> MalRet {
+  let path = match f {
+    Str(s) => s,
+    _ => return error("load-file: path is not Str"),
+  };
+  let src = match fs::read_to_string(path) {
+    Ok(src) => src,
+    Err(e) => return error(&format!("{}: {}", path, e)),
+  };
+  let mut res = Nil;
+  for form in reader::read_forms(&src, Some(path)) {
+    let (ast, pos) = match form {
+      Ok(form) => form,
+      Err(ErrRead(re)) => return error(&format!("{}:{}", path, re)),
+      Err(e) => return Err(e),
+    };
+    res = match eval(ast, env.clone()) {
+      Err(ErrString(s)) => return error(&format!("{}:{}: {}", path, pos, s)),
+      r => r?,
+    };
+  }
+  Ok(res)
+}
+
 // print
 fn print(ast: &MalVal) -> String {
   ast.pr_str(true)
@@ -212,6 +265,8 @@ fn print(ast: &MalVal) -> String {
 fn rep(str: &str, env: &Env) -> Result<String,MalErr> {
   let ast = read(str)?;
   let exp = eval(ast, env.clone())?;
+  // before printing, so an error in a lazy seq is reported
+  exp.realize()?;
   Ok(print(&exp))
 }
 
@@ -234,7 +289,6 @@ fn main() {
 
   // core.mal: defined using the language itself
   let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
-  let _ = rep("(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \")\")))))", &repl_env);
 
   // Invoked with arguments
   if let Some(f) = arg1 {
@@ -257,6 +311,7 @@ fn main() {
         if line.len() > 0 {
           match rep(&line, &repl_env) {
             Ok(out) => println!("{}", out),
+            Err(ErrRead(re)) => println!("Error: {}\n{}", re, re.caret()),
             Err(e)  => println!("Error: {}", format_error(e)),
           }
         }
@@ -1,8 +1,8 @@
 #![allow(non_snake_case)]
 
 use std::rc::Rc;
+use std::fs;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
 #[macro_use]
@@ -10,6 +10,10 @@ extern crate lazy_static;
 extern crate regex;
 extern crate itertools;
 extern crate fnv;
+extern crate im_rc;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
@@ -17,13 +21,16 @@ use rustyline::Editor;
 
 #[macro_use]
 mod types;
-use types::{MalVal,MalArgs,MalRet,MalErr,error,format_error};
-use types::MalVal::{Nil,Bool,Str,Sym,List,Vector,Hash,Func,MalFunc};
-use types::MalErr::{ErrString,ErrMalVal};
+use types::{MalVal,MalList,MalArgs,MalRet,MalErr,FnInfo,Name,error,format_error,hash_map,hash_set,lazy_seq};
+use types::MalVal::{Nil,Bool,Int,Str,Sym,List,Vector,Hash,Set,Func,MalFunc};
+use types::MalErr::{ErrString,ErrMalVal,ErrRead};
 mod reader;
 mod printer;
+mod numeric;
 mod env;
 use env::{Env,env_new,env_bind,env_find,env_get,env_set,env_sets};
+mod json;
+mod serialize;
 #[macro_use]
 mod core;
 
@@ -44,27 +51,27 @@ fn quasiquote(ast: &MalVal) -> MalVal {
             List(ref v0,_) | Vector(ref v0,_) if v0.len() > 0 => {
               match v0[0] {
                 Sym(ref s) if s == "splice-unquote" => {
-                  list![Sym("concat".to_string()),
+                  list![Sym(Name::new("concat")),
                         v0[1].clone(),
-                        quasiquote(&list!(v[1..].to_vec()))]
+                        quasiquote(&list!(v.skip(1)))]
                 },
                 _ => {
-                  list![Sym("cons".to_string()),
+                  list![Sym(Name::new("cons")),
                         quasiquote(a0),
-                        quasiquote(&list!(v[1..].to_vec()))]
+                        quasiquote(&list!(v.skip(1)))]
                 },
               }
             },
             _ => {
-              list![Sym("cons".to_string()),
+              list![Sym(Name::new("cons")),
                     quasiquote(a0),
-                    quasiquote(&list!(v[1..].to_vec()))]
+                    quasiquote(&list!(v.skip(1)))]
             }
           }
         }
       }
     },
-    _ => list![Sym("quote".to_string()), ast.clone()]
+    _ => list![Sym(Name::new("quote")), ast.clone()]
   }
 }
 
@@ -77,7 +84,7 @@ fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal,MalArgs)> {
             Some(e) => {
               match env_get(&e, &v[0]) {
                 Ok(f @ MalFunc{is_macro: true, ..}) => {
-                  Some((f, v[1..].to_vec()))
+                  Some((f, v.iter().skip(1).cloned().collect()))
                 },
                 _ => None,
               }
@@ -110,21 +117,27 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
   match ast {
     Sym(_)  => Ok(env_get(&env, &ast)?),
     List(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(list!(lst))
     },
     Vector(v,_) => {
-      let mut lst: MalArgs = vec![];
-      for a in v.iter() { lst.push(eval(a.clone(), env.clone())?) }
+      let mut lst = MalList::new();
+      for a in v.iter() { lst.push_back(eval(a.clone(), env.clone())?) }
       Ok(vector!(lst))
     },
     Hash(hm,_) => {
-      let mut new_hm: FnvHashMap<String,MalVal> = FnvHashMap::default();
+      let mut kvs: MalArgs = vec![];
       for (k,v) in hm.iter() {
-        new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+        kvs.push(eval(k.clone(), env.clone())?);
+        kvs.push(eval(v.clone(), env.clone())?);
       }
-      Ok(Hash(Rc::new(new_hm),Rc::new(Nil)))
+      hash_map(kvs)
+    },
+    Set(hs,_) => {
+      let mut xs: MalArgs = vec![];
+      for x in hs.iter() { xs.push(eval(x.clone(), env.clone())?) }
+      hash_set(xs)
     },
     _ => Ok(ast.clone()),
   }
@@ -136,7 +149,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
   'tco: loop {
 
   ret = match ast.clone() {
-    List(l,_) => {
+    List(l,lmeta) => {
       match macroexpand(ast.clone(), &env) {
         (true, Ok(new_ast)) => {
           ast = new_ast;
@@ -150,7 +163,11 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
       let a0 = &l[0];
       match a0 {
         Sym(ref a0sym) if a0sym == "def!" => {
-          env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
+          let val = eval(l[2].clone(), env.clone())?;
+          match l[1] {
+            Sym(ref name) => env_set(&env, l[1].clone(), val.named(name)),
+            _ => env_set(&env, l[1].clone(), val),
+          }
         },
         Sym(ref a0sym) if a0sym == "let*" => {
           env = env_new(Some(env.clone()));
@@ -187,11 +204,14 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
           let (a1, a2) = (l[1].clone(), l[2].clone());
           let r = eval(a2, env.clone())?;
           match r {
-            MalFunc{eval, ast, env, params, ..} => {
-              Ok(env_set(&env, a1.clone(),
-                 MalFunc{eval: eval, ast: ast.clone(), env: env.clone(),
-                         params: params.clone(), is_macro: true,
-                         meta: Rc::new(Nil)})?)
+            MalFunc{eval, ast, env, params, info, ..} => {
+              let m = MalFunc{eval: eval, ast: ast.clone(), env: env.clone(),
+                              params: params.clone(), is_macro: true,
+                              info: info.clone(), meta: Rc::new(Nil)};
+              match a1 {
+                Sym(ref name) => Ok(env_set(&env, a1.clone(), m.named(name))?),
+                _ => Ok(env_set(&env, a1.clone(), m)?),
+              }
             },
               _ => error("set_macro on non-function"),
           }
@@ -208,11 +228,12 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
               let exc = match e {
                 ErrMalVal(mv) => mv.clone(),
                 ErrString(s)  => Str(s.to_string()),
+                ErrRead(re)   => Str(re.to_string()),
               };
               match l[2].clone() {
                 List(c,_) => {
                   let catch_env = env_bind(Some(env.clone()),
-                                           list!(vec![c[1].clone()]),
+                                           list!(MalList::unit(c[1].clone())),
                                            vec![exc])?;
                   eval(c[2].clone(), catch_env)
                 },
@@ -223,9 +244,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
           }
         },
         Sym(ref a0sym) if a0sym == "do" => {
-          match eval_ast(&list!(l[1..l.len()-1].to_vec()), &env)? {
+          match eval_ast(&list!(l.skip(1).take(l.len()-2)), &env)? {
             List(_,_) => {
-              ast = l.last().unwrap_or(&Nil).clone();
+              ast = l.back().unwrap_or(&Nil).clone();
               continue 'tco;
             },
             _ => error("invalid do form"),
@@ -247,10 +268,39 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
           }
         },
         Sym(ref a0sym) if a0sym == "fn*" => {
-          let (a1, a2) = (l[1].clone(), l[2].clone());
+          // (fn* params body) or (fn* params "docstring" body)
+          let (a1, doc, a2) = match l[2] {
+            Str(ref s) if l.len() > 3 => {
+              (l[1].clone(), Some(s.to_string()), l[3].clone())
+            },
+            _ => (l[1].clone(), None, l[2].clone()),
+          };
+          let info = FnInfo{name: None, doc: doc,
+                            source: reader::fn_source(&lmeta)};
           Ok(MalFunc{eval: eval, ast: Rc::new(a2), env: env,
                      params: Rc::new(a1), is_macro: false,
-                     meta: Rc::new(Nil)})
+                     info: Rc::new(info), meta: Rc::new(Nil)})
+        },
+        Sym(ref a0sym) if a0sym == "lazy-seq" => {
+          // (lazy-seq body ...) evaluates its body in this env when the
+          // seq is first realized; without a body it is empty
+          if l.len() < 2 {
+            return Ok(lazy_seq(|| Ok(Nil)));
+          }
+          let mut body = l.skip(1);
+          body.push_front(Sym(Name::new("do")));
+          let (body, env) = (list!(body), env.clone());
+          Ok(lazy_seq(move || eval(body.clone(), env.clone())))
+        },
+        Sym(ref a0sym) if a0sym == "load-file" => {
+          if l.len() != 2 {
+            return error(&format!("load-file: wrong number of args ({})", l.len() - 1));
+          }
+          let f = eval(l[1].clone(), env.clone())?;
+          while let Some(ref e) = env.clone().outer {
+            env = e.clone();
+          }
+          load_file(&f, &env)
         },
         Sym(ref a0sym) if a0sym == "eval" => {
           ast = eval(l[1].clone(), env.clone())?;
@@ -263,9 +313,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
           match eval_ast(&ast, &env)? {
             List(ref el,_) => {
               let ref f = el[0].clone();
-              let args = el[1..].to_vec();
+              let args = el.iter().skip(1).cloned().collect();
               match f {
-                Func(_,_) => f.apply(args),
+                Func(..) => f.apply(args),
                 MalFunc{ast: mast, env: menv, params, ..} => {
                   let a = &**mast;
                   let p = &**params;
@@ -293,14 +343,52 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
   ret
 }
 
+// evaluates the forms of a file one by one, reporting where errors
+// happen
+fn load_file(f: &MalVal, env: &Env) -> MalRet {
+  let path = match f {
+    Str(s) => s,
+    _ => return error("load-file: path is not Str"),
+  };
+  let src = match fs::read_to_string(path) {
+    Ok(src) => src,
+    Err(e) => return error(&format!("{}: {}", path, e)),
+  };
+  let mut res = Nil;
+  for form in reader::read_forms(&src, Some(path)) {
+    let (ast, pos) = match form {
+      Ok(form) => form,
+      Err(ErrRead(re)) => return error(&format!("{}:{}", path, re)),
+      Err(e) => return Err(e),
+    };
+    res = match eval(ast, env.clone()) {
+      Err(ErrString(s)) => return error(&format!("{}:{}: {}", path, pos, s)),
+      r => r?,
+    };
+  }
+  Ok(res)
+}
+
 // print
 fn print(ast: &MalVal) -> String {
   ast.pr_str(true)
 }
 
+// REPL results are pretty printed when *repl-pprint* is true, or is a
+// number giving the width to print in
+fn repl_print(ast: &MalVal, env: &Env) -> String {
+  match env_get(env, &Sym(Name::new("*repl-pprint*"))) {
+    Ok(Bool(true))      => ast.pprint(80),
+    Ok(Int(w)) if w > 0 => ast.pprint(w as usize),
+    _                   => print(ast),
+  }
+}
+
 fn rep(str: &str, env: &Env) -> Result<String,MalErr> {
   let ast = read(str)?;
   let exp = eval(ast, env.clone())?;
+  // before printing, so an error in a lazy seq is reported
+  exp.realize()?;
   Ok(print(&exp))
 }
 
@@ -323,8 +411,8 @@ fn main() {
 
   // core.mal: defined using the language itself
   let _ = rep("(def! *host-language* \"rust\")", &repl_env);
+  let _ = rep("(def! *repl-pprint* false)", &repl_env);
   let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
-  let _ = rep("(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \")\")))))", &repl_env);
   let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
 	let _ = rep("(def! *gensym-counter* (atom 0))", &repl_env);
 	let _ = rep("(def! gensym (fn* [] (symbol (str \"G__\" (swap! *gensym-counter* (fn* [x] (+ 1 x)))))))", &repl_env);
@@ -344,20 +432,39 @@ fn main() {
 
   // main repl loop
   let _ = rep("(println (str \"Mal [\" *host-language* \"]\"))", &repl_env);
+  // lines of an entry that doesn't yet read as complete forms
+  let mut pending: Vec<String> = vec![];
   loop {
-    let readline = rl.readline("user> ");
+    let prompt = if pending.is_empty() { "user> " } else { " ...> " };
+    let readline = rl.readline(prompt);
     match readline {
       Ok(line) => {
-        rl.add_history_entry(&line);
+        // input that isn't a terminal keeps its line ending
+        pending.push(line.trim_end_matches(|c| c == '\n' || c == '\r').to_string());
+        let res = reader::read_all(pending.join("\n"));
+        if let Err(ErrRead(ref re)) = res {
+          if re.incomplete { continue }
+        }
+        // the history file holds one entry per line, so a multi-line
+        // entry is recalled as a single line
+        rl.add_history_entry(&pending.join(" "));
         rl.save_history(".mal-history").unwrap();
-        if line.len() > 0 {
-          match rep(&line, &repl_env) {
-            Ok(out) => println!("{}", out),
-            Err(e)  => println!("Error: {}", format_error(e)),
-          }
+        pending.clear();
+        match res {
+          Ok(forms) => for (ast, _) in forms {
+            match eval(ast, repl_env.clone()).and_then(|exp| exp.realize().map(|_| exp)) {
+              Ok(exp) => println!("{}", repl_print(&exp, &repl_env)),
+              Err(e)  => {
+                println!("Error: {}", format_error(e));
+                break;
+              },
+            }
+          },
+          Err(ErrRead(re)) => println!("Error: {}\n{}", re, re.caret()),
+          Err(e)  => println!("Error: {}", format_error(e)),
         }
       },
-      Err(ReadlineError::Interrupted) => continue,
+      Err(ReadlineError::Interrupted) => pending.clear(),
       Err(ReadlineError::Eof) => break,
       Err(err) => {
         println!("Error: {:?}", err);
@@ -1,45 +1,337 @@
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::fmt;
+use std::ops::Deref;
+use std::cmp::Ordering;
+use std::mem;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use std::hash::{Hash as StdHash,Hasher};
+use fnv::{FnvBuildHasher,FnvHasher,FnvHashSet};
+use im_rc;
+use num_bigint::BigInt;
+use num_rational::BigRational;
 use itertools::Itertools;
+use regex::Regex as Re;
 
-use types::MalErr::{ErrString,ErrMalVal};
-use types::MalVal::{Nil,Bool,Int,Str,Sym,List,Vector,Hash,Func,MalFunc,Atom};
+use types::MalErr::{ErrString,ErrMalVal,ErrRead};
+use types::MalVal::{Nil,Bool,Int,Bignum,Ratio,Float,Str,Keyword,Sym,Regex,List,Vector,Hash,Set,LazySeq,Func,MalFunc,Atom};
 use env::{Env,env_bind};
+use numeric;
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    //Float(f64),
+    // only for ints outside the range of i64
+    Bignum(Rc<BigInt>),
+    // never with a denominator of 1
+    Ratio(Rc<BigRational>),
+    Float(f64),
     Str(String),
-    Sym(String),
-    List(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
-    Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
+    Keyword(Name),
+    Sym(Name),
+    // a compiled pattern, equal to another with the same text
+    Regex(Rc<Re>),
+    List(Rc<MalList>, Rc<MalVal>),
+    Vector(Rc<MalList>, Rc<MalVal>),
+    Hash(Rc<MalMap>, Rc<MalVal>),
+    Set(Rc<MalSet>, Rc<MalVal>),
+    LazySeq(Rc<MalLazy>, Rc<MalVal>),
+    Func(fn(MalArgs) -> MalRet, Rc<FnInfo>, Rc<MalVal>),
     MalFunc {
       eval: fn(ast: MalVal, env: Env) -> MalRet,
       ast: Rc<MalVal>,
       env: Env,
       params: Rc<MalVal>,
       is_macro: bool,
+      info: Rc<FnInfo>,
       meta: Rc<MalVal>,
     },
     Atom(Rc<RefCell<MalVal>>),
 }
 
+// the text of a symbol or keyword, interned: every Name with the same text
+// shares one allocation, so names compare and hash by address
+#[derive(Clone)]
+pub struct Name(Rc<str>);
+
+thread_local! {
+  static NAMES: RefCell<FnvHashSet<Rc<str>>> = RefCell::new(FnvHashSet::default());
+}
+
+impl Name {
+  pub fn new(s: &str) -> Name {
+    NAMES.with(|names| {
+      let mut names = names.borrow_mut();
+      if let Some(n) = names.get(s) {
+        return Name(n.clone());
+      }
+      let n: Rc<str> = Rc::from(s);
+      names.insert(n.clone());
+      Name(n)
+    })
+  }
+}
+
+impl Deref for Name {
+  type Target = str;
+  fn deref(&self) -> &str {
+    &self.0
+  }
+}
+
+impl PartialEq for Name {
+  fn eq(&self, other: &Name) -> bool {
+    Rc::ptr_eq(&self.0, &other.0)
+  }
+}
+
+impl Eq for Name {}
+
+impl PartialEq<str> for Name {
+  fn eq(&self, other: &str) -> bool {
+    &*self.0 == other
+  }
+}
+
+impl StdHash for Name {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    (&*self.0 as *const str as *const u8 as usize).hash(state)
+  }
+}
+
+impl fmt::Display for Name {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl fmt::Debug for Name {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{:?}", &*self.0)
+  }
+}
+
+// what is known about a function besides how to call it: the name it was
+// first defined as, its docstring and where it was defined
+#[derive(Debug, Clone, Default)]
+pub struct FnInfo {
+  pub name: Option<String>,
+  pub doc: Option<String>,
+  pub source: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum MalErr {
   ErrString(String),
   ErrMalVal(MalVal),
+  ErrRead(Box<ReadErr>),
+}
+
+// position in reader input: byte offset plus 1-based line and column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pos {
+  pub offset: usize,
+  pub line: usize,
+  pub col: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadErr {
+  pub msg: String,
+  pub pos: Pos,
+  // the opening token left unclosed, and where it was
+  pub unclosed: Option<(String, Pos)>,
+  // text of the input line containing pos
+  pub src_line: String,
+  // the input ended mid-form, so more input could complete it
+  pub incomplete: bool,
 }
 
 pub type MalArgs = Vec<MalVal>;
+// the elements of a list or vector: a persistent RRB vector, so pushing at
+// either end, rest and concatenation share structure with the original
+// instead of copying it
+pub type MalList = im_rc::Vector<MalVal>;
 pub type MalRet = Result<MalVal,MalErr>;
 
+// hash-map entries, kept in insertion order so that printing, keys and vals
+// come out the same way every run. Both halves are persistent: a HAMT from
+// each key to when it was inserted, and the entries ordered by when they
+// were inserted, so a clone to assoc or dissoc on is cheap
+#[derive(Debug, Clone, Default)]
+pub struct MalMap {
+  entries: im_rc::HashMap<MalVal, u64, FnvBuildHasher>,
+  order: im_rc::OrdMap<u64, (MalVal, MalVal)>,
+  next: u64,
+}
+
+impl MalMap {
+  pub fn len(&self) -> usize {
+    self.order.len()
+  }
+
+  pub fn get(&self, k: &MalVal) -> Option<&MalVal> {
+    self.entries.get(k).map(|i| &self.order[i].1)
+  }
+
+  pub fn contains_key(&self, k: &MalVal) -> bool {
+    self.entries.contains_key(k)
+  }
+
+  // a key already present keeps its place, and the key it was first
+  // inserted with, as (assoc {1 :a} 1.0 :b) is {1 :b}
+  pub fn insert(&mut self, k: MalVal, v: MalVal) {
+    if let Some(i) = self.entries.get(&k) {
+      if let Some(e) = self.order.get_mut(i) {
+        e.1 = v;
+      }
+      return;
+    }
+    self.entries.insert(k.clone(), self.next);
+    self.order.insert(self.next, (k, v));
+    self.next += 1;
+  }
+
+  pub fn remove(&mut self, k: &MalVal) {
+    if let Some(i) = self.entries.remove(k) {
+      self.order.remove(&i);
+    }
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item=(&MalVal, &MalVal)> {
+    self.order.values().map(|(k, v)| (k, v))
+  }
+
+  pub fn keys(&self) -> impl Iterator<Item=&MalVal> {
+    self.iter().map(|(k, _)| k)
+  }
+
+  pub fn values(&self) -> impl Iterator<Item=&MalVal> {
+    self.iter().map(|(_, v)| v)
+  }
+}
+
+// set elements, in insertion order like hash-map keys
+#[derive(Debug, Clone, Default)]
+pub struct MalSet(MalMap);
+
+impl MalSet {
+  pub fn len(&self) -> usize {
+    self.0.len()
+  }
+
+  pub fn contains(&self, x: &MalVal) -> bool {
+    self.0.contains_key(x)
+  }
+
+  pub fn insert(&mut self, x: MalVal) {
+    self.0.insert(x, Nil)
+  }
+
+  pub fn remove(&mut self, x: &MalVal) {
+    self.0.remove(x)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item=&MalVal> {
+    self.0.keys()
+  }
+}
+
+// a sequence whose elements are worked out as they are asked for. Until
+// then it holds a thunk giving the seq it stands for (nil, a list or vector,
+// or another lazy seq), or a step: a seq to realize first and a function
+// given what uncons makes of it. Once realized, its first element and the
+// seq of the rest, which may itself be lazy. A thunk runs at most once per
+// cell
+pub struct MalLazy(RefCell<LazyState>);
+
+// what uncons gives: the first element and the rest, or None when empty
+pub type Uncons = Option<(MalVal,MalVal)>;
+
+type StepFn = Rc<dyn Fn(Uncons) -> MalRet>;
+
+#[derive(Clone)]
+enum LazyState {
+  Thunk(Rc<dyn Fn() -> MalRet>),
+  Step(MalVal, StepFn),
+  Empty,
+  Cons(MalVal, MalVal),
+}
+
+impl fmt::Debug for MalLazy {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self.0.borrow() {
+      LazyState::Thunk(_) | LazyState::Step(..) => {
+        write!(f, "LazySeq(<unrealized>)")
+      },
+      LazyState::Empty              => write!(f, "LazySeq()"),
+      LazyState::Cons(ref x, ref r) => write!(f, "LazySeq({:?}, {:?})", x, r),
+    }
+  }
+}
+
+// a long realized seq is a long chain of cells, and so are many steps over
+// one another; unlink it a cell at a time rather than letting each cell drop
+// the next one recursively
+impl Drop for MalLazy {
+  fn drop(&mut self) {
+    let mut next = match mem::replace(self.0.get_mut(), LazyState::Empty) {
+      LazyState::Cons(_, rest) | LazyState::Step(rest, _) => rest,
+      _ => return,
+    };
+    while let LazySeq(ls,_) = next {
+      next = match Rc::try_unwrap(ls) {
+        Ok(mut ls) => match mem::replace(ls.0.get_mut(), LazyState::Empty) {
+          LazyState::Cons(_, rest) | LazyState::Step(rest, _) => rest,
+          _ => return,
+        },
+        Err(_) => return,
+      };
+    }
+  }
+}
+
+pub fn lazy_seq<F: Fn() -> MalRet + 'static>(f: F) -> MalVal {
+  LazySeq(Rc::new(MalLazy(RefCell::new(LazyState::Thunk(Rc::new(f))))),
+          Rc::new(Nil))
+}
+
+// the seq f gives for what uncons makes of s, realizing s only when this
+// is realized. Unlike a thunk calling uncons itself, steps over steps are
+// realized in a loop, so a deep stack of them can't overflow the stack
+pub fn lazy_step<F: Fn(Uncons) -> MalRet + 'static>(s: MalVal, f: F) -> MalVal {
+  LazySeq(Rc::new(MalLazy(RefCell::new(LazyState::Step(s, Rc::new(f))))),
+          Rc::new(Nil))
+}
+
+// (cons x rest) without realizing rest
+pub fn lazy_cons(x: MalVal, rest: MalVal) -> MalVal {
+  LazySeq(Rc::new(MalLazy(RefCell::new(LazyState::Cons(x, rest)))),
+          Rc::new(Nil))
+}
+
+// the elements of a seq one at a time, realizing lazy seqs as it goes; it
+// ends after the first error
+pub struct SeqIter(Option<MalVal>);
+
+impl Iterator for SeqIter {
+  type Item = MalRet;
+
+  fn next(&mut self) -> Option<MalRet> {
+    let cur = self.0.take()?;
+    match cur.uncons() {
+      Ok(Some((x, rest))) => {
+        self.0 = Some(rest);
+        Some(Ok(x))
+      },
+      Ok(None) => None,
+      Err(e) => Some(Err(e)),
+    }
+  }
+}
+
 // type utility macros
 
 macro_rules! list {
@@ -48,7 +340,7 @@ macro_rules! list {
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    List(Rc::new(v),Rc::new(Nil))
+    List(Rc::new(::types::MalList::from(v)),Rc::new(Nil))
   }}
 }
 
@@ -58,7 +350,7 @@ macro_rules! vector {
   }};
   [$($args:expr),*] => {{
     let v: Vec<MalVal> = vec![$($args),*];
-    Vector(Rc::new(v),Rc::new(Nil))
+    Vector(Rc::new(::types::MalList::from(v)),Rc::new(Nil))
   }}
 }
 
@@ -72,6 +364,33 @@ pub fn format_error(e: MalErr) -> String {
   match e {
     ErrString(s)  => s.clone(),
     ErrMalVal(mv) => mv.pr_str(true),
+    ErrRead(re)   => re.to_string(),
+  }
+}
+
+impl fmt::Display for Pos {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}:{}", self.line, self.col)
+  }
+}
+
+impl fmt::Display for ReadErr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}: {}", self.pos, self.msg)?;
+    if let Some((ref tok, ref pos)) = self.unclosed {
+      write!(f, " (unclosed '{}' at {})", tok, pos)?;
+    }
+    Ok(())
+  }
+}
+
+impl ReadErr {
+  // the offending input line with a caret under the error position; tabs
+  // before it are kept so the caret lines up however wide they show
+  pub fn caret(&self) -> String {
+    let pad: String = self.src_line.chars().take(self.pos.col - 1)
+      .map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    format!("{}\n{}^", self.src_line, pad)
   }
 }
 
@@ -82,8 +401,8 @@ pub fn atom(mv: &MalVal) -> MalVal {
 impl MalVal {
   pub fn keyword(&self) -> MalRet {
     match self {
-      Str(s) if s.starts_with("\u{29e}") => Ok(Str(s.to_string())),
-      Str(s)                             => Ok(Str(format!("\u{29e}{}", s))),
+      Keyword(_) => Ok(self.clone()),
+      Str(s)     => Ok(Keyword(Name::new(s))),
       _ => error("invalid type for keyword"),
     }
   }
@@ -91,6 +410,8 @@ impl MalVal {
   pub fn empty_q(&self) -> MalRet {
     match self {
       List(l,_) | Vector(l,_) => Ok(Bool(l.len() == 0)),
+      Set(hs,_)               => Ok(Bool(hs.len() == 0)),
+      LazySeq(..)             => Ok(Bool(self.uncons()?.is_none())),
       Nil                     => Ok(Bool(true)),
       _ => error("invalid type for empty?"),
     }
@@ -99,14 +420,115 @@ impl MalVal {
   pub fn count(&self) -> MalRet {
     match self {
       List(l,_) | Vector(l,_) => Ok(Int(l.len() as i64)),
+      Set(hs,_)               => Ok(Int(hs.len() as i64)),
+      LazySeq(..)             => {
+        let mut n = 0;
+        for x in self.seq_iter() { x?; n += 1 }
+        Ok(Int(n))
+      },
       Nil                     => Ok(Int(0)),
       _ => error("invalid type for count"),
     }
   }
 
+  // the elements of a list, vector or set, the entries of a hash-map as
+  // [k v] vectors or the characters of a string; None for anything else
+  pub fn elements(&self) -> Option<MalList> {
+    match self {
+      List(l,_) | Vector(l,_) => Some((**l).clone()),
+      Set(hs,_)  => Some(hs.iter().cloned().collect()),
+      Hash(hm,_) => Some(hm.iter().map(|(k, v)| vector![k.clone(), v.clone()]).collect()),
+      Str(s)     => Some(s.chars().map(|c| Str(c.to_string())).collect()),
+      _ => None,
+    }
+  }
+
+  // the first element of a seq and the seq of the rest, or None when it is
+  // empty. Only a lazy seq's first cell is realized. Strings, sets and
+  // hash-maps are seqs of their elements
+  pub fn uncons(&self) -> Result<Uncons,MalErr> {
+    // the lazy cells being realized, innermost last, each with the step to
+    // take once the seq it waits on is realized. They are kept here rather
+    // than on the stack, so that lazy seqs nested however deep can't
+    // overflow it
+    let mut pending: Vec<(Rc<MalLazy>, Option<StepFn>)> = vec![];
+    let mut cur = self.clone();
+    loop {
+      let res = match cur {
+        Nil => None,
+        List(ref l,_) | Vector(ref l,_) => {
+          l.front().map(|x| (x.clone(), list!(l.skip(1))))
+        },
+        LazySeq(ref ls,_) => {
+          let state = ls.0.borrow().clone();
+          match state {
+            LazyState::Empty => None,
+            LazyState::Cons(x, rest) => Some((x, rest)),
+            LazyState::Thunk(f) => {
+              pending.push((ls.clone(), None));
+              cur = f()?;
+              continue;
+            },
+            LazyState::Step(s, f) => {
+              pending.push((ls.clone(), Some(f)));
+              cur = s;
+              continue;
+            },
+          }
+        },
+        _ => match cur.elements() {
+          Some(l) => {
+            cur = list!(l);
+            continue;
+          },
+          None => return Err(ErrString(format!("not a seq: {}", cur.pr_str(true)))),
+        },
+      };
+      // hand the result back out through the cells waiting on it
+      loop {
+        match pending.pop() {
+          None => return Ok(res),
+          Some((ls, None)) => {
+            *ls.0.borrow_mut() = match res {
+              Some((ref x, ref rest)) => LazyState::Cons(x.clone(), rest.clone()),
+              None                    => LazyState::Empty,
+            };
+          },
+          Some((ls, Some(f))) => {
+            pending.push((ls, None));
+            cur = f(res)?;
+            break;
+          },
+        }
+      }
+    }
+  }
+
+  pub fn seq_iter(&self) -> SeqIter {
+    SeqIter(Some(self.clone()))
+  }
+
+  // realizes every lazy seq in the value, so that printing it or comparing
+  // it can't fail partway; atoms are left alone
+  pub fn realize(&self) -> Result<(),MalErr> {
+    match self {
+      List(l,_) | Vector(l,_) => {
+        for x in l.iter() { x.realize()? }
+      },
+      Hash(hm,_) => {
+        for x in hm.values() { x.realize()? }
+      },
+      LazySeq(..) => {
+        for x in self.seq_iter() { x?.realize()? }
+      },
+      _ => (),
+    }
+    Ok(())
+  }
+
   pub fn apply(&self, args: MalArgs) -> MalRet {
     match *self {
-      Func(f,_) => f(args),
+      Func(f,..) => f(args),
       MalFunc{eval, ref ast, ref env, ref params, ..} => {
         let a = &**ast;
         let p = &**params;
@@ -117,13 +539,34 @@ impl MalVal {
     }
   }
 
-  pub fn keyword_q(&self) -> bool {
+  pub fn number_q(&self) -> bool {
     match self {
-      Str(s) if s.starts_with("\u{29e}") => true,
+      Int(_) | Bignum(_) | Ratio(_) | Float(_) => true,
       _ => false,
     }
   }
 
+  // whether this can be a hash-map key: anything compared by value, so not
+  // atoms, functions or lazy seqs, nor collections holding them
+  pub fn hashable_q(&self) -> bool {
+    self.unhashable().is_none()
+  }
+
+  // what keeps this from being a hash-map key, found in it or anywhere
+  // inside it: "an atom", "a function" or "a lazy seq"
+  pub fn unhashable(&self) -> Option<&'static str> {
+    match self {
+      List(l,_) | Vector(l,_) => l.iter().filter_map(|x| x.unhashable()).next(),
+      Hash(hm,_) => hm.iter()
+        .filter_map(|(k, v)| k.unhashable().or_else(|| v.unhashable()))
+        .next(),
+      Func(..) | MalFunc{..} => Some("a function"),
+      Atom(_) => Some("an atom"),
+      LazySeq(..) => Some("a lazy seq"),
+      _ => None,
+    }
+  }
+
   pub fn deref(&self) -> MalRet {
     match self {
       Atom(a) => Ok(a.borrow().clone()),
@@ -154,10 +597,30 @@ impl MalVal {
     }
   }
 
+  pub fn fn_info(&self) -> Result<&FnInfo,MalErr> {
+    match self {
+      Func(_,info,_) | MalFunc{info, ..} => Ok(info),
+      _ => Err(ErrString("attempt to inspect a non-function".to_string())),
+    }
+  }
+
+  // the function named `name` if it has no name yet, as def! gives it
+  pub fn named(&self, name: &str) -> MalVal {
+    let mut f = self.clone();
+    match f {
+      Func(_,ref mut info,_) | MalFunc{ref mut info, ..} if info.name.is_none() => {
+        Rc::make_mut(info).name = Some(name.to_string());
+      },
+      _ => (),
+    }
+    f
+  }
+
   pub fn get_meta(&self) -> MalRet {
     match self {
-      List(_,meta) | Vector(_,meta) | Hash(_,meta) => Ok((&**meta).clone()),
-      Func(_,meta) => Ok((&**meta).clone()),
+      List(_,meta) | Vector(_,meta) | Hash(_,meta) | Set(_,meta) |
+      LazySeq(_,meta) => Ok((&**meta).clone()),
+      Func(_,_,meta) => Ok((&**meta).clone()),
       MalFunc{meta,..} => Ok((&**meta).clone()),
       _ => error("meta not supported by type"),
     }
@@ -168,7 +631,9 @@ impl MalVal {
       List(_, ref mut meta) |
       Vector(_, ref mut meta) |
       Hash(_, ref mut meta) |
-      Func(_,ref mut meta) |
+      Set(_, ref mut meta) |
+      LazySeq(_, ref mut meta) |
+      Func(_,_,ref mut meta) |
       MalFunc{ref mut meta, ..} => {
         *meta = Rc::new((&*new_meta).clone());
       },
@@ -180,53 +645,180 @@ impl MalVal {
 
 impl PartialEq for MalVal {
   fn eq(&self, other: &MalVal) -> bool {
-    match (self, other) {
-      (Nil,Nil) => true,
-      (Bool(ref a),Bool(ref b)) => a == b,
-      (Int(ref a),Int(ref b)) => a == b,
-      (Str(ref a),Str(ref b)) => a == b,
-      (Sym(ref a),Sym(ref b)) => a == b,
-      (List(ref a,_),List(ref b,_)) |
-      (Vector(ref a,_),Vector(ref b,_)) |
-      (List(ref a,_),Vector(ref b,_)) |
-      (Vector(ref a,_),List(ref b,_)) => a == b,
-      (Hash(ref a,_),Hash(ref b,_)) => a == b,
-      (MalFunc{..}, MalFunc{..}) => false,
-      _ => false,
+    equal(self, other, &mut vec![])
+  }
+}
+
+// only a NaN is unequal to itself, and as a key it is never found again
+impl Eq for MalVal {}
+
+// consistent with equal: a number hashes by its exact value, lists and
+// vectors hash alike, and maps and sets hash their entries in any order
+impl StdHash for MalVal {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    match self {
+      Nil => 0.hash(state),
+      Bool(b) => { 1.hash(state); b.hash(state) },
+      Int(_) | Bignum(_) | Ratio(_) | Float(_) => {
+        2.hash(state);
+        numeric::hash(self, state);
+      },
+      Str(s) => { 3.hash(state); s.hash(state) },
+      Sym(s) => { 4.hash(state); s.hash(state) },
+      Keyword(k) => { 8.hash(state); k.hash(state) },
+      Regex(re) => { 10.hash(state); re.as_str().hash(state) },
+      List(l,_) | Vector(l,_) => {
+        5.hash(state);
+        l.len().hash(state);
+        for x in l.iter() { x.hash(state) }
+      },
+      Hash(hm,_) => {
+        6.hash(state);
+        let mut sum: u64 = 0;
+        for (k, v) in hm.iter() {
+          let mut h = FnvHasher::default();
+          k.hash(&mut h);
+          v.hash(&mut h);
+          sum = sum.wrapping_add(h.finish());
+        }
+        sum.hash(state);
+      },
+      Set(hs,_) => {
+        9.hash(state);
+        let mut sum: u64 = 0;
+        for x in hs.iter() {
+          let mut h = FnvHasher::default();
+          x.hash(&mut h);
+          sum = sum.wrapping_add(h.finish());
+        }
+        sum.hash(state);
+      },
+      // never keys, see hashable_q
+      Func(..) | MalFunc{..} | Atom(_) | LazySeq(..) => 7.hash(state),
     }
   }
 }
 
+// atoms are equal when they hold equal values; a pair of atoms already
+// being compared further out is taken to be equal, so comparing cyclic
+// values ends
+fn equal(a: &MalVal, b: &MalVal,
+         comparing: &mut Vec<(*const RefCell<MalVal>, *const RefCell<MalVal>)>) -> bool {
+  match (a, b) {
+    (Nil,Nil) => true,
+    (Bool(ref a),Bool(ref b)) => a == b,
+    (a, b) if a.number_q() && b.number_q() => {
+      match numeric::compare("=", a, b) {
+        Ok(Some(Ordering::Equal)) => true,
+        _ => false,
+      }
+    },
+    (Str(ref a),Str(ref b)) => a == b,
+    (Keyword(ref a),Keyword(ref b)) => a == b,
+    (Sym(ref a),Sym(ref b)) => a == b,
+    (Regex(ref a),Regex(ref b)) => a.as_str() == b.as_str(),
+    (List(ref a,_),List(ref b,_)) |
+    (Vector(ref a,_),Vector(ref b,_)) |
+    (List(ref a,_),Vector(ref b,_)) |
+    (Vector(ref a,_),List(ref b,_)) => {
+      a.len() == b.len() &&
+        a.iter().zip(b.iter()).all(|(a, b)| equal(a, b, comparing))
+    },
+    (Hash(ref a,_),Hash(ref b,_)) => {
+      a.len() == b.len() && a.iter().all(|(k, v)| {
+        match b.get(k) {
+          Some(bv) => equal(v, bv, comparing),
+          None     => false,
+        }
+      })
+    },
+    // element by element, as far as both go; a seq that fails to realize
+    // is equal to nothing
+    (LazySeq(..),LazySeq(..)) |
+    (LazySeq(..),List(..)) | (LazySeq(..),Vector(..)) |
+    (List(..),LazySeq(..)) | (Vector(..),LazySeq(..)) => {
+      let (mut ai, mut bi) = (a.seq_iter(), b.seq_iter());
+      loop {
+        match (ai.next(), bi.next()) {
+          (None, None) => return true,
+          (Some(Ok(ref x)), Some(Ok(ref y))) if equal(x, y, comparing) => (),
+          _ => return false,
+        }
+      }
+    },
+    (Set(ref a,_),Set(ref b,_)) => {
+      a.len() == b.len() && a.iter().all(|x| b.contains(x))
+    },
+    (Atom(ref a),Atom(ref b)) => {
+      let pair = (&**a as *const RefCell<MalVal>, &**b as *const RefCell<MalVal>);
+      if Rc::ptr_eq(a, b) || comparing.contains(&pair) {
+        return true;
+      }
+      comparing.push(pair);
+      let res = equal(&a.borrow(), &b.borrow(), comparing);
+      comparing.pop();
+      res
+    },
+    (MalFunc{..}, MalFunc{..}) => false,
+    _ => false,
+  }
+}
+
 pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
-  Func(f, Rc::new(Nil))
+  Func(f, Rc::new(FnInfo::default()), Rc::new(Nil))
+}
+
+// the value itself is left out, as printing a lazy seq may never end
+fn unhashable_msg(what: &str, x: &MalVal, kind: &str) -> String {
+  match x {
+    List(..) | Vector(..) | Hash(..) => format!("{} cannot contain {}", what, kind),
+    _ => format!("{} cannot be {}", what, kind),
+  }
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String,MalVal>, kvs: MalArgs) -> MalRet {
+pub fn _assoc(mut hm: MalMap, kvs: MalArgs) -> MalRet {
   if kvs.len() % 2 != 0 {
     return error("odd number of elements")
   }
   for (k, v) in kvs.iter().tuples() {
-    match k {
-      Str(s) => { hm.insert(s.to_string(), v.clone()); },
-      _      => { return error("key is not string") },
+    if let Some(kind) = k.unhashable() {
+      return error(&unhashable_msg("key", k, kind));
     }
+    hm.insert(k.clone(), v.clone());
   }
   Ok(Hash(Rc::new(hm),Rc::new(Nil)))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String,MalVal>, ks: MalArgs) -> MalRet {
+pub fn _dissoc(mut hm: MalMap, ks: MalArgs) -> MalRet {
   for k in ks.iter() {
-    match k {
-      Str(ref s) => { hm.remove(s); },
-      _      => { return error("key is not string") },
-    }
+    hm.remove(k);
   }
   Ok(Hash(Rc::new(hm),Rc::new(Nil)))
 }
 
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-  let hm: FnvHashMap<String,MalVal> = FnvHashMap::default();
-  _assoc(hm, kvs)
+  _assoc(MalMap::default(), kvs)
+}
+
+pub fn _conj_set(mut hs: MalSet, xs: MalArgs) -> MalRet {
+  for x in xs {
+    if let Some(kind) = x.unhashable() {
+      return error(&unhashable_msg("set element", &x, kind));
+    }
+    hs.insert(x);
+  }
+  Ok(Set(Rc::new(hs),Rc::new(Nil)))
+}
+
+pub fn _disj(mut hs: MalSet, xs: MalArgs) -> MalRet {
+  for x in xs.iter() {
+    hs.remove(x);
+  }
+  Ok(Set(Rc::new(hs),Rc::new(Nil)))
+}
+
+pub fn hash_set(xs: MalArgs) -> MalRet {
+  _conj_set(MalSet::default(), xs)
 }
 
 // vim: ts=2:sw=2:expandtab
@@ -1,22 +1,31 @@
+use std::cmp::Ordering;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::fs;
-use std::collections::HashMap;
 
-use crate::types::{MalForm,MalError,MalKey,MalNativeFn,MalResult,ToMalForm,Env};
-use crate::printer::pr_seq;
-use crate::reader::read_str;
+use crate::types::{MalForm,MalError,MalKey,MalMap,MalSet,MalNativeFn,MalResult,ToMalForm,Env};
+use crate::printer::{pr_seq, pprint as pprint_form};
+use crate::reader::{read_str, cst_str as cst_text, set_tag_reader, set_reader_macro, ReadHandler};
+use crate::numeric;
+use crate::json;
+use crate::serialize;
+
+use regex::{Captures, Regex};
 
 pub fn get_namespace() -> Vec<(&'static str, MalForm)> {
     vec![
-        ("+", binary_fn("+", |a,b| a + b)),
-        ("-", binary_fn("-", |a,b| a - b)),
-        ("*", binary_fn("*", |a,b| a * b)),
-        ("/", binary_fn("/", |a,b| a / b)),
-        ("<", binary_fn("<", |a,b| a < b)),
-        ("<=", binary_fn("<=", |a,b| a <= b)),
-        (">", binary_fn(">", |a,b| a > b)),
-        (">=", binary_fn(">=", |a,b| a >= b)),
+        ("+", arith_fn("+", numeric::add, 0, true)),
+        ("-", arith_fn("-", numeric::sub, 0, false)),
+        ("*", arith_fn("*", numeric::mul, 1, true)),
+        ("/", arith_fn("/", numeric::div, 1, false)),
+        ("<", compare_fn("<", |o| o == Ordering::Less)),
+        ("<=", compare_fn("<=", |o| o != Ordering::Greater)),
+        (">", compare_fn(">", |o| o == Ordering::Greater)),
+        (">=", compare_fn(">=", |o| o != Ordering::Less)),
+        ("number?", native_fn("number?", number_q)),
+        ("int?", native_fn("int?", int_q)),
+        ("ratio?", native_fn("ratio?", ratio_q)),
+        ("float?", native_fn("float?", float_q)),
         ("prn", native_fn("prn", prn)),
         ("list", native_fn("list", list)),
         ("list?", native_fn("list?", list_q)),
@@ -26,8 +35,28 @@ pub fn get_namespace() -> Vec<(&'static str, MalForm)> {
         ("pr-str", native_fn("pr-str", pr_str)),
         ("str", native_fn("str", str)),
         ("println", native_fn("println", println)),
+        ("pprint", native_fn("pprint", pprint)),
+        ("pprint-str", native_fn("pprint-str", pprint_str)),
+        ("json-encode", native_fn("json-encode", json_encode)),
+        ("json-decode", native_fn("json-decode", json_decode)),
+        ("serialize", native_fn("serialize", serialize_to)),
+        ("deserialize", native_fn("deserialize", deserialize_from)),
         ("read-string", native_fn("read-string", read_string)),
+        ("read-all", native_fn("read-all", read_all)),
+        ("read-cst", native_fn("read-cst", read_cst)),
+        ("cst-str", native_fn("cst-str", cst_str)),
+        ("set-tag-reader!", native_fn("set-tag-reader!", set_tag_reader_)),
+        ("set-reader-macro!", native_fn("set-reader-macro!", set_reader_macro_)),
         ("slurp", native_fn("slurp", slurp)),
+        ("re-pattern", native_fn("re-pattern", re_pattern)),
+        ("re-find", native_fn("re-find", re_find)),
+        ("re-matches", native_fn("re-matches", re_matches)),
+        ("fn-name", native_fn("fn-name", fn_name)),
+        ("arglists", native_fn("arglists", arglists)),
+        ("doc", native_fn("doc", doc)),
+        ("source", native_fn("source", source)),
+        ("meta", native_fn("meta", meta)),
+        ("with-meta", native_fn("with-meta", with_meta)),
         ("atom", native_fn("atom", atom)),
         ("atom?", native_fn("atom?", atom_q)),
         ("deref", native_fn("deref", deref)),
@@ -58,33 +87,101 @@ pub fn get_namespace() -> Vec<(&'static str, MalForm)> {
         ("contains?", native_fn("contains?", contains_q)),
         ("keys", native_fn("keys", keys)),
         ("vals", native_fn("vals", vals)),
+        ("hash-set", native_fn("hash-set", hash_set)),
+        ("set", native_fn("set", set)),
+        ("set?", native_fn("set?", set_q)),
+        ("disj", native_fn("disj", disj)),
+        ("conj", native_fn("conj", conj)),
+        ("seq", native_fn("seq", seq)),
+        ("union", native_fn("union", union)),
+        ("intersection", native_fn("intersection", intersection)),
+        ("difference", native_fn("difference", difference)),
     ]
 }
 
 pub fn native_fn<F: 'static>(name: &'static str, f: F) -> MalForm
     where F: Fn(Vec<MalForm>, &Rc<RefCell<Env>>) -> MalResult<MalForm>
 {
-    MalForm::NativeFn(name.to_string(), MalNativeFn(Rc::new(f)))
+    MalForm::NativeFn(name.to_string(), MalNativeFn(Rc::new(f)), None)
 }
 
-fn binary_fn<T>(name: &'static str, f: fn(f64, f64) -> T) -> MalForm
-    where T: ToMalForm + 'static
-{
+/// `(op a b c)` is `(op (op a b) c)` and `(op x)` is `(op identity x)`, so
+/// `(- x)` negates `x`. With no arguments, `nullary` ops give `identity`.
+fn arith_fn(name: &'static str, f: fn(&MalForm, &MalForm) -> MalResult<MalForm>, identity: i64, nullary: bool) -> MalForm {
+    native_fn(name, move |vec: Vec<MalForm>, _| {
+        match vec.as_slice() {
+            [] if nullary => Ok(MalForm::Int(identity, None)),
+            [] => Err(MalError::EvalError(format!("'{}': at least one argument required", name))),
+            [x] => f(&MalForm::Int(identity, None), x),
+            [x, rest @ ..] => rest.iter().try_fold(x.clone(), |acc, y| f(&acc, y)),
+        }
+    })
+}
+
+/// True when each argument compares to the next in a way `f` accepts, as in
+/// `(< a b c)`; never for NaN. Every pair is compared, so an argument that
+/// isn't a number is an error even once the answer is known.
+fn compare_fn(name: &'static str, f: fn(Ordering) -> bool) -> MalForm {
     native_fn(name, move |vec: Vec<MalForm>, _| {
         match vec.as_slice() {
-            [MalForm::Number(ref a), MalForm::Number(ref b)] => Ok(f(*a, *b).to_mal_form()),
-            _ => Err(MalError::EvalError(format!("'{}': wrong arguments", name))),
+            [] => Err(MalError::EvalError(format!("'{}': at least one argument required", name))),
+            [x] => numeric::compare(name, x, x).map(|_| true.to_mal_form()),
+            _ => {
+                let mut res = true;
+                for w in vec.windows(2) {
+                    if !numeric::compare(name, &w[0], &w[1])?.is_some_and(f) {
+                        res = false;
+                    }
+                }
+                Ok(res.to_mal_form())
+            }
         }
     })
 }
 
+fn number_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let is_number = match args.get(0) {
+        Some(MalForm::Int(_, _)) | Some(MalForm::Bignum(_, _)) | Some(MalForm::Ratio(_, _)) | Some(MalForm::Number(_, _)) => true,
+        _ => false,
+    };
+
+    Ok(is_number.to_mal_form())
+}
+
+fn int_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let is_int = match args.get(0) {
+        Some(MalForm::Int(_, _)) | Some(MalForm::Bignum(_, _)) => true,
+        _ => false,
+    };
+
+    Ok(is_int.to_mal_form())
+}
+
+fn ratio_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let is_ratio = match args.get(0) {
+        Some(MalForm::Ratio(_, _)) => true,
+        _ => false,
+    };
+
+    Ok(is_ratio.to_mal_form())
+}
+
+fn float_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let is_float = match args.get(0) {
+        Some(MalForm::Number(_, _)) => true,
+        _ => false,
+    };
+
+    Ok(is_float.to_mal_form())
+}
+
 fn list(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
-    Ok(MalForm::List(args))
+    Ok(MalForm::List(args, None, None))
 }
 
 fn list_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let is_list = match args.get(0) {
-        Some(MalForm::List(_)) => true,
+        Some(MalForm::List(_, _, _)) => true,
         _ => false,
     };
 
@@ -93,10 +190,11 @@ fn list_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
 fn empty_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let vec = match args.get(0) {
-        Some(MalForm::List(v)) => v,
-        Some(MalForm::Vector(v)) => v,
-        Some(x) => return Err(MalError::EvalError(format!("'empty?' expects a list or a vector, {} was given", x))),
-        None => return Err(MalError::EvalError(format!("'empty?' expects a list or a vector, nothing was given"))),
+        Some(MalForm::List(v, _, _)) => v,
+        Some(MalForm::Vector(v, _, _)) => v,
+        Some(MalForm::Set(s, _, _)) => return Ok(s.is_empty().to_mal_form()),
+        Some(x) => return Err(MalError::EvalError(format!("'empty?' expects a list, a vector or a set, {} was given", x))),
+        None => return Err(MalError::EvalError(format!("'empty?' expects a list, a vector or a set, nothing was given"))),
     };
 
     Ok(vec.is_empty().to_mal_form())
@@ -104,21 +202,23 @@ fn empty_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
 fn count(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let vec = match args.get(0) {
-        Some(MalForm::List(v)) => v,
-        Some(MalForm::Vector(v)) => v,
-        Some(MalForm::Nil) => return Ok(0.0.to_mal_form()),
-        Some(x) => return Err(MalError::EvalError(format!("'count' expects a list or a vector, {} was given", x))),
-        None => return Err(MalError::EvalError(format!("'count' expects a list or a vector, nothing was given"))),
+        Some(MalForm::List(v, _, _)) => v,
+        Some(MalForm::Vector(v, _, _)) => v,
+        Some(MalForm::Set(s, _, _)) => return Ok(MalForm::Int(s.len() as i64, None)),
+        Some(MalForm::Nil(_)) => return Ok(MalForm::Int(0, None)),
+        Some(x) => return Err(MalError::EvalError(format!("'count' expects a list, a vector or a set, {} was given", x))),
+        None => return Err(MalError::EvalError(format!("'count' expects a list, a vector or a set, nothing was given"))),
     };
 
-    Ok(MalForm::Number(vec.len() as f64))
+    Ok(MalForm::Int(vec.len() as i64, None))
 }
 
+/// `(= a b c)` when all the arguments are equal.
 fn eq(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
-    match args.as_slice() {
-        [a, b] => Ok((a == b).to_mal_form()),
-        _ => Err(MalError::EvalError(format!("'=' expects two arguments"))),
+    if args.is_empty() {
+        return Err(MalError::EvalError(format!("'=' expects at least one argument")));
     }
+    Ok(args.windows(2).all(|w| w[0] == w[1]).to_mal_form())
 }
 
 fn prn(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
@@ -141,17 +241,205 @@ fn str(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(pr_seq(&args, "", false).to_mal_form())
 }
 
+fn pprint_args(name: &str, args: &[MalForm]) -> MalResult<String> {
+    match args {
+        [x] => Ok(pprint_form(x, 80)),
+        [x, MalForm::Int(w, _)] if *w >= 1 => Ok(pprint_form(x, *w as usize)),
+        [_, w] => Err(MalError::EvalError(format!("'{}': width must be a positive integer, {} was given", name, w))),
+        _ => Err(MalError::EvalError(format!("'{}': expected a value and an optional width", name))),
+    }
+}
+
+fn pprint(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    println!("{}", pprint_args("pprint", &args)?);
+    Ok(().to_mal_form())
+}
+
+fn pprint_str(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    Ok(pprint_args("pprint-str", &args)?.to_mal_form())
+}
+
+fn json_encode(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match args.get(0) {
+        Some(x) => Ok(json::encode(x)?.to_mal_form()),
+        _ => Err(MalError::EvalError(format!("'json-encode': argument required"))),
+    }
+}
+
+/// `(json-decode s)` or `(json-decode s {:keywordize true})`.
+fn json_decode(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let keywordize = match args.get(1) {
+        None | Some(MalForm::Nil(_)) => false,
+        Some(MalForm::HashMap(opts, _, _)) => match opts.get(&MalForm::Key(MalKey::Keyword("keywordize".to_string()), None)) {
+            None | Some(MalForm::Nil(_)) | Some(MalForm::Bool(false, _)) => false,
+            _ => true,
+        },
+        Some(x) => return Err(MalError::EvalError(format!("'json-decode': options must be a hash-map, {} was given", x))),
+    };
+    match args.get(0) {
+        Some(MalForm::Key(MalKey::String(s), _)) => json::decode(s, keywordize),
+        Some(x) => Err(MalError::EvalError(format!("'json-decode': argument must be a string, {} was given", x))),
+        _ => Err(MalError::EvalError(format!("'json-decode': argument required"))),
+    }
+}
+
+/// `(serialize x path)` writes `x` to a file, to be read back with
+/// `(deserialize path)`.
+fn serialize_to(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match (args.get(0), args.get(1)) {
+        (Some(x), Some(MalForm::Key(MalKey::String(path), _))) => {
+            let data = serialize::encode(x)?;
+            match fs::write(path, data) {
+                Ok(_) => Ok(MalForm::Nil(None)),
+                Err(e) => Err(MalError::EvalError(format!("'serialize': {}: {}", path, e))),
+            }
+        }
+        (Some(_), Some(x)) => Err(MalError::EvalError(format!("'serialize': path must be a string, {} was given", x))),
+        _ => Err(MalError::EvalError(format!("'serialize': value and path required"))),
+    }
+}
+
+fn deserialize_from(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match args.get(0) {
+        Some(MalForm::Key(MalKey::String(path), _)) => match fs::read(path) {
+            Ok(data) => serialize::decode(&data),
+            Err(e) => Err(MalError::EvalError(format!("'deserialize': {}: {}", path, e))),
+        },
+        Some(x) => Err(MalError::EvalError(format!("'deserialize': path must be a string, {} was given", x))),
+        _ => Err(MalError::EvalError(format!("'deserialize': argument required"))),
+    }
+}
+
 fn read_string(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     match args.get(0) {
-        Some(MalForm::Key(MalKey::String(ref s))) => read_str(s),
+        Some(MalForm::Key(MalKey::String(ref s), _)) => read_str(s),
         Some(x) => Err(MalError::EvalError(format!("'read-string': argument must be a string, {} was given", x))),
         _ => Err(MalError::EvalError(format!("'read-string': argument required"))),
     }
 }
 
+fn read_all(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match args.get(0) {
+        Some(MalForm::Key(MalKey::String(ref s), _)) => {
+            let forms = crate::reader::read_all(s, None)?;
+            Ok(MalForm::List(forms.into_iter().map(|(form, _)| form).collect(), None, None))
+        },
+        Some(x) => Err(MalError::EvalError(format!("'read-all': argument must be a string, {} was given", x))),
+        _ => Err(MalError::EvalError(format!("'read-all': argument required"))),
+    }
+}
+
+fn read_cst(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match args.get(0) {
+        Some(MalForm::Key(MalKey::String(ref s), _)) => {
+            let cst = crate::reader::read_cst(s, None)?;
+            Ok(MalForm::Vector(cst.iter().map(|node| node.to_mal()).collect(), None, None))
+        },
+        Some(x) => Err(MalError::EvalError(format!("'read-cst': argument must be a string, {} was given", x))),
+        _ => Err(MalError::EvalError(format!("'read-cst': argument required"))),
+    }
+}
+
+fn cst_str(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match args.get(0) {
+        Some(node) => Ok(MalForm::Key(MalKey::String(cst_text(node)?), None)),
+        _ => Err(MalError::EvalError(format!("'cst-str': argument required"))),
+    }
+}
+
+fn set_tag_reader_(args: Vec<MalForm>, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let tag = match args.get(0) {
+        Some(MalForm::Symbol(s, _)) | Some(MalForm::Key(MalKey::String(s), _)) => s.clone(),
+        _ => return Err(MalError::EvalError(format!("'set-tag-reader!': first argument must be a symbol or a string"))),
+    };
+    let f = match args.get(1) {
+        Some(MalForm::MalFn(f)) => f.fn_.clone(),
+        Some(MalForm::NativeFn(_, f, _)) => f.clone(),
+        _ => return Err(MalError::EvalError(format!("'set-tag-reader!': second argument must be a function"))),
+    };
+
+    let env = env.clone();
+    set_tag_reader(&tag, Rc::new(move |form| f.0(vec![form], &env)));
+    Ok(MalForm::Nil(None))
+}
+
+fn set_reader_macro_(args: Vec<MalForm>, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let c = match args.get(0) {
+        Some(MalForm::Key(MalKey::String(s), _)) => s.clone(),
+        _ => return Err(MalError::EvalError(format!("'set-reader-macro!': first argument must be a string"))),
+    };
+    let f = match args.get(1) {
+        Some(MalForm::MalFn(f)) => Some(f.fn_.clone()),
+        Some(MalForm::NativeFn(_, f, _)) => Some(f.clone()),
+        Some(MalForm::Nil(_)) => None,
+        _ => return Err(MalError::EvalError(format!("'set-reader-macro!': second argument must be a function or nil"))),
+    };
+
+    let env = env.clone();
+    let handler = f.map(|f| Rc::new(move |form| f.0(vec![form], &env)) as ReadHandler);
+    set_reader_macro(&c, handler)
+        .map_err(|msg| MalError::EvalError(format!("'set-reader-macro!': {}", msg)))?;
+    Ok(MalForm::Nil(None))
+}
+
+fn regex(name: &str, pattern: &str) -> MalResult<Regex> {
+    Regex::new(pattern).map_err(|err| MalError::EvalError(format!("'{}': invalid regex: {}", name, err)))
+}
+
+/// The whole match, or a vector of it and every group when there are groups.
+fn re_groups(caps: Captures) -> MalForm {
+    let to_form = |m: Option<regex::Match>| m.map(|m| m.as_str().to_mal_form()).unwrap_or(MalForm::Nil(None));
+    if caps.len() == 1 {
+        to_form(caps.get(0))
+    } else {
+        MalForm::Vector(caps.iter().map(to_form).collect(), None, None)
+    }
+}
+
+fn re_pattern(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match args.as_slice() {
+        [MalForm::Key(MalKey::String(s), _)] => Ok(MalForm::Regex(Rc::new(regex("re-pattern", s)?), None)),
+        [re@MalForm::Regex(..)] => Ok(re.clone()),
+        _ => Err(MalError::EvalError("'re-pattern': expects a string".to_string())),
+    }
+}
+
+/// The pattern of a regex or string argument, anchored at both ends for
+/// `re-matches`.
+fn re_arg(name: &str, pattern: &MalForm, anchored: bool) -> MalResult<Rc<Regex>> {
+    let pattern = match pattern {
+        MalForm::Regex(re, _) if !anchored => return Ok(re.clone()),
+        MalForm::Regex(re, _) => re.as_str(),
+        MalForm::Key(MalKey::String(p), _) => p,
+        _ => return Err(MalError::EvalError(format!("'{}': expects a regex and a string", name))),
+    };
+    let re = if anchored { regex(name, &format!("^(?:{})$", pattern))? } else { regex(name, pattern)? };
+    Ok(Rc::new(re))
+}
+
+fn re_find(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match args.as_slice() {
+        [p, MalForm::Key(MalKey::String(s), _)] => {
+            let re = re_arg("re-find", p, false)?;
+            Ok(re.captures(s).map(re_groups).unwrap_or(MalForm::Nil(None)))
+        },
+        _ => Err(MalError::EvalError("'re-find': expects a regex and a string".to_string())),
+    }
+}
+
+fn re_matches(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match args.as_slice() {
+        [p, MalForm::Key(MalKey::String(s), _)] => {
+            let re = re_arg("re-matches", p, true)?;
+            Ok(re.captures(s).map(re_groups).unwrap_or(MalForm::Nil(None)))
+        },
+        _ => Err(MalError::EvalError("'re-matches': expects a regex and a string".to_string())),
+    }
+}
+
 fn slurp(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     match args.get(0) {
-        Some(MalForm::Key(MalKey::String(ref s))) => {
+        Some(MalForm::Key(MalKey::String(ref s), _)) => {
             let contents = fs::read_to_string(s);
             Ok(contents.map(|x| x.to_mal_form()).unwrap_or(().to_mal_form()))
         },
@@ -168,6 +456,61 @@ fn atom(mut args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(MalForm::Atom(Rc::new(RefCell::new(args.remove(0)))))
 }
 
+/// The function that `fn-name`, `arglists`, `doc` and `source` describe.
+fn fn_arg<'a>(name: &str, args: &'a [MalForm]) -> MalResult<&'a MalForm> {
+    match args.get(0) {
+        Some(f @ MalForm::NativeFn(..)) | Some(f @ MalForm::MalFn(_)) => Ok(f),
+        Some(x) => Err(MalError::EvalError(format!("'{}': argument must be a function, {} was given", name, x))),
+        _ => Err(MalError::EvalError(format!("'{}': argument required", name))),
+    }
+}
+
+fn fn_name(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    Ok(match fn_arg("fn-name", &args)? {
+        MalForm::MalFn(f) => f.name.to_mal_form(),
+        MalForm::NativeFn(name, _, _) => name.to_mal_form(),
+        _ => MalForm::Nil(None),
+    })
+}
+
+fn arglists(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    Ok(match fn_arg("arglists", &args)? {
+        MalForm::MalFn(f) => {
+            let params = f.params.iter().map(|p| MalForm::Symbol(p.clone(), None)).collect();
+            MalForm::List(vec![MalForm::Vector(params, None, None)], None, None)
+        }
+        _ => MalForm::Nil(None),
+    })
+}
+
+fn doc(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    Ok(match fn_arg("doc", &args)? {
+        MalForm::MalFn(f) => f.doc.to_mal_form(),
+        _ => MalForm::Nil(None),
+    })
+}
+
+fn source(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    Ok(match fn_arg("source", &args)? {
+        MalForm::MalFn(f) => f.span.as_ref().map(|span| span.to_string()).to_mal_form(),
+        _ => MalForm::Nil(None),
+    })
+}
+
+fn meta(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match args.get(0) {
+        Some(x) => x.meta(),
+        None => Err(MalError::EvalError(format!("'meta': argument required"))),
+    }
+}
+
+fn with_meta(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    match (args.get(0), args.get(1)) {
+        (Some(x), Some(meta)) => x.with_meta(meta.clone()),
+        _ => Err(MalError::EvalError(format!("'with-meta': expects a value and its metadata"))),
+    }
+}
+
 fn atom_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(if let Some(MalForm::Atom(_)) = args.get(0) {
         true
@@ -201,7 +544,7 @@ fn cons(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
         (Some(x), Some(xs)) => {
             let mut res = xs.clone();
             res.insert(0, x.clone());
-            Ok(MalForm::List(res))
+            Ok(MalForm::List(res, None, None))
         },
         _ => Err(MalError::EvalError(format!("'cons': wrong arguments")))
     }
@@ -213,22 +556,22 @@ fn concat(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let mut it = args.into_iter();
     while let Some(ref mut x) = it.next() {
         match x {
-            MalForm::List(ref mut xs) => {
+            MalForm::List(ref mut xs, _, _) => {
                 result.append(xs);
             },
-            MalForm::Vector(ref mut xs) => {
+            MalForm::Vector(ref mut xs, _, _) => {
                 result.append(xs);
             },
             _ => return Err(MalError::EvalError(format!("'concat': arguments must be lists, {} given", x))),
         }
     }
 
-    Ok(MalForm::List(result))
+    Ok(MalForm::List(result, None, None))
 }
 
 fn nth(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     match (args.get(0), args.get(1)) {
-        (Some(xs_list), Some(MalForm::Number(i))) => {
+        (Some(xs_list), Some(MalForm::Int(i, _))) => {
             let xs = xs_list.coerce_list().ok_or(MalError::EvalError(format!("'nth': first argument is neither a list nor a vector")))?;
             Ok(xs.get(*i as usize).ok_or(MalError::EvalError(format!("'nth': index out of bounds")))?.clone())
         },
@@ -237,26 +580,26 @@ fn nth(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 }
 
 fn first(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
-    if let Some(MalForm::Nil) = args.get(0) {
-        return Ok(MalForm::Nil);
+    if let Some(MalForm::Nil(_)) = args.get(0) {
+        return Ok(MalForm::Nil(None));
     }
 
     match args.get(0).and_then(|x| x.coerce_list()) {
         Some(xs) => {
-            Ok(xs.get(0).unwrap_or(&MalForm::Nil).clone())
+            Ok(xs.get(0).unwrap_or(&MalForm::Nil(None)).clone())
         },
         _ => Err(MalError::EvalError(format!("'first': wrong arguments")))
     }
 }
 
 fn rest(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
-    if let Some(MalForm::Nil) = args.get(0) {
-        return Ok(MalForm::List(vec![]));
+    if let Some(MalForm::Nil(_)) = args.get(0) {
+        return Ok(MalForm::List(vec![], None, None));
     }
 
     match args.get(0).and_then(|x| x.coerce_list()) {
         Some(xs) => {
-            Ok(MalForm::List(xs.get(1 ..).unwrap_or(&[]).to_vec().clone()))
+            Ok(MalForm::List(xs.get(1 ..).unwrap_or(&[]).to_vec().clone(), None, None))
         },
         _ => Err(MalError::EvalError(format!("'rest': wrong arguments")))
     }
@@ -276,7 +619,7 @@ fn apply(mut args: Vec<MalForm>, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
     let f = match args.remove(0) {
         MalForm::MalFn(f) => f.fn_.clone(),
-        MalForm::NativeFn(_, f) => f,
+        MalForm::NativeFn(_, f, _) => f,
         _ => return Err(MalError::EvalError(format!("'apply': first argument must be a function"))),
     };
 
@@ -295,7 +638,7 @@ fn map(mut args: Vec<MalForm>, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
     let f = match args.remove(0) {
         MalForm::MalFn(f) => f.fn_.clone(),
-        MalForm::NativeFn(_, f) => f,
+        MalForm::NativeFn(_, f, _) => f,
         _ => return Err(MalError::EvalError(format!("'apply': first argument must be a function"))),
     };
 
@@ -304,196 +647,296 @@ fn map(mut args: Vec<MalForm>, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
         .ok_or(MalError::EvalError(format!("'apply': last argument must be a list or a vector")))?;
 
     let res = rest.iter().map(|x| f.0(vec![x.clone()], env)).collect::<MalResult<Vec<MalForm>>>()?;
-    Ok(MalForm::List(res))
+    Ok(MalForm::List(res, None, None))
 }
 
 fn nil_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match args.get(0) {
-        Some(MalForm::Nil) => true,
+        Some(MalForm::Nil(_)) => true,
         _ => false,
     }.to_mal_form())
 }
 
 fn true_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match args.get(0) {
-        Some(MalForm::Bool(true)) => true,
+        Some(MalForm::Bool(true, _)) => true,
         _ => false,
     }.to_mal_form())
 }
 
 fn false_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match args.get(0) {
-        Some(MalForm::Bool(false)) => true,
+        Some(MalForm::Bool(false, _)) => true,
         _ => false,
     }.to_mal_form())
 }
 
 fn symbol_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match args.get(0) {
-        Some(MalForm::Symbol(_)) => true,
+        Some(MalForm::Symbol(_, _)) => true,
         _ => false,
     }.to_mal_form())
 }
 
 fn keyword_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match args.get(0) {
-        Some(MalForm::Key(MalKey::Keyword(_))) => true,
+        Some(MalForm::Key(MalKey::Keyword(_), _)) => true,
         _ => false,
     }.to_mal_form())
 }
 
 fn vector_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match args.get(0) {
-        Some(MalForm::Vector(_)) => true,
+        Some(MalForm::Vector(_, _, _)) => true,
         _ => false,
     }.to_mal_form())
 }
 
 fn map_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match args.get(0) {
-        Some(MalForm::HashMap(_)) => true,
+        Some(MalForm::HashMap(_, _, _)) => true,
         _ => false,
     }.to_mal_form())
 }
 
 fn sequential_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match args.get(0) {
-        Some(MalForm::List(_)) => true,
-        Some(MalForm::Vector(_)) => true,
+        Some(MalForm::List(_, _, _)) => true,
+        Some(MalForm::Vector(_, _, _)) => true,
         _ => false,
     }.to_mal_form())
 }
 
 fn symbol(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     match args.get(0) {
-        Some(MalForm::Key(MalKey::String(s))) => Ok(MalForm::Symbol(s.clone())),
+        Some(MalForm::Key(MalKey::String(s), _)) => Ok(MalForm::Symbol(s.clone(), None)),
         _ => Err(MalError::EvalError(format!("'symbol': argument must be string"))),
     }
 }
 
 fn keyword(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     match args.get(0) {
-        Some(MalForm::Key(MalKey::String(s))) => Ok(MalForm::Key(MalKey::Keyword(s.clone()))),
+        Some(MalForm::Key(MalKey::String(s), _)) => Ok(MalForm::Key(MalKey::Keyword(s.clone()), None)),
         _ => Err(MalError::EvalError(format!("'keyword': argument must be string"))),
     }
 }
 
 fn vector(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
-    Ok(MalForm::Vector(args))
+    Ok(MalForm::Vector(args, None, None))
 }
 
 fn hash_map(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
-    let mut res = HashMap::new();
+    let mut res = MalMap::new();
 
     let mut it = args.iter();
 
-    while let Some(mkey) = it.next() {
-        match mkey {
-            MalForm::Key(key) => {
-                let value = it.next().ok_or(MalError::EvalError(format!("'hash-map': missing value for {}", key)))?;
-                res.insert(key.clone(), value.clone());
-            },
-            _ => return Err(MalError::EvalError(format!("'hash-map': key must be either a string or keyword"))),
-        }
+    while let Some(key) = it.next() {
+        let value = it.next().ok_or(MalError::EvalError(format!("'hash-map': missing value for {}", key)))?;
+        res.insert(key.to_key("hash-map")?, value.clone());
     }
 
-    Ok(MalForm::HashMap(res))
+    Ok(MalForm::HashMap(res, None, None))
 }
 
 fn assoc(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let mut it = args.iter();
 
     let mut res = match it.next() {
-        Some(MalForm::HashMap(x)) => x.clone(),
+        Some(MalForm::HashMap(x, _, _)) => x.clone(),
         _ => return Err(MalError::EvalError(format!("'assoc': first argument must be a hash-map"))),
     };
 
-    while let Some(mkey) = it.next() {
-        match mkey {
-            MalForm::Key(key) => {
-                let value = it.next().ok_or(MalError::EvalError(format!("'assoc': missing value for {}", key)))?;
-                res.insert(key.clone(), value.clone());
-            },
-            _ => return Err(MalError::EvalError(format!("'assoc': key must be either a string or keyword"))),
-        }
+    while let Some(key) = it.next() {
+        let value = it.next().ok_or(MalError::EvalError(format!("'assoc': missing value for {}", key)))?;
+        res.insert(key.to_key("assoc")?, value.clone());
     }
 
-    Ok(MalForm::HashMap(res))
+    Ok(MalForm::HashMap(res, None, None))
 }
 
 fn dissoc(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let mut it = args.iter();
 
     let mut res = match it.next() {
-        Some(MalForm::HashMap(x)) => x.clone(),
+        Some(MalForm::HashMap(x, _, _)) => x.clone(),
         _ => return Err(MalError::EvalError(format!("'dissoc': first argument must be a hash-map"))),
     };
 
-    while let Some(mkey) = it.next() {
-        match mkey {
-            MalForm::Key(key) => {
-                res.remove(key);
-            },
-            _ => return Err(MalError::EvalError(format!("'dissoc': key must be either a string or keyword"))),
-        }
+    for key in it {
+        res.shift_remove(key);
     }
 
-    Ok(MalForm::HashMap(res))
+    Ok(MalForm::HashMap(res, None, None))
 }
 
 fn get(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let hm = match args.get(0) {
-        Some(MalForm::HashMap(x)) => x,
-        Some(MalForm::Nil) => return Ok(MalForm::Nil),
+        Some(MalForm::HashMap(x, _, _)) => x,
+        Some(MalForm::Nil(_)) => return Ok(MalForm::Nil(None)),
         _ => return Err(MalError::EvalError(format!("'get': first argument must be a hash-map"))),
     };
 
-    let key = match args.get(1) {
-        Some(MalForm::Key(k)) => k,
-        _ => return Err(MalError::EvalError(format!("'get': second argument must be either a string or keyword"))),
-    };
+    let key = args.get(1).ok_or(MalError::EvalError(format!("'get': missing key")))?;
 
     Ok(match hm.get(key) {
         Some(x) => x.clone(),
-        None => MalForm::Nil,
+        None => MalForm::Nil(None),
     })
 }
 
 fn contains_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
-    let hm = match args.get(0) {
-        Some(MalForm::HashMap(x)) => x,
-        Some(MalForm::Nil) => return Ok(MalForm::Nil),
-        _ => return Err(MalError::EvalError(format!("'contains?': first argument must be a hash-map"))),
+    let coll = match args.get(0) {
+        Some(x@MalForm::HashMap(_, _, _)) | Some(x@MalForm::Set(_, _, _)) => x,
+        Some(MalForm::Nil(_)) => return Ok(MalForm::Nil(None)),
+        _ => return Err(MalError::EvalError(format!("'contains?': first argument must be a hash-map or a set"))),
     };
 
-    let key = match args.get(1) {
-        Some(MalForm::Key(k)) => k,
-        _ => return Err(MalError::EvalError(format!("'contains?': second argument must be either a string or keyword"))),
-    };
+    let key = args.get(1).ok_or(MalError::EvalError(format!("'contains?': missing key")))?;
 
-    Ok(hm.contains_key(key).to_mal_form())
+    Ok(match coll {
+        MalForm::Set(s, _, _) => s.contains(key),
+        MalForm::HashMap(hm, _, _) => hm.contains_key(key),
+        _ => unreachable!(),
+    }.to_mal_form())
 }
 
 fn keys(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let hm = match args.get(0) {
-        Some(MalForm::HashMap(x)) => x,
-        // Some(MalForm::Nil) => return Ok(MalForm::Nil),
+        Some(MalForm::HashMap(x, _, _)) => x,
+        // Some(MalForm::Nil(_)) => return Ok(MalForm::Nil(_)),
         _ => return Err(MalError::EvalError(format!("'keys': first argument must be a hash-map"))),
     };
 
-    let res = hm.keys().map(|x| MalForm::Key(x.clone())).collect::<Vec<_>>();
+    let res = hm.keys().cloned().collect::<Vec<_>>();
 
-    Ok(MalForm::List(res))
+    Ok(MalForm::List(res, None, None))
 }
 
 fn vals(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let hm = match args.get(0) {
-        Some(MalForm::HashMap(x)) => x,
-        // Some(MalForm::Nil) => return Ok(MalForm::Nil),
+        Some(MalForm::HashMap(x, _, _)) => x,
+        // Some(MalForm::Nil(_)) => return Ok(MalForm::Nil(_)),
         _ => return Err(MalError::EvalError(format!("'vals': first argument must be a hash-map"))),
     };
 
     let res = hm.values().map(|x| x.clone()).collect::<Vec<_>>();
 
-    Ok(MalForm::List(res))
+    Ok(MalForm::List(res, None, None))
+}
+
+/// Gathers `xs` into a set.
+fn to_set<'a, I>(name: &str, xs: I) -> MalResult<MalSet>
+    where I: IntoIterator<Item = &'a MalForm>
+{
+    xs.into_iter().map(|x| x.to_set_element(name)).collect()
+}
+
+fn hash_set(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    Ok(MalForm::Set(to_set("hash-set", &args)?, None, None))
+}
+
+fn set(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    Ok(MalForm::Set(match args.get(0) {
+        Some(MalForm::List(xs, _, _)) | Some(MalForm::Vector(xs, _, _)) => to_set("set", xs)?,
+        Some(MalForm::Set(s, _, _)) => s.clone(),
+        Some(MalForm::Nil(_)) => MalSet::new(),
+        _ => return Err(MalError::EvalError(format!("'set': argument must be a list, a vector, a set or nil"))),
+    }, None, None))
+}
+
+fn set_q(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    Ok(match args.get(0) {
+        Some(MalForm::Set(_, _, _)) => true,
+        _ => false,
+    }.to_mal_form())
+}
+
+fn disj(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let mut it = args.iter();
+
+    let mut res = match it.next() {
+        Some(MalForm::Set(x, _, _)) => x.clone(),
+        _ => return Err(MalError::EvalError(format!("'disj': first argument must be a set"))),
+    };
+
+    for x in it {
+        res.shift_remove(x);
+    }
+
+    Ok(MalForm::Set(res, None, None))
+}
+
+/// `(conj coll x ...)` adds to the front of a list, the end of a vector, or
+/// to a set.
+fn conj(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let xs = args.get(1..).unwrap_or(&[]);
+    match args.get(0) {
+        Some(MalForm::List(l, _, _)) => {
+            let res = xs.iter().rev().chain(l).cloned().collect();
+            Ok(MalForm::List(res, None, None))
+        }
+        Some(MalForm::Vector(v, _, _)) => {
+            let res = v.iter().chain(xs).cloned().collect();
+            Ok(MalForm::Vector(res, None, None))
+        }
+        Some(MalForm::Set(s, _, _)) => {
+            let mut res = s.clone();
+            res.extend(to_set("conj", xs)?);
+            Ok(MalForm::Set(res, None, None))
+        }
+        _ => Err(MalError::EvalError(format!("'conj': first argument must be a list, a vector or a set"))),
+    }
+}
+
+/// The elements of a collection or the characters of a string as a list,
+/// or nil when there are none.
+fn seq(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let res: Vec<MalForm> = match args.get(0) {
+        Some(MalForm::List(xs, _, _)) | Some(MalForm::Vector(xs, _, _)) => xs.clone(),
+        Some(MalForm::Set(s, _, _)) => s.iter().cloned().collect(),
+        Some(MalForm::HashMap(hm, _, _)) => hm.iter()
+            .map(|(k, v)| MalForm::Vector(vec![k.clone(), v.clone()], None, None))
+            .collect(),
+        Some(MalForm::Key(MalKey::String(s), _)) => s.chars().map(|c| c.to_string().to_mal_form()).collect(),
+        Some(MalForm::Nil(_)) => vec![],
+        _ => return Err(MalError::EvalError(format!("'seq': argument must be a list, a vector, a set, a hash-map, a string or nil"))),
+    };
+
+    Ok(if res.is_empty() { MalForm::Nil(None) } else { MalForm::List(res, None, None) })
+}
+
+fn set_args<'a>(name: &str, args: &'a [MalForm]) -> MalResult<Vec<&'a MalSet>> {
+    args.iter().map(|x| match x {
+        MalForm::Set(s, _, _) => Ok(s),
+        _ => Err(MalError::EvalError(format!("'{}': arguments must be sets, {} was given", name, x))),
+    }).collect()
+}
+
+fn union(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let mut res = MalSet::new();
+    for s in set_args("union", &args)? {
+        res.extend(s.iter().cloned());
+    }
+
+    Ok(MalForm::Set(res, None, None))
+}
+
+fn intersection(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let sets = set_args("intersection", &args)?;
+    let (first, rest) = sets.split_first()
+        .ok_or(MalError::EvalError(format!("'intersection': at least one argument expected")))?;
+
+    let res = first.iter().filter(|k| rest.iter().all(|s| s.contains(*k))).cloned().collect();
+
+    Ok(MalForm::Set(res, None, None))
+}
+
+fn difference(args: Vec<MalForm>, _env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
+    let sets = set_args("difference", &args)?;
+    let (first, rest) = sets.split_first()
+        .ok_or(MalError::EvalError(format!("'difference': at least one argument expected")))?;
+
+    let res = first.iter().filter(|k| !rest.iter().any(|s| s.contains(*k))).cloned().collect();
+
+    Ok(MalForm::Set(res, None, None))
 }
@@ -0,0 +1,317 @@
+//! JSON conversion for `json-encode` and `json-decode`. Strings, numbers,
+//! booleans and nil map to themselves, lists and vectors to arrays, and
+//! hash-maps to objects.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use num_bigint::BigInt;
+
+use crate::numeric;
+use crate::types::{MalError, MalForm, MalKey, MalMap, MalResult};
+
+/// Where in a document a value is, such as `$.items[2].name`.
+fn path_str(path: &[String]) -> String {
+    format!("${}", path.concat())
+}
+
+fn key_segment(k: &str) -> String {
+    if !k.is_empty() && k.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+        format!(".{}", k)
+    } else {
+        format!("[{}]", quote(k))
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            '\u{8}' => res.push_str("\\b"),
+            '\u{c}' => res.push_str("\\f"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+pub fn encode(x: &MalForm) -> MalResult<String> {
+    let mut out = String::new();
+    encode_into(x, &mut vec![], &mut out)?;
+    Ok(out)
+}
+
+fn encode_into(x: &MalForm, path: &mut Vec<String>, out: &mut String) -> MalResult<()> {
+    match x {
+        MalForm::Nil(_) => out.push_str("null"),
+        MalForm::Bool(b, _) => out.push_str(if *b { "true" } else { "false" }),
+        MalForm::Int(i, _) => out.push_str(&i.to_string()),
+        MalForm::Bignum(b, _) => out.push_str(&b.to_string()),
+        MalForm::Number(n, _) if !n.is_finite() => {
+            return Err(MalError::EvalError(format!("'json-encode': cannot encode {} at {}", n, path_str(path))));
+        }
+        MalForm::Number(n, _) => out.push_str(&format!("{:?}", n)),
+        // Keywords are written as their name, as JSON has no keywords.
+        MalForm::Key(MalKey::String(s), _) | MalForm::Key(MalKey::Keyword(s), _) => out.push_str(&quote(s)),
+        MalForm::List(xs, _, _) | MalForm::Vector(xs, _, _) => {
+            out.push('[');
+            for (i, x) in xs.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                path.push(format!("[{}]", i));
+                encode_into(x, path, out)?;
+                path.pop();
+            }
+            out.push(']');
+        }
+        MalForm::HashMap(xs, _, _) => {
+            out.push('{');
+            for (i, (k, v)) in xs.iter().enumerate() {
+                let k = match k {
+                    MalForm::Key(MalKey::String(s), _) | MalForm::Key(MalKey::Keyword(s), _) => s,
+                    _ => return Err(MalError::EvalError(format!("'json-encode': cannot encode key {} at {}", k, path_str(path)))),
+                };
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&quote(k));
+                out.push(':');
+                path.push(key_segment(k));
+                encode_into(v, path, out)?;
+                path.pop();
+            }
+            out.push('}');
+        }
+        // JSON has no sets, so they go out as arrays.
+        MalForm::Set(xs, _, _) => {
+            let xs = xs.iter().cloned().collect();
+            encode_into(&MalForm::Vector(xs, None, None), path, out)?;
+        }
+        MalForm::Symbol(..) | MalForm::Ratio(_, _) | MalForm::Regex(_, _) | MalForm::Atom(_) | MalForm::NativeFn(..) | MalForm::MalFn(_) => {
+            let kind = match x {
+                MalForm::Symbol(..) => "symbol",
+                MalForm::Ratio(_, _) => "ratio",
+                MalForm::Regex(_, _) => "regex",
+                MalForm::Atom(_) => "atom",
+                _ => "function",
+            };
+            return Err(MalError::EvalError(format!("'json-encode': cannot encode {} {} at {}", kind, x, path_str(path))));
+        }
+    }
+    Ok(())
+}
+
+/// Arrays and objects nested deeper than this are refused rather than
+/// decoded recursively until the stack runs out.
+const MAX_DEPTH: usize = 512;
+
+struct Decoder<'a> {
+    chars: Peekable<Chars<'a>>,
+    path: Vec<String>,
+    keywordize: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn error<T>(&self, msg: &str) -> MalResult<T> {
+        Err(MalError::EvalError(format!("'json-decode': {} at {}", msg, path_str(&self.path))))
+    }
+
+    fn unexpected<T>(&mut self) -> MalResult<T> {
+        match self.chars.peek() {
+            Some(&c) => self.error(&format!("unexpected {:?}", c)),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = self.chars.peek() {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> MalResult<()> {
+        for c in word.chars() {
+            if self.chars.peek() != Some(&c) {
+                return self.unexpected();
+            }
+            self.chars.next();
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> MalResult<MalForm> {
+        self.skip_ws();
+        match self.chars.peek() {
+            Some('n') => self.expect("null").map(|_| MalForm::Nil(None)),
+            Some('t') => self.expect("true").map(|_| MalForm::Bool(true, None)),
+            Some('f') => self.expect("false").map(|_| MalForm::Bool(false, None)),
+            Some('"') => Ok(MalForm::Key(MalKey::String(self.string()?), None)),
+            Some('[') | Some('{') if self.path.len() >= MAX_DEPTH => self.error("too deeply nested"),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            _ => self.unexpected(),
+        }
+    }
+
+    fn array(&mut self) -> MalResult<MalForm> {
+        self.chars.next();
+        let mut items = vec![];
+        self.skip_ws();
+        if self.chars.peek() == Some(&']') {
+            self.chars.next();
+            return Ok(MalForm::Vector(items, None, None));
+        }
+        loop {
+            self.path.push(format!("[{}]", items.len()));
+            items.push(self.value()?);
+            self.path.pop();
+            self.skip_ws();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(MalForm::Vector(items, None, None)),
+                Some(c) => return self.error(&format!("expected ',' or ']', found {:?}", c)),
+                None => return self.error("unexpected end of input"),
+            }
+        }
+    }
+
+    fn object(&mut self) -> MalResult<MalForm> {
+        self.chars.next();
+        let mut res = MalMap::new();
+        self.skip_ws();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(MalForm::HashMap(res, None, None));
+        }
+        loop {
+            self.skip_ws();
+            match self.chars.peek() {
+                Some('"') => (),
+                Some(&c) => return self.error(&format!("expected a string key, found {:?}", c)),
+                None => return self.error("unexpected end of input"),
+            }
+            let k = self.string()?;
+            self.skip_ws();
+            if self.chars.next() != Some(':') {
+                return self.error(&format!("expected ':' after key {}", quote(&k)));
+            }
+            self.path.push(key_segment(&k));
+            let v = self.value()?;
+            self.path.pop();
+            let k = MalForm::Key(if self.keywordize { MalKey::Keyword(k) } else { MalKey::String(k) }, None);
+            res.insert(k, v);
+            self.skip_ws();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(MalForm::HashMap(res, None, None)),
+                Some(c) => return self.error(&format!("expected ',' or '}}', found {:?}", c)),
+                None => return self.error("unexpected end of input"),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> MalResult<u32> {
+        let mut n = 0;
+        for _ in 0..4 {
+            match self.chars.next().and_then(|c| c.to_digit(16)) {
+                Some(d) => n = n * 16 + d,
+                None => return self.error("invalid \\u escape"),
+            }
+        }
+        Ok(n)
+    }
+
+    fn string(&mut self) -> MalResult<String> {
+        self.chars.next();
+        let mut res = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(res),
+                Some('\\') => {
+                    let c = match self.chars.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let mut n = self.hex4()?;
+                            // a surrogate pair is written as two escapes; a
+                            // surrogate on its own is not a character
+                            if (0xd800..0xe000).contains(&n) {
+                                let lo = if n < 0xdc00 && self.chars.clone().take(2).eq("\\u".chars()) {
+                                    self.chars.nth(1);
+                                    self.hex4()?
+                                } else {
+                                    0
+                                };
+                                if !(0xdc00..0xe000).contains(&lo) {
+                                    return self.error(&format!("invalid surrogate \\u{:04x}", n));
+                                }
+                                n = 0x10000 + ((n - 0xd800) << 10) + (lo - 0xdc00);
+                            }
+                            match std::char::from_u32(n) {
+                                Some(c) => c,
+                                None => return self.error("invalid \\u escape"),
+                            }
+                        }
+                        Some(c) => return self.error(&format!("invalid escape \\{}", c)),
+                        None => return self.error("unterminated string"),
+                    };
+                    res.push(c);
+                }
+                Some(c) if (c as u32) < 0x20 => return self.error("unescaped control character in string"),
+                Some(c) => res.push(c),
+                None => return self.error("unterminated string"),
+            }
+        }
+    }
+
+    fn number(&mut self) -> MalResult<MalForm> {
+        let mut s = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_digit() || "-+.eE".contains(c)) {
+                break;
+            }
+            s.push(c);
+            self.chars.next();
+        }
+        let digits = s.trim_start_matches('-');
+        if digits.is_empty() || (digits.starts_with('0') && digits[1..].starts_with(|c: char| c.is_ascii_digit())) {
+            return self.error(&format!("invalid number {}", s));
+        }
+        if !s.contains(|c| ".eE".contains(c)) {
+            return match s.parse::<BigInt>() {
+                Ok(n) => Ok(numeric::from_bigint(n)),
+                Err(_) => self.error(&format!("invalid number {}", s)),
+            };
+        }
+        match s.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(MalForm::Number(n, None)),
+            Ok(_) => self.error(&format!("number {} is out of range", s)),
+            Err(_) => self.error(&format!("invalid number {}", s)),
+        }
+    }
+}
+
+pub fn decode(s: &str, keywordize: bool) -> MalResult<MalForm> {
+    let mut d = Decoder { chars: s.chars().peekable(), path: vec![], keywordize };
+    let res = d.value()?;
+    d.skip_ws();
+    match d.chars.peek() {
+        None => Ok(res),
+        Some(_) => d.unexpected(),
+    }
+}
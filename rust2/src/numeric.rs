@@ -0,0 +1,162 @@
+//! The numeric tower. Ints become bignums when they overflow, dividing ints
+//! that don't divide exactly gives a ratio, and anything with a float in it
+//! gives a float. Exact results are brought back down to the smallest type
+//! that holds them, so an exact number has only one representation.
+
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{ToPrimitive, Zero};
+
+use crate::types::{MalError, MalForm, MalResult};
+
+enum Num {
+    Int(i64),
+    Exact(BigRational),
+    Float(f64),
+}
+
+impl Num {
+    fn to_exact(&self) -> BigRational {
+        match self {
+            Num::Int(i) => BigRational::from_integer(BigInt::from(*i)),
+            Num::Exact(r) => r.clone(),
+            Num::Float(_) => unreachable!(),
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        match self {
+            Num::Int(i) => *i as f64,
+            Num::Exact(r) => ratio_to_f64(r),
+            Num::Float(f) => *f,
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Num::Int(i) => *i == 0,
+            Num::Exact(r) => r.is_zero(),
+            Num::Float(f) => *f == 0.0,
+        }
+    }
+}
+
+fn num(name: &str, x: &MalForm) -> MalResult<Num> {
+    match x {
+        MalForm::Int(i, _) => Ok(Num::Int(*i)),
+        MalForm::Bignum(b, _) => Ok(Num::Exact(BigRational::from_integer((**b).clone()))),
+        MalForm::Ratio(r, _) => Ok(Num::Exact((**r).clone())),
+        MalForm::Number(n, _) => Ok(Num::Float(*n)),
+        _ => Err(MalError::EvalError(format!("'{}': arguments must be numbers, {} was given", name, x))),
+    }
+}
+
+fn ratio_to_f64(r: &BigRational) -> f64 {
+    let n = r.numer().to_f64().unwrap_or(f64::NAN);
+    let d = r.denom().to_f64().unwrap_or(f64::NAN);
+    n / d
+}
+
+/// Hashes a number by its exact value, as `compare` compares floats with
+/// exact numbers, so `2.0` hashes like `2` and `0.5` like `1/2`. NaN and the
+/// infinities, which equal no exact number, hash by their bits.
+pub fn hash<H: Hasher>(x: &MalForm, state: &mut H) {
+    match x {
+        MalForm::Int(i, _) => i.hash(state),
+        MalForm::Bignum(b, _) => b.hash(state),
+        MalForm::Ratio(r, _) => r.hash(state),
+        MalForm::Number(f, _) => match BigRational::from_float(*f) {
+            Some(r) => hash(&from_ratio(r), state),
+            None => f.to_bits().hash(state),
+        },
+        _ => (),
+    }
+}
+
+pub fn from_bigint(b: BigInt) -> MalForm {
+    match b.to_i64() {
+        Some(i) => MalForm::Int(i, None),
+        None => MalForm::Bignum(Rc::new(b), None),
+    }
+}
+
+pub fn from_ratio(r: BigRational) -> MalForm {
+    if r.is_integer() {
+        from_bigint(r.to_integer())
+    } else {
+        MalForm::Ratio(Rc::new(r), None)
+    }
+}
+
+/// Ints are tried with `int` first, which gives `None` on overflow.
+fn arith(
+    name: &str,
+    a: &MalForm,
+    b: &MalForm,
+    int: fn(i64, i64) -> Option<i64>,
+    exact: fn(&BigRational, &BigRational) -> BigRational,
+    float: fn(f64, f64) -> f64,
+) -> MalResult<MalForm> {
+    let (x, y) = (num(name, a)?, num(name, b)?);
+    if let (Num::Int(i), Num::Int(j)) = (&x, &y) {
+        if let Some(res) = int(*i, *j) {
+            return Ok(MalForm::Int(res, None));
+        }
+    }
+    match (x, y) {
+        (Num::Float(f), y) => Ok(MalForm::Number(float(f, y.to_f64()), None)),
+        (x, Num::Float(f)) => Ok(MalForm::Number(float(x.to_f64(), f), None)),
+        (x, y) => Ok(from_ratio(exact(&x.to_exact(), &y.to_exact()))),
+    }
+}
+
+pub fn add(a: &MalForm, b: &MalForm) -> MalResult<MalForm> {
+    arith("+", a, b, i64::checked_add, |x, y| x + y, |x, y| x + y)
+}
+
+pub fn sub(a: &MalForm, b: &MalForm) -> MalResult<MalForm> {
+    arith("-", a, b, i64::checked_sub, |x, y| x - y, |x, y| x - y)
+}
+
+pub fn mul(a: &MalForm, b: &MalForm) -> MalResult<MalForm> {
+    arith("*", a, b, i64::checked_mul, |x, y| x * y, |x, y| x * y)
+}
+
+pub fn div(a: &MalForm, b: &MalForm) -> MalResult<MalForm> {
+    match (num("/", a)?, num("/", b)?) {
+        (Num::Float(f), y) => Ok(MalForm::Number(f / y.to_f64(), None)),
+        (x, Num::Float(f)) => Ok(MalForm::Number(x.to_f64() / f, None)),
+        (_, ref y) if y.is_zero() => Err(MalError::EvalError("'/': division by zero".to_string())),
+        // Only i64::MIN / -1 overflows, and its remainder overflows too.
+        (Num::Int(i), Num::Int(j)) if i.checked_rem(j) == Some(0) => Ok(MalForm::Int(i / j, None)),
+        (x, y) => Ok(from_ratio(x.to_exact() / y.to_exact())),
+    }
+}
+
+/// A float against an exact number, by the exact value of the float rather
+/// than by rounding the exact number to a float, so that `=` stays
+/// transitive.
+fn compare_float(f: f64, x: &Num) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f.is_infinite() {
+        Some(if f > 0.0 { Ordering::Greater } else { Ordering::Less })
+    } else {
+        BigRational::from_float(f).map(|r| r.cmp(&x.to_exact()))
+    }
+}
+
+/// `None` when either number is NaN.
+pub fn compare(name: &str, a: &MalForm, b: &MalForm) -> MalResult<Option<Ordering>> {
+    match (num(name, a)?, num(name, b)?) {
+        (Num::Int(i), Num::Int(j)) => Ok(Some(i.cmp(&j))),
+        (Num::Float(f), Num::Float(g)) => Ok(f.partial_cmp(&g)),
+        (Num::Float(f), y) => Ok(compare_float(f, &y)),
+        (x, Num::Float(f)) => Ok(compare_float(f, &x).map(Ordering::reverse)),
+        (x, y) => Ok(Some(x.to_exact().cmp(&y.to_exact()))),
+    }
+}
@@ -1,7 +1,10 @@
 #![allow(dead_code)]
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::rc::Rc;
 
-use crate::types::{MalForm,MalKey,ToMalForm};
+use crate::types::{MalForm,MalKey};
 
 impl MalForm {
     pub fn pr_str(&self, print_readably: bool) -> String {
@@ -18,6 +21,10 @@ pub fn escape_string(s: &String) -> String {
             '\\' => res.push_str("\\\\"),
             '\n' => res.push_str("\\n"),
             '"' => res.push_str("\\\""),
+            '\t' => res.push_str("\\t"),
+            '\r' => res.push_str("\\r"),
+            '\0' => res.push_str("\\0"),
+            c if c.is_control() => res.push_str(&format!("\\u{{{:x}}}", c as u32)),
             _ => res.push(c),
         }
     }
@@ -25,31 +32,120 @@ pub fn escape_string(s: &String) -> String {
     res
 }
 
+type AtomPtr = *const RefCell<MalForm>;
+
+/// Atoms that lead back to themselves, numbered as they are first printed,
+/// so that a cycle prints as `#1=(atom (#1#))` rather than forever.
+struct Cycles {
+    cyclic: HashSet<AtomPtr>,
+    labels: HashMap<AtomPtr, usize>,
+}
+
+impl Cycles {
+    fn new(x: &MalForm) -> Cycles {
+        let mut cyclic = HashSet::new();
+        find_cycles(x, &mut vec![], &mut HashSet::new(), &mut cyclic);
+        Cycles { cyclic, labels: HashMap::new() }
+    }
+
+    /// The label of a cyclic atom, and whether this is its first appearance.
+    fn label(&mut self, a: &Rc<RefCell<MalForm>>) -> Option<(usize, bool)> {
+        let p = &**a as AtomPtr;
+        if !self.cyclic.contains(&p) {
+            return None;
+        }
+        if let Some(&n) = self.labels.get(&p) {
+            return Some((n, false));
+        }
+        let n = self.labels.len() + 1;
+        self.labels.insert(p, n);
+        Some((n, true))
+    }
+}
+
+fn find_cycles(x: &MalForm, path: &mut Vec<AtomPtr>, done: &mut HashSet<AtomPtr>, cyclic: &mut HashSet<AtomPtr>) {
+    match x {
+        MalForm::List(xs, _, _) | MalForm::Vector(xs, _, _) => {
+            for x in xs {
+                find_cycles(x, path, done, cyclic);
+            }
+        }
+        MalForm::HashMap(xs, _, _) => {
+            for x in xs.values() {
+                find_cycles(x, path, done, cyclic);
+            }
+        }
+        MalForm::Atom(a) => {
+            let p = &**a as AtomPtr;
+            if path.contains(&p) {
+                cyclic.insert(p);
+            } else if done.insert(p) {
+                path.push(p);
+                find_cycles(&a.borrow(), path, done, cyclic);
+                path.pop();
+            }
+        }
+        _ => (),
+    }
+}
+
 pub fn pr_str(x: &MalForm, print_readably: bool) -> String {
+    pr(x, print_readably, &mut Cycles::new(x))
+}
+
+fn pr(x: &MalForm, print_readably: bool, cy: &mut Cycles) -> String {
     match x {
-        MalForm::NativeFn(name, _) => format!("#<{}>", name),
-        MalForm::MalFn(_) => format!("#<fn*>"),
-        MalForm::Key(MalKey::String(s)) =>
+        MalForm::NativeFn(name, _, _) => format!("#<fn {}>", name),
+        MalForm::MalFn(f) => {
+            let params = f.params.join(" ");
+            match &f.name {
+                Some(name) => format!("#<fn {} [{}]>", name, params),
+                None => format!("#<fn [{}]>", params),
+            }
+        }
+        MalForm::Key(MalKey::String(s), _) =>
             if print_readably { format!("\"{}\"", escape_string(s)) } else { s.clone() },
-        MalForm::Key(MalKey::Keyword(s)) => format!(":{}", s),
-        MalForm::Number(n) => format!("{}", n),
-        MalForm::Symbol(s) => format!("{}", s),
-        MalForm::Bool(true) => format!("true"),
-        MalForm::Bool(false) => format!("false"),
-        MalForm::Nil => format!("nil"),
-        MalForm::List(xs) => format!("({})", pr_seq(xs, " ", print_readably)),
-        MalForm::Vector(xs) => format!("[{}]", pr_seq(xs, " ", print_readably)),
-        MalForm::HashMap(xs) => {
+        MalForm::Key(MalKey::Keyword(s), _) => format!(":{}", s),
+        MalForm::Int(i, _) => format!("{}", i),
+        MalForm::Bignum(b, _) => format!("{}", b),
+        MalForm::Ratio(r, _) => format!("{}", r),
+        MalForm::Number(n, _) if n.is_nan() => format!("##NaN"),
+        MalForm::Number(n, _) if n.is_infinite() => format!("{}", if *n > 0.0 { "##Inf" } else { "##-Inf" }),
+        // Debug formatting always has a '.' or an exponent, so floats read back as floats.
+        MalForm::Number(n, _) => format!("{:?}", n),
+        MalForm::Symbol(s, _) => format!("{}", s),
+        // Only a '"' needs escaping, as the reader takes the rest of the
+        // pattern as written.
+        MalForm::Regex(re, _) =>
+            if print_readably { format!("#\"{}\"", re.as_str().replace('"', "\\\"")) } else { re.as_str().to_string() },
+        MalForm::Bool(true, _) => format!("true"),
+        MalForm::Bool(false, _) => format!("false"),
+        MalForm::Nil(_) => format!("nil"),
+        MalForm::List(xs, _, _) => format!("({})", pr_items(xs, print_readably, cy)),
+        MalForm::Vector(xs, _, _) => format!("[{}]", pr_items(xs, print_readably, cy)),
+        MalForm::HashMap(xs, _, _) => {
             let v: Vec<MalForm> = xs
                 .into_iter()
-                .flat_map(|(k, v)| vec![k.to_mal_form(), v.clone()])
+                .flat_map(|(k, v)| vec![k.clone(), v.clone()])
                 .collect();
-            format!("{{{}}}", pr_seq(&v, " ", print_readably))
+            format!("{{{}}}", pr_items(&v, print_readably, cy))
+        },
+        MalForm::Set(xs, _, _) => {
+            let v: Vec<MalForm> = xs.iter().cloned().collect();
+            format!("#{{{}}}", pr_items(&v, print_readably, cy))
+        },
+        MalForm::Atom(x) => match cy.label(x) {
+            Some((n, false)) => format!("#{}#", n),
+            Some((n, true)) => format!("#{}=(atom {})", n, pr(&x.borrow(), print_readably, cy)),
+            None => format!("(atom {})", pr(&x.borrow(), print_readably, cy)),
         },
-        MalForm::Atom(x) => format!("(atom {})", pr_str(&x.borrow(), print_readably)),
     }
 }
 
+fn pr_items(xs: &[MalForm], print_readably: bool, cy: &mut Cycles) -> String {
+    xs.iter().map(|x| pr(x, print_readably, cy)).collect::<Vec<_>>().join(" ")
+}
+
 pub fn pr_seq(xs: &Vec<MalForm>, separator: &str, print_readably: bool) -> String {
     let mut res = String::new();
     let mut it = xs.into_iter();
@@ -66,6 +162,187 @@ pub fn pr_seq(xs: &Vec<MalForm>, separator: &str, print_readably: bool) -> Strin
     res
 }
 
+/// A document for the pretty printer, after Wadler's "A prettier printer".
+enum Doc {
+    Text(String),
+    /// A space, or a newline and indentation if its group is broken.
+    Line,
+    Nest(usize, Box<Doc>),
+    /// Sets the indentation to the column the document starts in.
+    Align(Box<Doc>),
+    Cat(Vec<Doc>),
+    /// Laid out on one line if it fits, otherwise broken at its lines.
+    Group(Box<Doc>),
+}
+
+fn text(s: &str) -> Doc {
+    Doc::Text(s.to_string())
+}
+
+fn lines(docs: Vec<Doc>) -> Doc {
+    let mut res = vec![];
+    for (i, d) in docs.into_iter().enumerate() {
+        if i > 0 {
+            res.push(Doc::Line);
+        }
+        res.push(d);
+    }
+    Doc::Cat(res)
+}
+
+/// Groups each pair of documents, for bindings and map entries.
+fn pairs(docs: Vec<Doc>) -> Vec<Doc> {
+    let mut res = vec![];
+    let mut it = docs.into_iter();
+    while let Some(a) = it.next() {
+        match it.next() {
+            Some(b) => res.push(Doc::Group(Box::new(Doc::Cat(vec![a, Doc::Line, b])))),
+            None => res.push(a),
+        }
+    }
+    res
+}
+
+fn seq_doc(open: &str, items: Vec<Doc>, close: &str) -> Doc {
+    Doc::Group(Box::new(Doc::Cat(vec![
+        text(open),
+        Doc::Align(Box::new(lines(items))),
+        text(close),
+    ])))
+}
+
+/// `(head first rest...)`, with `rest` indented by `indent` from the
+/// opening paren when broken.
+fn form_doc(head: &str, first: Option<Doc>, rest: Vec<Doc>, indent: usize) -> Doc {
+    let mut docs = vec![text("("), text(head)];
+    if let Some(first) = first {
+        docs.push(text(" "));
+        docs.push(first);
+    }
+    if !rest.is_empty() {
+        docs.push(Doc::Nest(indent, Box::new(Doc::Cat(vec![Doc::Line, lines(rest)]))));
+    }
+    docs.push(text(")"));
+    Doc::Group(Box::new(Doc::Align(Box::new(Doc::Cat(docs)))))
+}
+
+fn docs(xs: &[MalForm], cy: &mut Cycles) -> Vec<Doc> {
+    xs.iter().map(|x| doc(x, cy)).collect()
+}
+
+fn doc(x: &MalForm, cy: &mut Cycles) -> Doc {
+    match x {
+        MalForm::List(xs, _, _) => {
+            let head = match xs.first() {
+                Some(MalForm::Symbol(s, _)) => s.as_str(),
+                _ => return seq_doc("(", docs(xs, cy), ")"),
+            };
+            match head {
+                "let*" | "fn*" | "def!" | "defmacro!" if xs.len() > 2 => {
+                    let first = match &xs[1] {
+                        MalForm::Vector(b, _, _) if head == "let*" => seq_doc("[", pairs(docs(b, cy)), "]"),
+                        MalForm::List(b, _, _) if head == "let*" => seq_doc("(", pairs(docs(b, cy)), ")"),
+                        x => doc(x, cy),
+                    };
+                    form_doc(head, Some(first), docs(&xs[2..], cy), 2)
+                }
+                "if" if xs.len() > 2 => form_doc(head, Some(doc(&xs[1], cy)), docs(&xs[2..], cy), 4),
+                "cond" => form_doc(head, None, pairs(docs(&xs[1..], cy)), 2),
+                _ => form_doc(head, None, docs(&xs[1..], cy), 2),
+            }
+        }
+        MalForm::Vector(xs, _, _) => seq_doc("[", docs(xs, cy), "]"),
+        MalForm::HashMap(xs, _, _) => {
+            let v: Vec<MalForm> = xs
+                .iter()
+                .flat_map(|(k, v)| vec![k.clone(), v.clone()])
+                .collect();
+            seq_doc("{", pairs(docs(&v, cy)), "}")
+        }
+        MalForm::Set(xs, _, _) => {
+            let v: Vec<MalForm> = xs.iter().cloned().collect();
+            seq_doc("#{", docs(&v, cy), "}")
+        }
+        MalForm::Atom(a) => match cy.label(a) {
+            Some((n, false)) => Doc::Text(format!("#{}#", n)),
+            Some((n, true)) => Doc::Cat(vec![
+                Doc::Text(format!("#{}=", n)),
+                form_doc("atom", Some(doc(&a.borrow(), cy)), vec![], 2),
+            ]),
+            None => form_doc("atom", Some(doc(&a.borrow(), cy)), vec![], 2),
+        },
+        _ => Doc::Text(pr_str(x, true)),
+    }
+}
+
+/// Prints `x` readably, laid out to fit in `width` columns where possible.
+pub fn pprint(x: &MalForm, width: usize) -> String {
+    let doc = doc(x, &mut Cycles::new(x));
+    let mut out = String::new();
+    let mut col = 0;
+    // (indentation, flat, document) still to lay out, last first
+    let mut stack: Vec<(usize, bool, &Doc)> = vec![(0, false, &doc)];
+    while let Some((indent, flat, d)) = stack.pop() {
+        match d {
+            Doc::Text(s) => {
+                out.push_str(s);
+                col += s.chars().count();
+            }
+            Doc::Line if flat => {
+                out.push(' ');
+                col += 1;
+            }
+            Doc::Line => {
+                out.push('\n');
+                out.push_str(&" ".repeat(indent));
+                col = indent;
+            }
+            Doc::Nest(i, d) => stack.push((indent + i, flat, d)),
+            Doc::Align(d) => stack.push((col, flat, d)),
+            Doc::Cat(ds) => {
+                for d in ds.iter().rev() {
+                    stack.push((indent, flat, d));
+                }
+            }
+            Doc::Group(d) => {
+                let flat = flat || fits(width as isize - col as isize, d, &stack);
+                stack.push((indent, flat, d));
+            }
+        }
+    }
+    out
+}
+
+/// Whether `doc` laid out flat, and what follows it up to the next line
+/// break, fits in `room` columns.
+fn fits(mut room: isize, doc: &Doc, rest: &[(usize, bool, &Doc)]) -> bool {
+    let mut stack = vec![(true, doc)];
+    let mut rest = rest.iter().rev();
+    loop {
+        let (flat, d) = match stack.pop() {
+            Some(x) => x,
+            None => match rest.next() {
+                Some(&(_, flat, d)) => (flat, d),
+                None => return true,
+            },
+        };
+        match d {
+            Doc::Text(s) => room -= s.chars().count() as isize,
+            Doc::Line if flat => room -= 1,
+            Doc::Line => return true,
+            Doc::Nest(_, d) | Doc::Align(d) | Doc::Group(d) => stack.push((flat, d)),
+            Doc::Cat(ds) => {
+                for d in ds.iter().rev() {
+                    stack.push((flat, d));
+                }
+            }
+        }
+        if room < 0 {
+            return false;
+        }
+    }
+}
+
 impl fmt::Display for MalForm {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", pr_str(self, true))
@@ -1,21 +1,465 @@
 use lalrpop_util::lalrpop_mod;
 lalrpop_mod!(pub reader);
 
-use crate::types::{MalForm, MalError};
+use std::fmt;
+use std::str::FromStr;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use lalrpop_util::ParseError;
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
+use regex::Regex;
+
+use crate::types::{MalForm, MalKey, MalMap, MalError, MalResult, Span, ToMalForm};
+use crate::utils::Source;
+use crate::numeric;
+
+pub const UNBALANCED_QUOTE: &str = "Detected unbalanced quote";
+
+/// Handler for a `#tag` literal or a reader macro, called with the form that
+/// follows it.
+pub type ReadHandler = Rc<dyn Fn(MalForm) -> MalResult<MalForm>>;
+
+thread_local! {
+    static TAG_READERS: RefCell<HashMap<String, ReadHandler>> = {
+        let mut readers: HashMap<String, ReadHandler> = HashMap::new();
+        readers.insert("inst".to_string(), Rc::new(string_tag));
+        readers.insert("uuid".to_string(), Rc::new(string_tag));
+        RefCell::new(readers)
+    };
+}
+
+/// `#inst` and `#uuid` values are kept as their strings.
+fn string_tag(form: MalForm) -> MalResult<MalForm> {
+    match form {
+        MalForm::Key(MalKey::String(_), _) => Ok(form),
+        _ => Err(MalError::EvalError(format!("tagged literal expects a string, {} was given", form))),
+    }
+}
+
+/// Makes `#tag form` read as `f` applied to `form`.
+pub fn set_tag_reader(tag: &str, f: ReadHandler) {
+    TAG_READERS.with(|readers| readers.borrow_mut().insert(tag.to_string(), f));
+}
+
+thread_local! {
+    static READER_MACROS: RefCell<HashMap<char, ReadHandler>> = RefCell::new(HashMap::new());
+}
+
+/// Makes `c` a reader macro, or stops it being one when `f` is `None`.
+///
+/// A symbol starting with `c` reads as `f` applied to the rest of the symbol,
+/// and `c` on its own in a list, a vector or at the top level reads as `f`
+/// applied to the form after it.
+pub fn set_reader_macro(c: &str, f: Option<ReadHandler>) -> Result<(), String> {
+    let mut chars = c.chars();
+    let c = match (chars.next(), chars.next()) {
+        (Some(c), None) => c,
+        _ => return Err("reader macro must be a single character".to_string()),
+    };
+    if c.is_whitespace() || c.is_ascii_digit() || "()[]{}\"'`~@^#;,:".contains(c) {
+        return Err(format!("'{}' cannot be a reader macro", c));
+    }
+
+    READER_MACROS.with(|macros| match f {
+        Some(f) => macros.borrow_mut().insert(c, f),
+        None => macros.borrow_mut().remove(&c),
+    });
+    Ok(())
+}
+
+/// Applies reader macros in `form`. If `form` is a reader macro character on
+/// its own, its argument is taken from `rest`.
+pub fn expand_reader_macros<I>(form: MalForm, rest: &mut I) -> MalResult<MalForm>
+    where I: Iterator<Item = MalForm>
+{
+    let macros = READER_MACROS.with(|macros| macros.borrow().clone());
+    if macros.is_empty() {
+        return Ok(form);
+    }
+    expand(form, rest, &macros)
+}
+
+fn expand<I>(form: MalForm, rest: &mut I, macros: &HashMap<char, ReadHandler>) -> MalResult<MalForm>
+    where I: Iterator<Item = MalForm>
+{
+    match form {
+        MalForm::List(list, span, meta) => Ok(MalForm::List(expand_seq(list, macros)?, span, meta)),
+        MalForm::Vector(vector, span, meta) => Ok(MalForm::Vector(expand_seq(vector, macros)?, span, meta)),
+        MalForm::HashMap(hash, span, meta) => {
+            let hash = hash.into_iter()
+                .map(|(k, v)| {
+                    let k = expand(k, &mut None.into_iter(), macros)?.to_key("reader macro")?;
+                    Ok((k, expand(v, &mut None.into_iter(), macros)?))
+                })
+                .collect::<MalResult<_>>()?;
+            Ok(MalForm::HashMap(hash, span, meta))
+        },
+        MalForm::Set(set, span, meta) => {
+            let set = set.into_iter()
+                .map(|x| expand(x, &mut None.into_iter(), macros)?.to_set_element("reader macro"))
+                .collect::<MalResult<_>>()?;
+            Ok(MalForm::Set(set, span, meta))
+        },
+        MalForm::Symbol(name, span) => {
+            let mut chars = name.chars();
+            let f = match chars.next().and_then(|c| macros.get(&c)) {
+                Some(f) => f,
+                None => return Ok(MalForm::Symbol(name, span)),
+            };
+            let arg = match chars.as_str() {
+                "" => rest.next()
+                    .ok_or_else(|| MalError::EvalError(format!("expected form after '{}'", name)))
+                    .and_then(|arg| expand(arg, rest, macros)),
+                tail => read_str(tail),
+            };
+            arg.and_then(|arg| f(arg)).map_err(|err| err.at(span.as_ref()))
+        },
+        form => Ok(form),
+    }
+}
+
+fn expand_seq(forms: Vec<MalForm>, macros: &HashMap<char, ReadHandler>) -> MalResult<Vec<MalForm>> {
+    let mut res = Vec::new();
+    let mut it = forms.into_iter();
+    while let Some(form) = it.next() {
+        res.push(expand(form, &mut it, macros)?);
+    }
+    Ok(res)
+}
 
 pub fn read_str(str: &str) -> Result<MalForm, MalError> {
+    let source = Source::new(str, None);
     let mut errors = Vec::new();
 
-    match reader::FormParser::new().parse(&mut errors, str) {
-        Ok(res) => {
-            if errors.is_empty() {
-                Ok(res)
-            } else {
-                Err(MalError::ParseError(errors[0].clone()))
-            }
+    let res = reader::FormParser::new().parse(&mut errors, &source, str)
+        .map_err(|err| err.map_token(|t| (t.0, t.1.to_string())));
+    let form = check(res, errors, &source)?;
+    expand_reader_macros(form, &mut None.into_iter())
+}
+
+/// Reads every top-level form in `str`, along with where each one is.
+pub fn read_all(str: &str, file: Option<&str>) -> Result<Vec<(MalForm, Rc<Span>)>, MalError> {
+    let mut forms = parse_all(str, file)?.into_iter();
+    let mut res = Vec::new();
+    while let Some((form, span)) = forms.next() {
+        let form = expand_reader_macros(form, &mut forms.by_ref().map(|(form, _)| form))
+            .map_err(|err| err.at(Some(&span)))?;
+        res.push((form, span));
+    }
+    Ok(res)
+}
+
+/// Like `read_all`, but leaves reader macros for `expand_reader_macros`, so
+/// that ones defined while evaluating the forms apply to the forms after.
+pub fn parse_all(str: &str, file: Option<&str>) -> Result<Vec<(MalForm, Rc<Span>)>, MalError> {
+    let source = Source::new(str, file);
+    let mut errors = Vec::new();
+
+    let res = reader::FormsParser::new().parse(&mut errors, &source, str)
+        .map_err(|err| err.map_token(|t| (t.0, t.1.to_string())));
+    check(res, errors, &source)
+}
+
+/// Whether `err` comes from input that ended mid-form, such as an unclosed
+/// list or string, so that reading more input could complete it.
+pub fn is_incomplete(err: &MalError) -> bool {
+    match err {
+        MalError::Located(_, err) => is_incomplete(err),
+        MalError::ParseErrors(errs) => errs.iter().any(is_incomplete),
+        MalError::ParseError(ParseError::UnrecognizedToken { token: None, .. }) => true,
+        MalError::ParseError(ParseError::User { error }) => *error == UNBALANCED_QUOTE,
+        _ => false,
+    }
+}
+
+/// A node of the lossless syntax tree read by `read_cst`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cst {
+    /// Whitespace, commas and comments.
+    Trivia(String),
+    /// An atom as written.
+    Atom(String, Rc<Span>),
+    /// A bracketed sequence, trivia included.
+    Seq { open: String, items: Vec<Cst>, close: String, span: Rc<Span> },
+    /// A reader macro such as `'` or `#_`, then the trivia and forms it applies to.
+    Prefixed { prefix: String, items: Vec<Cst>, span: Rc<Span> },
+}
+
+impl Cst {
+    fn seq(open: &str, items: Vec<Cst>, close: &str, span: Rc<Span>) -> Cst {
+        Cst::Seq { open: open.to_string(), items, close: close.to_string(), span }
+    }
+
+    fn prefixed(prefix: &str, items: Vec<Cst>, span: Rc<Span>) -> Cst {
+        Cst::Prefixed { prefix: prefix.to_string(), items, span }
+    }
+
+    /// Where the node is in the source; trivia has no span of its own.
+    pub fn span(&self) -> Option<&Rc<Span>> {
+        match self {
+            Cst::Trivia(_) => None,
+            Cst::Atom(_, span) | Cst::Seq { span, .. } | Cst::Prefixed { span, .. } => Some(span),
         }
-        Err(err) => {
-            Err(MalError::ParseError(err.map_token(|t| (t.0, t.1.to_string()))))
+    }
+
+    /// Adds the trivia the grammar skipped over, taking it from `src`.
+    fn with_trivia(self, src: &str) -> Cst {
+        match self {
+            Cst::Seq { open, items, close, span } => {
+                let items = with_trivia(src, items, span.start + open.len(), span.end - close.len());
+                Cst::Seq { open, items, close, span }
+            },
+            Cst::Prefixed { prefix, items, span } => {
+                let items = with_trivia(src, items, span.start + prefix.len(), span.end);
+                Cst::Prefixed { prefix, items, span }
+            },
+            cst => cst,
+        }
+    }
+}
+
+/// `items`, which lie between `start` and `end`, with the text around them
+/// added as trivia.
+fn with_trivia(src: &str, items: Vec<Cst>, start: usize, end: usize) -> Vec<Cst> {
+    let mut res = Vec::new();
+    let mut pos = start;
+    for item in items {
+        let (item_start, item_end) = match item.span() {
+            Some(span) => (span.start, span.end),
+            None => (pos, pos),
+        };
+        if item_start > pos {
+            res.push(Cst::Trivia(src[pos..item_start].to_string()));
+        }
+        res.push(item.with_trivia(src));
+        pos = item_end;
+    }
+    if end > pos {
+        res.push(Cst::Trivia(src[pos..end].to_string()));
+    }
+    res
+}
+
+impl fmt::Display for Cst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cst::Trivia(text) | Cst::Atom(text, _) => write!(f, "{}", text),
+            Cst::Seq { open, items, close, .. } => {
+                write!(f, "{}", open)?;
+                for item in items {
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "{}", close)
+            },
+            Cst::Prefixed { prefix, items, .. } => {
+                write!(f, "{}", prefix)?;
+                for item in items {
+                    write!(f, "{}", item)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+fn keyword(name: &str) -> MalForm {
+    MalForm::Key(MalKey::Keyword(name.to_string()), None)
+}
+
+fn string(s: &str) -> MalForm {
+    MalForm::Key(MalKey::String(s.to_string()), None)
+}
+
+impl Cst {
+    /// The node as a hash-map for `read-cst`: its `:type` (`:trivia`, `:atom`,
+    /// `:seq` or `:prefixed`), its `:text`, `:open` and `:close` or `:prefix`,
+    /// its `:items`, and the `:line` and `:col` where it starts.
+    pub fn to_mal(&self) -> MalForm {
+        let items = |items: &[Cst]| MalForm::Vector(items.iter().map(Cst::to_mal).collect(), None, None);
+        let mut map = MalMap::new();
+        match self {
+            Cst::Trivia(text) => {
+                map.insert(keyword("type"), keyword("trivia"));
+                map.insert(keyword("text"), string(text));
+            },
+            Cst::Atom(text, _) => {
+                map.insert(keyword("type"), keyword("atom"));
+                map.insert(keyword("text"), string(text));
+            },
+            Cst::Seq { open, items: xs, close, .. } => {
+                map.insert(keyword("type"), keyword("seq"));
+                map.insert(keyword("open"), string(open));
+                map.insert(keyword("items"), items(xs));
+                map.insert(keyword("close"), string(close));
+            },
+            Cst::Prefixed { prefix, items: xs, .. } => {
+                map.insert(keyword("type"), keyword("prefixed"));
+                map.insert(keyword("prefix"), string(prefix));
+                map.insert(keyword("items"), items(xs));
+            },
+        }
+        if let Some(span) = self.span() {
+            map.insert(keyword("line"), MalForm::Int(span.line as i64, None));
+            map.insert(keyword("col"), MalForm::Int(span.column as i64, None));
+        }
+        MalForm::HashMap(map, None, None)
+    }
+}
+
+/// The source text of a node as given by `read-cst`, or of a list or vector
+/// of them, so that code can edit the tree and write it back out.
+pub fn cst_str(node: &MalForm) -> MalResult<String> {
+    let map = match node {
+        MalForm::HashMap(map, _, _) => map,
+        MalForm::List(xs, _, _) | MalForm::Vector(xs, _, _) => {
+            return xs.iter().map(cst_str).collect();
         },
+        x => return Err(MalError::EvalError(format!("'cst-str': expected a node or a sequence of them, {} was given", x))),
+    };
+    let text = |name: &str| match map.get(&keyword(name)) {
+        Some(MalForm::Key(MalKey::String(s), _)) => Ok(s.clone()),
+        Some(x) => Err(MalError::EvalError(format!("'cst-str': :{} must be a string, {} was given", name, x))),
+        None => Ok(String::new()),
+    };
+    let mut res = text("text")? + &text("open")? + &text("prefix")?;
+    if let Some(items) = map.get(&keyword("items")) {
+        res.push_str(&cst_str(items)?);
+    }
+    res.push_str(&text("close")?);
+    Ok(res)
+}
+
+/// Reads `str` as a lossless syntax tree, keeping comments, whitespace and
+/// reader macros as written. Printing the result gives back `str` exactly.
+pub fn read_cst(str: &str, file: Option<&str>) -> Result<Vec<Cst>, MalError> {
+    let source = Source::new(str, file);
+    let mut errors = Vec::new();
+
+    let res = reader::CstFormsParser::new().parse(&mut errors, &source, str)
+        .map_err(|err| err.map_token(|t| (t.0, t.1.to_string())));
+    let cst = check(res, errors, &source)?;
+    Ok(with_trivia(str, cst, 0, str.len()))
+}
+
+/// `#"..."` reads as a compiled regex, so a bad pattern is a read error.
+fn read_regex(text: &str, span: Rc<Span>, errors: &mut Vec<MalError>) -> MalForm {
+    let pattern = if text.len() > 2 && text.ends_with('"') {
+        text[2..text.len() - 1].replace("\\\"", "\"")
+    } else {
+        text[2..].to_string()
+    };
+    match Regex::new(&pattern) {
+        Ok(re) => MalForm::Regex(Rc::new(re), Some(span)),
+        Err(err) => {
+            errors.push(MalError::EvalError(format!("invalid regex: {}", err)).at(Some(&span)));
+            MalForm::Nil(Some(span))
+        }
+    }
+}
+
+fn read_tagged(tag: &str, form: MalForm, span: Rc<Span>, errors: &mut Vec<MalError>) -> MalForm {
+    let reader = TAG_READERS.with(|readers| readers.borrow().get(tag).cloned());
+    let res = match reader {
+        Some(f) => f(form),
+        None => Err(MalError::EvalError(format!("no reader function for tag {}", tag))),
+    };
+    res.unwrap_or_else(|err| {
+        errors.push(err.at(Some(&span)));
+        MalForm::Nil(None)
+    })
+}
+
+fn is_digits(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+fn parse_int(s: &str) -> Option<BigInt> {
+    let (negative, digits) = match s.chars().next() {
+        Some('-') => (true, &s[1..]),
+        Some('+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    if !is_digits(digits) {
+        return None;
     }
+    let n = BigInt::from_str(digits).ok()?;
+    Some(if negative { -n } else { n })
+}
+
+/// Whether `s` is written as a float: digits with an optional sign, then an
+/// optional `.` with any digits after it and an optional exponent. Unlike
+/// `f64::from_str`, this leaves `inf`, `NaN` and `.5` to be symbols.
+fn is_float(s: &str) -> bool {
+    let s = s.strip_prefix(&['-', '+'][..]).unwrap_or(s);
+    let (mantissa, exponent) = match s.find(&['e', 'E'][..]) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let (int, frac) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+        None => (mantissa, ""),
+    };
+    is_digits(int) && (frac.is_empty() || is_digits(frac))
+        && exponent.map_or(true, |e| is_digits(e.strip_prefix(&['-', '+'][..]).unwrap_or(e)))
+}
+
+/// Integer literals read as exact integers, with an optional `N` suffix, and
+/// `n/d` as exact ratios. Other numbers read as floats.
+fn read_num_or_symbol(s: &str, span: Rc<Span>, errors: &mut Vec<MalError>) -> MalForm {
+    let int = s.strip_suffix('N').unwrap_or(s);
+    if let Some(n) = parse_int(int) {
+        return numeric::from_bigint(n).with_span(span);
+    }
+    if let Some(slash) = s.find('/') {
+        let (n, d) = (&s[..slash], &s[slash + 1..]);
+        if let (Some(n), true) = (parse_int(n), is_digits(d)) {
+            let d = BigInt::from_str(d).unwrap();
+            if d.is_zero() {
+                errors.push(MalError::EvalError(format!("ratio {} has a zero denominator", s)).at(Some(&span)));
+                return MalForm::Nil(Some(span));
+            }
+            return numeric::from_ratio(BigRational::new(n, d)).with_span(span);
+        }
+    }
+    match f64::from_str(s) {
+        Ok(n) if is_float(s) => MalForm::Number(n, Some(span)),
+        _ => MalForm::Symbol(String::from(s), Some(span)),
+    }
+}
+
+fn unbalanced_quote(span: Rc<Span>) -> MalError {
+    MalError::ParseError(ParseError::User { error: UNBALANCED_QUOTE }).at(Some(&span))
+}
+
+/// Fails with every error found while reading, if there were any.
+fn check<T>(res: Result<T, ParseError<usize, (usize, String), &'static str>>,
+            mut errors: Vec<MalError>,
+            source: &Source) -> Result<T, MalError> {
+    match res {
+        Ok(res) if errors.is_empty() => return Ok(res),
+        Ok(_) => (),
+        Err(err) => errors.push(parse_error(err, source)),
+    }
+
+    if errors.len() == 1 {
+        Err(errors.remove(0))
+    } else {
+        Err(MalError::ParseErrors(errors))
+    }
+}
+
+fn parse_error(err: ParseError<usize, (usize, String), &'static str>, source: &Source) -> MalError {
+    let location = match &err {
+        ParseError::InvalidToken { location } => Some(*location),
+        ParseError::UnrecognizedToken { token: Some((l, _, _)), .. } => Some(*l),
+        ParseError::UnrecognizedToken { token: None, .. } => Some(source.len()),
+        ParseError::ExtraToken { token: (l, _, _) } => Some(*l),
+        ParseError::User { .. } => None,
+    };
+
+    MalError::ParseError(err).at(location.map(|l| source.span(l, l)).as_ref())
 }
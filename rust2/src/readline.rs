@@ -1,22 +1,85 @@
 use rustyline::Editor;
+use rustyline::error::ReadlineError;
 
 pub struct Reader {
     rl: Editor<()>,
+    history_file: String,
 }
 
 impl Reader {
     pub fn new(history_file: &str) -> Reader {
         let mut result = Reader {
             rl: Editor::<()>::new(),
+            history_file: history_file.to_string(),
         };
         let _ = result.rl.load_history(history_file);
         result
     }
 
     pub fn readline(&mut self, prompt: &str) -> rustyline::Result<String> {
+        self.read_entry(prompt, prompt, |_| true)
+    }
+
+    /// Reads lines, prompting with `more_prompt` after the first, until
+    /// `complete` accepts everything read so far. The whole entry becomes a
+    /// single history item. Ctrl-C after the first line drops the entry and
+    /// starts over at `prompt`.
+    pub fn read_entry<F>(&mut self, prompt: &str, more_prompt: &str, complete: F) -> rustyline::Result<String>
+        where F: Fn(&str) -> bool
+    {
+        'entry: loop {
+            let mut lines = vec![self.read_trimmed(prompt)?];
+            while !complete(&lines.join("\n")) {
+                match self.read_trimmed(more_prompt) {
+                    Ok(line) => lines.push(line),
+                    Err(ReadlineError::Interrupted) => continue 'entry,
+                    Err(error) => return Err(error),
+                }
+            }
+            return self.add_entry(&lines);
+        }
+    }
+
+    // the history file holds one entry per line, so a multi-line entry is
+    // recalled as a single line
+    fn add_entry(&mut self, lines: &[String]) -> rustyline::Result<String> {
+        self.add_history(&lines.join(" "))?;
+        Ok(lines.join("\n"))
+    }
+
+    // input that isn't a terminal keeps its line ending
+    fn read_trimmed(&mut self, prompt: &str) -> rustyline::Result<String> {
         let input = self.rl.readline(prompt)?;
-        self.rl.add_history_entry(input.as_ref());
-        self.rl.save_history("mal_history.txt")?;
-        Ok(input)
+        Ok(input.trim_end_matches(&['\n', '\r'][..]).to_string())
+    }
+
+    fn add_history(&mut self, entry: &str) -> rustyline::Result<()> {
+        self.rl.add_history_entry(entry);
+        self.rl.save_history(&self.history_file)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reader;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn multi_line_entry_survives_reload() {
+        let path = env::temp_dir().join(format!("mal-history-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut reader = Reader::new(path);
+        let lines = vec!["(+ 1".to_string(), "   2)".to_string()];
+        assert_eq!(reader.add_entry(&lines).unwrap(), "(+ 1\n   2)");
+
+        let reloaded = Reader::new(path);
+        let history = reloaded.rl.history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.get(0).map(String::as_str), Some("(+ 1    2)"));
+        let _ = fs::remove_file(path);
     }
 }
@@ -0,0 +1,362 @@
+//! Compact binary encoding of mal values for `serialize` and `deserialize`:
+//! a tag byte per value, with LEB128 lengths and zigzag integers. Atoms keep
+//! their identity, so an atom reached twice is read back as one atom and
+//! cycles through atoms survive.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::Zero;
+use regex::Regex;
+
+use crate::numeric;
+use crate::types::{MalError, MalForm, MalKey, MalMap, MalResult, MalSet};
+
+const MAGIC: &[u8] = b"mal\x01";
+
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+/// A zigzag-encoded integer.
+const INT: u8 = 3;
+const STRING: u8 = 4;
+const KEYWORD: u8 = 5;
+const SYMBOL: u8 = 6;
+const LIST: u8 = 7;
+const VECTOR: u8 = 8;
+const MAP: u8 = 9;
+/// An atom, followed by its value.
+const ATOM: u8 = 10;
+/// The id of an atom written earlier.
+const REF: u8 = 11;
+/// Metadata, followed by the value it belongs to.
+const META: u8 = 12;
+/// A float, as 8 little-endian bytes.
+const FLOAT: u8 = 13;
+/// A length and that many bytes of two's complement, little-endian.
+const BIGINT: u8 = 14;
+/// Two bigints without their tags, the numerator and the denominator.
+const RATIO: u8 = 15;
+/// A length and that many elements.
+const SET: u8 = 16;
+/// The text of a pattern, compiled again when read.
+const REGEX: u8 = 17;
+
+/// Values nested deeper than this are refused rather than encoded or
+/// decoded recursively until the stack runs out.
+const MAX_DEPTH: usize = 512;
+
+struct Encoder {
+    out: Vec<u8>,
+    /// Ids of the atoms written so far, by address. An atom is numbered
+    /// before its value is written, so a cycle back to it is a reference.
+    atoms: HashMap<*const RefCell<MalForm>, usize>,
+    depth: usize,
+}
+
+impl Encoder {
+    fn uint(&mut self, mut n: u64) {
+        loop {
+            let b = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                self.out.push(b);
+                return;
+            }
+            self.out.push(b | 0x80);
+        }
+    }
+
+    fn bytes(&mut self, tag: u8, s: &str) {
+        self.out.push(tag);
+        self.uint(s.len() as u64);
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn bigint(&mut self, b: &BigInt) {
+        let bytes = b.to_signed_bytes_le();
+        self.uint(bytes.len() as u64);
+        self.out.extend_from_slice(&bytes);
+    }
+
+    fn value(&mut self, x: &MalForm) -> MalResult<()> {
+        if self.depth >= MAX_DEPTH {
+            return Err(MalError::EvalError("'serialize': too deeply nested".to_string()));
+        }
+        self.depth += 1;
+        let res = self.tagged(x);
+        self.depth -= 1;
+        res
+    }
+
+    fn tagged(&mut self, x: &MalForm) -> MalResult<()> {
+        match x {
+            MalForm::List(_, _, Some(meta)) | MalForm::Vector(_, _, Some(meta)) |
+            MalForm::HashMap(_, _, Some(meta)) | MalForm::Set(_, _, Some(meta)) => {
+                self.out.push(META);
+                self.value(meta)?;
+            }
+            _ => (),
+        }
+        match x {
+            MalForm::Nil(_) => self.out.push(NIL),
+            MalForm::Bool(false, _) => self.out.push(FALSE),
+            MalForm::Bool(true, _) => self.out.push(TRUE),
+            MalForm::Int(i, _) => {
+                self.out.push(INT);
+                self.uint(((i << 1) ^ (i >> 63)) as u64);
+            }
+            MalForm::Bignum(b, _) => {
+                self.out.push(BIGINT);
+                self.bigint(b);
+            }
+            MalForm::Ratio(r, _) => {
+                self.out.push(RATIO);
+                self.bigint(r.numer());
+                self.bigint(r.denom());
+            }
+            MalForm::Number(n, _) => {
+                self.out.push(FLOAT);
+                self.out.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            MalForm::Key(MalKey::String(s), _) => self.bytes(STRING, s),
+            MalForm::Key(MalKey::Keyword(s), _) => self.bytes(KEYWORD, s),
+            MalForm::Symbol(s, _) => self.bytes(SYMBOL, s),
+            MalForm::Regex(re, _) => self.bytes(REGEX, re.as_str()),
+            MalForm::List(xs, _, _) | MalForm::Vector(xs, _, _) => {
+                self.out.push(if let MalForm::List(..) = x { LIST } else { VECTOR });
+                self.uint(xs.len() as u64);
+                for x in xs {
+                    self.value(x)?;
+                }
+            }
+            MalForm::HashMap(xs, _, _) => {
+                self.out.push(MAP);
+                self.uint(xs.len() as u64);
+                for (k, v) in xs {
+                    self.value(k)?;
+                    self.value(v)?;
+                }
+            }
+            MalForm::Set(xs, _, _) => {
+                self.out.push(SET);
+                self.uint(xs.len() as u64);
+                for x in xs {
+                    self.value(x)?;
+                }
+            }
+            MalForm::Atom(a) => {
+                if let Some(&id) = self.atoms.get(&Rc::as_ptr(a)) {
+                    self.out.push(REF);
+                    self.uint(id as u64);
+                    return Ok(());
+                }
+                self.out.push(ATOM);
+                let id = self.atoms.len();
+                self.atoms.insert(Rc::as_ptr(a), id);
+                self.value(&a.borrow())?;
+            }
+            MalForm::MalFn(_) => {
+                return Err(MalError::EvalError(format!("'serialize': cannot serialize closure {}", x)));
+            }
+            MalForm::NativeFn(..) => {
+                return Err(MalError::EvalError(format!("'serialize': cannot serialize function {}", x)));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn encode(x: &MalForm) -> MalResult<Vec<u8>> {
+    let mut enc = Encoder { out: MAGIC.to_vec(), atoms: HashMap::new(), depth: 0 };
+    enc.value(x)?;
+    Ok(enc.out)
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Atoms by id, numbered as the encoder numbers them.
+    atoms: Vec<Rc<RefCell<MalForm>>>,
+    /// How many values the one being read is inside of.
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn error<T>(&self, msg: &str) -> MalResult<T> {
+        Err(MalError::EvalError(format!("'deserialize': {} at byte {}", msg, self.pos)))
+    }
+
+    fn byte(&mut self) -> MalResult<u8> {
+        match self.data.get(self.pos) {
+            Some(&b) => {
+                self.pos += 1;
+                Ok(b)
+            }
+            None => self.error("unexpected end of data"),
+        }
+    }
+
+    fn uint(&mut self) -> MalResult<u64> {
+        let mut n: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift > 63 {
+                return self.error("integer too long");
+            }
+            n |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+            shift += 7;
+        }
+    }
+
+    fn len(&mut self) -> MalResult<usize> {
+        let n = self.uint()?;
+        if n > (self.data.len() - self.pos) as u64 {
+            return self.error("length past end of data");
+        }
+        Ok(n as usize)
+    }
+
+    fn string(&mut self) -> MalResult<String> {
+        let n = self.len()?;
+        match String::from_utf8(self.data[self.pos..self.pos + n].to_vec()) {
+            Ok(s) => {
+                self.pos += n;
+                Ok(s)
+            }
+            Err(_) => self.error("invalid UTF-8 in string"),
+        }
+    }
+
+    fn bigint(&mut self) -> MalResult<BigInt> {
+        let n = self.len()?;
+        let b = BigInt::from_signed_bytes_le(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn values(&mut self) -> MalResult<Vec<MalForm>> {
+        let n = self.len()?;
+        let mut items = Vec::with_capacity(n);
+        for _ in 0..n {
+            items.push(self.value()?);
+        }
+        Ok(items)
+    }
+
+    fn value(&mut self) -> MalResult<MalForm> {
+        if self.depth >= MAX_DEPTH {
+            return self.error("too deeply nested");
+        }
+        self.depth += 1;
+        let res = self.tagged();
+        self.depth -= 1;
+        res
+    }
+
+    fn tagged(&mut self) -> MalResult<MalForm> {
+        let tag = self.byte()?;
+        Ok(match tag {
+            NIL => MalForm::Nil(None),
+            FALSE => MalForm::Bool(false, None),
+            TRUE => MalForm::Bool(true, None),
+            INT => {
+                let n = self.uint()?;
+                MalForm::Int(((n >> 1) as i64) ^ -((n & 1) as i64), None)
+            }
+            BIGINT => numeric::from_bigint(self.bigint()?),
+            RATIO => {
+                let n = self.bigint()?;
+                let d = self.bigint()?;
+                if d.is_zero() {
+                    return self.error("ratio with a zero denominator");
+                }
+                numeric::from_ratio(BigRational::new(n, d))
+            }
+            FLOAT => {
+                if self.data.len() - self.pos < 8 {
+                    return self.error("unexpected end of data");
+                }
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&self.data[self.pos..self.pos + 8]);
+                self.pos += 8;
+                MalForm::Number(f64::from_bits(u64::from_le_bytes(bytes)), None)
+            }
+            STRING => MalForm::Key(MalKey::String(self.string()?), None),
+            KEYWORD => MalForm::Key(MalKey::Keyword(self.string()?), None),
+            SYMBOL => MalForm::Symbol(self.string()?, None),
+            REGEX => match Regex::new(&self.string()?) {
+                Ok(re) => MalForm::Regex(Rc::new(re), None),
+                Err(_) => return self.error("invalid regex"),
+            },
+            LIST => MalForm::List(self.values()?, None, None),
+            VECTOR => MalForm::Vector(self.values()?, None, None),
+            MAP => {
+                let n = self.len()?;
+                let mut res = MalMap::new();
+                for _ in 0..n {
+                    let k = self.value()?;
+                    if !k.is_hashable() {
+                        return self.error("map key is an atom or function");
+                    }
+                    let v = self.value()?;
+                    res.insert(k, v);
+                }
+                MalForm::HashMap(res, None, None)
+            }
+            SET => {
+                let n = self.len()?;
+                let mut res = MalSet::new();
+                for _ in 0..n {
+                    let x = self.value()?;
+                    if !x.is_hashable() {
+                        return self.error("set element is an atom or function");
+                    }
+                    res.insert(x);
+                }
+                MalForm::Set(res, None, None)
+            }
+            ATOM => {
+                let a = Rc::new(RefCell::new(MalForm::Nil(None)));
+                self.atoms.push(a.clone());
+                let v = self.value()?;
+                *a.borrow_mut() = v;
+                MalForm::Atom(a)
+            }
+            REF => {
+                let id = self.uint()?;
+                match self.atoms.get(id as usize) {
+                    Some(a) => MalForm::Atom(a.clone()),
+                    None => return self.error(&format!("reference to unknown atom {}", id)),
+                }
+            }
+            META => {
+                let meta = self.value()?;
+                match self.value()?.with_meta(meta) {
+                    Ok(x) => x,
+                    Err(_) => return self.error("metadata on a value that cannot have it"),
+                }
+            }
+            _ => return self.error(&format!("unknown tag {}", tag)),
+        })
+    }
+}
+
+pub fn decode(data: &[u8]) -> MalResult<MalForm> {
+    if !data.starts_with(MAGIC) {
+        return Err(MalError::EvalError("'deserialize': not serialized mal data".to_string()));
+    }
+    let mut dec = Decoder { data, pos: MAGIC.len(), atoms: vec![], depth: 0 };
+    let res = dec.value()?;
+    if dec.pos != data.len() {
+        return dec.error("trailing data");
+    }
+    Ok(res)
+}
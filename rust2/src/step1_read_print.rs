@@ -1,5 +1,8 @@
 mod readline;
 mod types;
+#[allow(dead_code)]
+mod numeric;
+#[allow(dead_code)]
 mod reader;
 mod utils;
 mod printer;
@@ -5,23 +5,29 @@ use std::clone::Clone;
 
 mod readline;
 mod types;
+#[allow(dead_code)]
+mod numeric;
+#[allow(dead_code)]
 mod reader;
 mod utils;
 mod printer;
 
 use rustyline::error::ReadlineError;
-use types::{MalForm,MalError,MalNativeFn,MalResult,Env as NewEnv};
+use types::{MalForm,MalMap,MalSet,MalError,MalNativeFn,MalResult,Env as NewEnv};
 
 const PROMPT: &str = "user> ";
 const HISTORY_FILE: &str = "mal_history.txt";
 
-fn binary_fn(name: &'static str, f: fn(f64, f64) -> f64) -> MalForm {
+fn binary_fn(name: &'static str, int: fn(i64, i64) -> Option<i64>, float: fn(f64, f64) -> f64) -> MalForm {
     MalForm::NativeFn(name.to_string(), MalNativeFn(Rc::new(move |vec: Vec<MalForm>, _| {
         match vec.as_slice() {
-            [MalForm::Number(ref a), MalForm::Number(ref b)] => Ok(MalForm::Number(f(*a, *b))),
+            [MalForm::Int(a, _), MalForm::Int(b, _)] => int(*a, *b)
+                .map(|n| MalForm::Int(n, None))
+                .ok_or_else(|| MalError::EvalError(format!("'{}': integer overflow or division by zero", name))),
+            [MalForm::Number(ref a, _), MalForm::Number(ref b, _)] => Ok(MalForm::Number(float(*a, *b), None)),
             _ => Err(MalError::EvalError(format!("'{}': wrong arguments", name))),
         }
-    })))
+    })), None)
 }
 
 type Env = HashMap<String, MalForm>;
@@ -30,10 +36,10 @@ fn main() {
     let mut editor = readline::Reader::new(HISTORY_FILE);
 
     let mut repl_env = Env::new();
-    repl_env.insert("+".to_string(), binary_fn("+", |a,b| a + b));
-    repl_env.insert("-".to_string(), binary_fn("-", |a,b| a - b));
-    repl_env.insert("*".to_string(), binary_fn("*", |a,b| a * b));
-    repl_env.insert("/".to_string(), binary_fn("/", |a,b| a / b));
+    repl_env.insert("+".to_string(), binary_fn("+", i64::checked_add, |a,b| a + b));
+    repl_env.insert("-".to_string(), binary_fn("-", i64::checked_sub, |a,b| a - b));
+    repl_env.insert("*".to_string(), binary_fn("*", i64::checked_mul, |a,b| a * b));
+    repl_env.insert("/".to_string(), binary_fn("/", i64::checked_div, |a,b| a / b));
 
     loop {
         match editor.readline(PROMPT) {
@@ -60,35 +66,39 @@ fn read(str: &String) -> MalResult<MalForm> {
 
 fn eval_ast(ast: &MalForm, env: &mut Env) -> MalResult<MalForm> {
     Ok(match ast {
-        MalForm::Symbol(ref sym) => match env.get(sym) {
+        MalForm::Symbol(ref sym, _) => match env.get(sym) {
             Some(val) => val.clone(),
             None => return Err(MalError::EvalError(format!("'{}' not found", sym))),
         },
-        MalForm::List(ref list) => {
+        MalForm::List(ref list, _, _) => {
             let res: Result<Vec<_>, _> = list.into_iter().map(|x| eval(x, env)).collect();
-            MalForm::List(res?)
+            MalForm::List(res?, None, None)
         },
-        MalForm::Vector(ref list) => {
+        MalForm::Vector(ref list, _, _) => {
             let res: Result<Vec<_>, _> = list.into_iter().map(|x| eval(x, env)).collect();
-            MalForm::Vector(res?)
+            MalForm::Vector(res?, None, None)
         },
-        MalForm::HashMap(ref hash) => {
-            let res: MalResult<HashMap<_, _>> = hash.into_iter().map(|(k, v)| Ok((k.clone(), eval(v, env)?))).collect();
-            MalForm::HashMap(res?)
+        MalForm::HashMap(ref hash, _, _) => {
+            let res: MalResult<MalMap> = hash.into_iter().map(|(k, v)| Ok((eval(k, env)?.to_key("hash-map")?, eval(v, env)?))).collect();
+            MalForm::HashMap(res?, None, None)
+        },
+        MalForm::Set(ref set, _, _) => {
+            let res: MalResult<MalSet> = set.into_iter().map(|x| eval(x, env)?.to_set_element("hash-set")).collect();
+            MalForm::Set(res?, None, None)
         },
         x => x.clone(),
     })
 }
 
 fn eval(ast: &MalForm, env: &mut Env) -> MalResult<MalForm> {
-    Ok(if let MalForm::List(xs) = ast {
+    Ok(if let MalForm::List(xs, _, _) = ast {
         if xs.is_empty() {
             ast.clone()
-        } else if let MalForm::List(v) = eval_ast(ast, env)? {
+        } else if let MalForm::List(v, _, _) = eval_ast(ast, env)? {
             let f_ast = &v.as_slice()[0];
             let args = &v.as_slice()[1 ..];
             match f_ast {
-                MalForm::NativeFn(_, MalNativeFn(f)) => f(args.to_vec(), &Rc::new(RefCell::new(NewEnv::new(None))))?,
+                MalForm::NativeFn(_, MalNativeFn(f), _) => f(args.to_vec(), &Rc::new(RefCell::new(NewEnv::new(None))))?,
                 _ => return Err(MalError::EvalError(format!("'{}' is not a function", f_ast))),
             }
         } else {
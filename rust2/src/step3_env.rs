@@ -1,39 +1,44 @@
-use std::collections::HashMap;
 use std::rc::Rc;
 use std::clone::Clone;
 use std::cell::RefCell;
 
 mod readline;
 mod types;
+#[allow(dead_code)]
+mod numeric;
+#[allow(dead_code)]
 mod reader;
 mod utils;
 mod env;
 mod printer;
 
 use rustyline::error::ReadlineError;
-use types::{MalForm,MalError,MalNativeFn,MalResult};
+use types::{MalForm,MalMap,MalSet,MalError,MalNativeFn,MalResult};
 use env::Env;
 
 const PROMPT: &str = "user> ";
 const HISTORY_FILE: &str = "mal_history.txt";
 
-fn binary_fn(name: &'static str, f: fn(f64, f64) -> f64) -> MalForm {
+fn binary_fn(name: &'static str, int: fn(i64, i64) -> Option<i64>, float: fn(f64, f64) -> f64) -> MalForm {
     MalForm::NativeFn(name.to_string(), MalNativeFn(Rc::new(move |vec: Vec<MalForm>, _| {
         match vec.as_slice() {
-            [MalForm::Number(ref a), MalForm::Number(ref b)] => Ok(MalForm::Number(f(*a, *b))),
+            [MalForm::Int(a, _), MalForm::Int(b, _)] => int(*a, *b)
+                .map(|n| MalForm::Int(n, None))
+                .ok_or_else(|| MalError::EvalError(format!("'{}': integer overflow or division by zero", name))),
+            [MalForm::Number(ref a, _), MalForm::Number(ref b, _)] => Ok(MalForm::Number(float(*a, *b), None)),
             _ => Err(MalError::EvalError(format!("'{}': wrong arguments", name))),
         }
-    })))
+    })), None)
 }
 
 fn main() {
     let mut editor = readline::Reader::new(HISTORY_FILE);
 
     let mut repl_env = Env::new(None);
-    repl_env.set("+".to_string(), binary_fn("+", |a,b| a + b));
-    repl_env.set("-".to_string(), binary_fn("-", |a,b| a - b));
-    repl_env.set("*".to_string(), binary_fn("*", |a,b| a * b));
-    repl_env.set("/".to_string(), binary_fn("/", |a,b| a / b));
+    repl_env.set("+".to_string(), binary_fn("+", i64::checked_add, |a,b| a + b));
+    repl_env.set("-".to_string(), binary_fn("-", i64::checked_sub, |a,b| a - b));
+    repl_env.set("*".to_string(), binary_fn("*", i64::checked_mul, |a,b| a * b));
+    repl_env.set("/".to_string(), binary_fn("/", i64::checked_div, |a,b| a / b));
 
     let repl_env = Rc::new(RefCell::new(repl_env));
 
@@ -62,18 +67,22 @@ fn read(str: &String) -> MalResult<MalForm> {
 
 fn eval_ast(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match ast {
-        MalForm::Symbol(ref sym) => env.borrow().get(&sym)?.clone(),
-        MalForm::List(ref list) => {
+        MalForm::Symbol(ref sym, _) => env.borrow().get(&sym)?.clone(),
+        MalForm::List(ref list, _, _) => {
             let res: Result<Vec<_>, _> = list.into_iter().map(|x| eval(x, env)).collect();
-            MalForm::List(res?)
+            MalForm::List(res?, None, None)
         },
-        MalForm::Vector(ref list) => {
+        MalForm::Vector(ref list, _, _) => {
             let res: Result<Vec<_>, _> = list.into_iter().map(|x| eval(x, env)).collect();
-            MalForm::Vector(res?)
+            MalForm::Vector(res?, None, None)
         },
-        MalForm::HashMap(ref hash) => {
-            let res: MalResult<HashMap<_, _>> = hash.into_iter().map(|(k, v)| Ok((k.clone(), eval(v, env)?))).collect();
-            MalForm::HashMap(res?)
+        MalForm::HashMap(ref hash, _, _) => {
+            let res: MalResult<MalMap> = hash.into_iter().map(|(k, v)| Ok((eval(k, env)?.to_key("hash-map")?, eval(v, env)?))).collect();
+            MalForm::HashMap(res?, None, None)
+        },
+        MalForm::Set(ref set, _, _) => {
+            let res: MalResult<MalSet> = set.into_iter().map(|x| eval(x, env)?.to_set_element("hash-set")).collect();
+            MalForm::Set(res?, None, None)
         },
         x => x.clone(),
     })
@@ -81,21 +90,21 @@ fn eval_ast(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
 fn process_bindings(bindings_ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<()> {
     let vec = match bindings_ast {
-        MalForm::List(v) => v,
-        MalForm::Vector(v) => v,
+        MalForm::List(v, _, _) => v,
+        MalForm::Vector(v, _, _) => v,
         _ => return Err(MalError::EvalError(format!("'let*': bindings list must be either a list or vector, {} was given", bindings_ast))),
     };
 
     let mut b = vec.into_iter();
 
     while let Some(key_ast) = b.next() {
-        if let MalForm::Symbol(ref key) = key_ast {
+        if let MalForm::Symbol(ref key, _) = key_ast {
             let val_ast = b.next().ok_or(MalError::EvalError(format!("'let*': mising value for {}", key)))?;
             let val = eval(val_ast, env)?;
 
             env.borrow_mut().set(key.clone(), val.clone());
         } else {
-            return Err(MalError::EvalError(format!("'let*': binding name must be a symbol, {} was given", key_ast)));
+            return Err(MalError::EvalError(format!("'let*': binding name must be a symbol, {} was given", key_ast)).at(key_ast.span()));
         }
     }
 
@@ -104,7 +113,7 @@ fn process_bindings(bindings_ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult
 
 fn eval_def_(args: &[MalForm], env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     match args {
-        [MalForm::Symbol(name), val_ast] => {
+        [MalForm::Symbol(name, _), val_ast] => {
             let val = eval(val_ast, env)?;
             env.borrow_mut().set(name.clone(), val.clone());
             Ok(val)
@@ -126,9 +135,9 @@ fn eval_let_(args: &[MalForm], env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 }
 
 fn eval_fn(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
-    if let MalForm::List(xs) = eval_ast(ast, env)? {
+    if let MalForm::List(xs, _, _) = eval_ast(ast, env)? {
         match &xs[0] {
-            MalForm::NativeFn(_, MalNativeFn(f)) => {
+            MalForm::NativeFn(_, MalNativeFn(f), _) => {
                 let args = &xs[1 ..];
                 f(args.to_vec(), env)
             },
@@ -140,14 +149,14 @@ fn eval_fn(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 }
 
 fn eval(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
-    Ok(if let MalForm::List(xs) = ast {
+    Ok(if let MalForm::List(xs, _, _) = ast {
         if xs.is_empty() {
             ast.clone()
         } else {
             let s = xs.as_slice();
             match &s[0] {
-                MalForm::Symbol(sym) if sym == "def!" => eval_def_(&s[1..], env)?,
-                MalForm::Symbol(sym) if sym == "let*" => eval_let_(&s[1..], env)?,
+                MalForm::Symbol(sym, _) if sym == "def!" => eval_def_(&s[1..], env)?,
+                MalForm::Symbol(sym, _) if sym == "let*" => eval_let_(&s[1..], env)?,
                 _ => eval_fn(ast, env)?,
             }
         }
@@ -1,18 +1,21 @@
-use std::collections::HashMap;
 use std::rc::Rc;
 use std::clone::Clone;
 use std::cell::RefCell;
 
 mod readline;
 mod types;
+mod numeric;
+#[allow(dead_code)]
 mod reader;
 mod utils;
 mod env;
 mod core;
+mod json;
+mod serialize;
 mod printer;
 
 use rustyline::error::ReadlineError;
-use types::{MalForm,MalError,MalNativeFn,MalFn,MalResult};
+use types::{MalForm,MalMap,MalSet,MalError,MalNativeFn,MalFn,MalResult};
 use env::Env;
 
 const PROMPT: &str = "user> ";
@@ -54,18 +57,22 @@ fn read(str: &str) -> MalResult<MalForm> {
 
 fn eval_ast(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match ast {
-        MalForm::Symbol(ref sym) => env.borrow().get(&sym)?.clone(),
-        MalForm::List(ref list) => {
+        MalForm::Symbol(ref sym, _) => env.borrow().get(&sym)?.clone(),
+        MalForm::List(ref list, _, _) => {
             let res: Result<Vec<_>, _> = list.into_iter().map(|x| eval(x, env)).collect();
-            MalForm::List(res?)
+            MalForm::List(res?, None, None)
         },
-        MalForm::Vector(ref list) => {
+        MalForm::Vector(ref list, _, _) => {
             let res: Result<Vec<_>, _> = list.into_iter().map(|x| eval(x, env)).collect();
-            MalForm::Vector(res?)
+            MalForm::Vector(res?, None, None)
         },
-        MalForm::HashMap(ref hash) => {
-            let res: MalResult<HashMap<_, _>> = hash.into_iter().map(|(k, v)| Ok((k.clone(), eval(v, env)?))).collect();
-            MalForm::HashMap(res?)
+        MalForm::HashMap(ref hash, _, _) => {
+            let res: MalResult<MalMap> = hash.into_iter().map(|(k, v)| Ok((eval(k, env)?.to_key("hash-map")?, eval(v, env)?))).collect();
+            MalForm::HashMap(res?, None, None)
+        },
+        MalForm::Set(ref set, _, _) => {
+            let res: MalResult<MalSet> = set.into_iter().map(|x| eval(x, env)?.to_set_element("hash-set")).collect();
+            MalForm::Set(res?, None, None)
         },
         x => x.clone(),
     })
@@ -73,21 +80,21 @@ fn eval_ast(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
 fn process_bindings(bindings_ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<()> {
     let vec = match bindings_ast {
-        MalForm::List(v) => v,
-        MalForm::Vector(v) => v,
+        MalForm::List(v, _, _) => v,
+        MalForm::Vector(v, _, _) => v,
         _ => return Err(MalError::EvalError(format!("'let*': bindings list must be either a list or vector, {} was given", bindings_ast))),
     };
 
     let mut b = vec.into_iter();
 
     while let Some(key_ast) = b.next() {
-        if let MalForm::Symbol(ref key) = key_ast {
+        if let MalForm::Symbol(ref key, _) = key_ast {
             let val_ast = b.next().ok_or(MalError::EvalError(format!("'let*': mising value for {}", key)))?;
             let val = eval(val_ast, env)?;
 
             env.borrow_mut().set(key.clone(), val.clone());
         } else {
-            return Err(MalError::EvalError(format!("'let*': binding name must be a symbol, {} was given", key_ast)));
+            return Err(MalError::EvalError(format!("'let*': binding name must be a symbol, {} was given", key_ast)).at(key_ast.span()));
         }
     }
 
@@ -96,7 +103,7 @@ fn process_bindings(bindings_ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult
 
 fn eval_def_(args: &[MalForm], env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     match args {
-        [MalForm::Symbol(name), val_ast] => {
+        [MalForm::Symbol(name, _), val_ast] => {
             let val = eval(val_ast, env)?;
             env.borrow_mut().set(name.clone(), val.clone());
             Ok(val)
@@ -108,14 +115,14 @@ fn eval_def_(args: &[MalForm], env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
 fn get_binds(form: &MalForm) -> MalResult<Vec<String>> {
     let v = match form {
-        MalForm::List(x) => x,
-        MalForm::Vector(x) => x,
+        MalForm::List(x, _, _) => x,
+        MalForm::Vector(x, _, _) => x,
         _ => return Err(MalError::EvalError(format!("'fn*' bindings list must be a list or vector, {} given", form))),
     };
 
     let res: MalResult<Vec<_>> = v.iter().map(|x| match x {
-        MalForm::Symbol(name) => Ok(name.clone()),
-        _ => Err(MalError::EvalError(format!("'fn*' bindings must be symbols, {} given", x))),
+        MalForm::Symbol(name, _) => Ok(name.clone()),
+        _ => Err(MalError::EvalError(format!("'fn*' bindings must be symbols, {} given", x)).at(x.span())),
     }).collect();
 
     res
@@ -135,14 +142,14 @@ fn eval(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     let mut env = env.clone();
 
     loop {
-        if let MalForm::List(xs) = &ast {
+        if let MalForm::List(xs, _, _) = &ast {
             if xs.is_empty() {
                 return Ok(ast)
             } else {
                 let s = xs.as_slice();
                 match &s[0] {
-                    MalForm::Symbol(sym) if sym == "def!" => return eval_def_(&s[1..], &env),
-                    MalForm::Symbol(sym) if sym == "let*" =>
+                    MalForm::Symbol(sym, _) if sym == "def!" => return eval_def_(&s[1..], &env),
+                    MalForm::Symbol(sym, _) if sym == "let*" =>
                         match &s[1..] {
                             [bindings_ast, value_ast] => {
                                 let new_env = Rc::new(RefCell::new(Env::new(Some(env.clone()))));
@@ -154,7 +161,7 @@ fn eval(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
                             },
                             _ => return Err(MalError::EvalError("'let*' requires at least 2 arguments".to_string())),
                         },
-                    MalForm::Symbol(sym) if sym == "do" => {
+                    MalForm::Symbol(sym, _) if sym == "do" => {
                         for arg in &s[1 .. s.len()-1] {
                             let _ = eval(&arg, &env)?;
                         }
@@ -162,22 +169,22 @@ fn eval(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
                         ast = s[s.len() - 1].clone();
                         // tco
                     },
-                    MalForm::Symbol(sym) if sym == "if" => {
+                    MalForm::Symbol(sym, _) if sym == "if" => {
                         let cond_ast = s.get(1).ok_or(MalError::EvalError(format!("Missing condition for 'if'")))?;
                         let cond = eval(cond_ast, &env)?;
 
                         let i = match cond {
-                            MalForm::Bool(false) | MalForm::Nil => 3,
+                            MalForm::Bool(false, _) | MalForm::Nil(_) => 3,
                             _ => 2,
                         };
 
-                        ast = s.get(i).unwrap_or(&MalForm::Nil).clone();
+                        ast = s.get(i).unwrap_or(&MalForm::Nil(None)).clone();
                         // tco
                     },
-                    MalForm::Symbol(sym) if sym == "fn*" => return eval_fn_(&s[1..], &env),
-                    _ => if let MalForm::List(xs) = eval_ast(&ast, &env)? {
+                    MalForm::Symbol(sym, _) if sym == "fn*" => return eval_fn_(&s[1..], &env),
+                    _ => if let MalForm::List(xs, _, _) = eval_ast(&ast, &env)? {
                         match &xs[0] {
-                            MalForm::NativeFn(_, MalNativeFn(f)) => {
+                            MalForm::NativeFn(_, MalNativeFn(f), _) => {
                                 let args = &xs[1 ..];
                                 return f(args.to_vec(), &env);
                             },
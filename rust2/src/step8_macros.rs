@@ -1,18 +1,22 @@
-use std::collections::HashMap;
 use std::rc::Rc;
 use std::clone::Clone;
 use std::cell::RefCell;
+use std::fs;
 
 mod readline;
 mod types;
+mod numeric;
+#[allow(dead_code)]
 mod reader;
 mod utils;
 mod env;
 mod core;
+mod json;
+mod serialize;
 mod printer;
 
 use rustyline::error::ReadlineError;
-use types::{MalForm,MalError,MalNativeFn,MalFn,MalResult,ToMalForm};
+use types::{MalForm,MalMap,MalSet,MalKey,MalError,MalNativeFn,MalFn,MalResult,ToMalForm};
 use env::Env;
 
 const PROMPT: &str = "user> ";
@@ -50,7 +54,7 @@ fn main() {
                 }
 
                 // Not sure if that should be repl env or calling env
-                let res = eval(&MalForm::List(f_args), &repl_env_clone)?;
+                let res = eval(&MalForm::List(f_args, None, None), &repl_env_clone)?;
 
                 *atom.borrow_mut() = res.clone();
 
@@ -61,12 +65,33 @@ fn main() {
         }));
     }
 
+    {
+        let repl_env_clone = repl_env.clone(); // to be moved into load-file
+        repl_env.borrow_mut().set("load-file".to_string(), core::native_fn("load-file", move |args, _| {
+            match args.first() {
+                Some(MalForm::Key(MalKey::String(ref file), _)) => {
+                    let contents = fs::read_to_string(file)
+                        .map_err(|err| MalError::EvalError(format!("'load-file': {}: {}", file, err)))?;
+                    let mut res = MalForm::Nil(None);
+                    let mut forms = reader::parse_all(&contents, Some(file))?.into_iter();
+                    while let Some((form, span)) = forms.next() {
+                        res = reader::expand_reader_macros(form, &mut forms.by_ref().map(|(form, _)| form))
+                            .and_then(|form| eval(&form, &repl_env_clone))
+                            .map_err(|err| err.at(Some(&span)))?;
+                    }
+                    Ok(res)
+                },
+                Some(x) => Err(MalError::EvalError(format!("'load-file': argument must be a string, {} was given", x))),
+                _ => Err(MalError::EvalError("'load-file': argument required".to_string())),
+            }
+        }));
+    }
+
     repl_env.borrow_mut().set(
         "*ARGV*".to_string(),
-        MalForm::List(std::env::args().skip(2).map(|x| x.to_mal_form()).collect::<Vec<MalForm>>()));
+        MalForm::List(std::env::args().skip(2).map(|x| x.to_mal_form()).collect::<Vec<MalForm>>(), None, None));
 
     let _ = rep(r#"(def! not (fn* (a) (if a false true)))"#, &repl_env);
-    let _ = rep(r#"(def! load-file (fn* (f) (eval (read-string (str "(do " (slurp f) ")")))))"#, &repl_env);
     let _ = rep(r#"(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw "odd number of forms to cond")) (cons 'cond (rest (rest xs)))))))"#, &repl_env);
     let _ = rep(r#"(defmacro! or (fn* (& xs) (if (empty? xs) nil (if (= 1 (count xs)) (first xs) `(let* (or_FIXME ~(first xs)) (if or_FIXME or_FIXME (or ~@(rest xs))))))))"#, &repl_env);
 
@@ -100,18 +125,22 @@ fn read(str: &str) -> MalResult<MalForm> {
 
 fn eval_ast(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     Ok(match ast {
-        MalForm::Symbol(ref sym) => env.borrow().get(&sym)?.clone(),
-        MalForm::List(ref list) => {
+        MalForm::Symbol(ref sym, _) => env.borrow().get(&sym)?.clone(),
+        MalForm::List(ref list, _, _) => {
             let res: Result<Vec<_>, _> = list.into_iter().map(|x| eval(x, env)).collect();
-            MalForm::List(res?)
+            MalForm::List(res?, None, None)
         },
-        MalForm::Vector(ref list) => {
+        MalForm::Vector(ref list, _, _) => {
             let res: Result<Vec<_>, _> = list.into_iter().map(|x| eval(x, env)).collect();
-            MalForm::Vector(res?)
+            MalForm::Vector(res?, None, None)
+        },
+        MalForm::HashMap(ref hash, _, _) => {
+            let res: MalResult<MalMap> = hash.into_iter().map(|(k, v)| Ok((eval(k, env)?.to_key("hash-map")?, eval(v, env)?))).collect();
+            MalForm::HashMap(res?, None, None)
         },
-        MalForm::HashMap(ref hash) => {
-            let res: MalResult<HashMap<_, _>> = hash.into_iter().map(|(k, v)| Ok((k.clone(), eval(v, env)?))).collect();
-            MalForm::HashMap(res?)
+        MalForm::Set(ref set, _, _) => {
+            let res: MalResult<MalSet> = set.into_iter().map(|x| eval(x, env)?.to_set_element("hash-set")).collect();
+            MalForm::Set(res?, None, None)
         },
         x => x.clone(),
     })
@@ -119,21 +148,21 @@ fn eval_ast(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
 fn process_bindings(bindings_ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<()> {
     let vec = match bindings_ast {
-        MalForm::List(v) => v,
-        MalForm::Vector(v) => v,
+        MalForm::List(v, _, _) => v,
+        MalForm::Vector(v, _, _) => v,
         _ => return Err(MalError::EvalError(format!("'let*': bindings list must be either a list or vector, {} was given", bindings_ast))),
     };
 
     let mut b = vec.into_iter();
 
     while let Some(key_ast) = b.next() {
-        if let MalForm::Symbol(ref key) = key_ast {
+        if let MalForm::Symbol(ref key, _) = key_ast {
             let val_ast = b.next().ok_or(MalError::EvalError(format!("'let*': mising value for {}", key)))?;
             let val = eval(val_ast, env)?;
 
             env.borrow_mut().set(key.clone(), val.clone());
         } else {
-            return Err(MalError::EvalError(format!("'let*': binding name must be a symbol, {} was given", key_ast)));
+            return Err(MalError::EvalError(format!("'let*': binding name must be a symbol, {} was given", key_ast)).at(key_ast.span()));
         }
     }
 
@@ -142,7 +171,7 @@ fn process_bindings(bindings_ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult
 
 fn eval_def_(args: &[MalForm], env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     match args {
-        [MalForm::Symbol(name), val_ast] => {
+        [MalForm::Symbol(name, _), val_ast] => {
             let val = eval(val_ast, env)?;
             env.borrow_mut().set(name.clone(), val.clone());
             Ok(val)
@@ -154,7 +183,7 @@ fn eval_def_(args: &[MalForm], env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
 fn eval_defmacro_(args: &[MalForm], env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     match args {
-        [MalForm::Symbol(name), val_ast] => {
+        [MalForm::Symbol(name, _), val_ast] => {
             if let MalForm::MalFn(ref f) = eval(val_ast, env)? {
                 let mut m: MalFn = (**f).clone();
                 m.is_macro = true;
@@ -173,14 +202,14 @@ fn eval_defmacro_(args: &[MalForm], env: &Rc<RefCell<Env>>) -> MalResult<MalForm
 
 fn get_binds(form: &MalForm) -> MalResult<Vec<String>> {
     let v = match form {
-        MalForm::List(x) => x,
-        MalForm::Vector(x) => x,
+        MalForm::List(x, _, _) => x,
+        MalForm::Vector(x, _, _) => x,
         _ => return Err(MalError::EvalError(format!("'fn*' bindings list must be a list or vector, {} given", form))),
     };
 
     let res: MalResult<Vec<_>> = v.iter().map(|x| match x {
-        MalForm::Symbol(name) => Ok(name.clone()),
-        _ => Err(MalError::EvalError(format!("'fn*' bindings must be symbols, {} given", x))),
+        MalForm::Symbol(name, _) => Ok(name.clone()),
+        _ => Err(MalError::EvalError(format!("'fn*' bindings must be symbols, {} given", x)).at(x.span())),
     }).collect();
 
     res
@@ -197,53 +226,53 @@ fn eval_fn_(args: &[MalForm], env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
 fn is_pair(ast: &MalForm) -> bool {
     match ast {
-        MalForm::List(xs) => !xs.is_empty(),
-        MalForm::Vector(xs) => !xs.is_empty(),
+        MalForm::List(xs, _, _) => !xs.is_empty(),
+        MalForm::Vector(xs, _, _) => !xs.is_empty(),
         _ => false,
     }
 }
 
 fn quasiquote(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     if !is_pair(ast) {
-        return Ok(MalForm::List(vec![MalForm::Symbol("quote".to_string()), ast.clone()]));
+        return Ok(MalForm::List(vec![MalForm::Symbol("quote".to_string(), None), ast.clone()], None, None));
     }
 
     // xs contains at least 1 element
     let xs = ast.coerce_list().unwrap();
     let x = &xs[0];
 
-    if *x == MalForm::Symbol("unquote".to_string()) {
+    if *x == MalForm::Symbol("unquote".to_string(), None) {
         return xs.get(1).map(|x| x.clone()).ok_or(MalError::EvalError(format!("'unquote': argument required")));
     }
 
     if is_pair(x) {
         let ys = x.coerce_list().unwrap();
         let y = &ys[0];
-        if *y == MalForm::Symbol("splice-unquote".to_string()) {
+        if *y == MalForm::Symbol("splice-unquote".to_string(), None) {
             let res = vec![
-                MalForm::Symbol("concat".to_string()),
+                MalForm::Symbol("concat".to_string(), None),
                 ys.get(1)
                     .map(|x| x.clone())
                     .ok_or(MalError::EvalError(format!("'splice-unquote': at least one argument expected")))?,
-                quasiquote(&MalForm::List(xs.into_iter().skip(1).map(|x| x.clone()).collect()), env)?,
+                quasiquote(&MalForm::List(xs.into_iter().skip(1).map(|x| x.clone()).collect(), None, None), env)?,
             ];
-            return Ok(MalForm::List(res));
+            return Ok(MalForm::List(res, None, None));
         }
     }
 
     let res = vec![
-        MalForm::Symbol("cons".to_string()),
+        MalForm::Symbol("cons".to_string(), None),
         quasiquote(&x, env)?,
-        quasiquote(&MalForm::List(xs.into_iter().skip(1).map(|x| x.clone()).collect()), env)?,
+        quasiquote(&MalForm::List(xs.into_iter().skip(1).map(|x| x.clone()).collect(), None, None), env)?,
     ];
-    Ok(MalForm::List(res))
+    Ok(MalForm::List(res, None, None))
 }
 
 fn is_macro_call(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<bool> {
     match ast {
-        MalForm::List(xs) => {
+        MalForm::List(xs, _, _) => {
             match xs.get(0) {
-                Some(MalForm::Symbol(ref x)) => {
+                Some(MalForm::Symbol(ref x, _)) => {
                     match env.borrow().get(x) {
                         Ok(MalForm::MalFn(f)) => Ok(f.is_macro),
                         _ => Ok(false),
@@ -261,7 +290,7 @@ fn macroexpand(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
     while is_macro_call(&ast, env)? {
         let xs = ast.coerce_list().unwrap(); // should be here because is_macro_call is true
-        if let MalForm::Symbol(ref f_name) = xs[0] {
+        if let MalForm::Symbol(ref f_name, _) = xs[0] {
             let f_form = env.borrow().get(f_name).unwrap();
 
             match &f_form {
@@ -286,7 +315,7 @@ fn eval(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
     loop {
         // println!("Evaluating {}", ast);
 
-        if let MalForm::List(xs) = &ast {
+        if let MalForm::List(xs, _, _) = &ast {
             if xs.is_empty() {
                 return Ok(ast)
             }
@@ -296,16 +325,16 @@ fn eval(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
 
         ast = macroexpand(&ast, &env)?;
 
-        if let MalForm::List(xs) = &ast {
+        if let MalForm::List(xs, _, _) = &ast {
             if xs.is_empty() {
                 return Ok(ast)
             }
 
             let s = xs.as_slice();
             match &s[0] {
-                MalForm::Symbol(sym) if sym == "def!" => return eval_def_(&s[1..], &env),
-                MalForm::Symbol(sym) if sym == "defmacro!" => return eval_defmacro_(&s[1..], &env),
-                MalForm::Symbol(sym) if sym == "let*" =>
+                MalForm::Symbol(sym, _) if sym == "def!" => return eval_def_(&s[1..], &env),
+                MalForm::Symbol(sym, _) if sym == "defmacro!" => return eval_defmacro_(&s[1..], &env),
+                MalForm::Symbol(sym, _) if sym == "let*" =>
                     match &s[1..] {
                         [bindings_ast, value_ast] => {
                             let new_env = Rc::new(RefCell::new(Env::new(Some(env.clone()))));
@@ -317,7 +346,7 @@ fn eval(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
                         },
                         _ => return Err(MalError::EvalError("'let*' requires at least 2 arguments".to_string())),
                     },
-                MalForm::Symbol(sym) if sym == "do" => {
+                MalForm::Symbol(sym, _) if sym == "do" => {
                     for arg in &s[1 .. s.len()-1] {
                         let _ = eval(&arg, &env)?;
                     }
@@ -325,25 +354,25 @@ fn eval(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
                     ast = s[s.len() - 1].clone();
                     // tco
                 },
-                MalForm::Symbol(sym) if sym == "if" => {
+                MalForm::Symbol(sym, _) if sym == "if" => {
                     let cond_ast = s.get(1).ok_or(MalError::EvalError(format!("Missing condition for 'if'")))?;
                     let cond = eval(cond_ast, &env)?;
 
                     let i = match cond {
-                        MalForm::Bool(false) | MalForm::Nil => 3,
+                        MalForm::Bool(false, _) | MalForm::Nil(_) => 3,
                         _ => 2,
                     };
 
-                    ast = s.get(i).unwrap_or(&MalForm::Nil).clone();
+                    ast = s.get(i).unwrap_or(&MalForm::Nil(None)).clone();
                     // tco
                 },
-                MalForm::Symbol(sym) if sym == "quote" => {
+                MalForm::Symbol(sym, _) if sym == "quote" => {
                     match s.get(1) {
                         Some(x) => return Ok(x.clone()),
                         _ => return Err(MalError::EvalError(format!("'quote': must have an argument"))),
                     }
                 },
-                MalForm::Symbol(sym) if sym == "quasiquote" => {
+                MalForm::Symbol(sym, _) if sym == "quasiquote" => {
                     match s.get(1) {
                         Some(x) => {
                             ast = quasiquote(&x, &env)?;
@@ -352,15 +381,15 @@ fn eval(ast: &MalForm, env: &Rc<RefCell<Env>>) -> MalResult<MalForm> {
                         _ => return Err(MalError::EvalError(format!("'quasiquote': argument required"))),
                     }
                 },
-                MalForm::Symbol(sym) if sym == "macroexpand" => {
+                MalForm::Symbol(sym, _) if sym == "macroexpand" => {
                     return macroexpand(
                         s.get(1).ok_or(MalError::EvalError(format!("'macroexpand': argument required")))?,
                         &env);
                 },
-                MalForm::Symbol(sym) if sym == "fn*" => return eval_fn_(&s[1..], &env),
-                _ => if let MalForm::List(xs) = eval_ast(&ast, &env)? {
+                MalForm::Symbol(sym, _) if sym == "fn*" => return eval_fn_(&s[1..], &env),
+                _ => if let MalForm::List(xs, _, _) = eval_ast(&ast, &env)? {
                     match &xs[0] {
-                        MalForm::NativeFn(_, MalNativeFn(f)) => {
+                        MalForm::NativeFn(_, MalNativeFn(f), _) => {
                             let args = &xs[1 ..];
                             return f(args.to_vec(), &env);
                         },
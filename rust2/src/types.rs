@@ -9,36 +9,51 @@ pub trait ToMalForm {
 
 impl ToMalForm for bool {
     fn to_mal_form(&self) -> MalForm {
-        MalForm::Bool(*self)
+        MalForm::Bool(*self, None)
+    }
+}
+
+impl ToMalForm for i64 {
+    fn to_mal_form(&self) -> MalForm {
+        MalForm::Int(*self, None)
     }
 }
 
 impl ToMalForm for f64 {
     fn to_mal_form(&self) -> MalForm {
-        MalForm::Number(*self)
+        MalForm::Number(*self, None)
     }
 }
 
 impl ToMalForm for String {
     fn to_mal_form(&self) -> MalForm {
-        MalForm::Key(MalKey::String(self.clone()))
+        MalForm::Key(MalKey::String(self.clone()), None)
     }
 }
 
 impl ToMalForm for () {
     fn to_mal_form(&self) -> MalForm {
-        MalForm::Nil
+        MalForm::Nil(None)
+    }
+}
+
+impl<T: ToMalForm> ToMalForm for Option<T> {
+    fn to_mal_form(&self) -> MalForm {
+        match self {
+            Some(x) => x.to_mal_form(),
+            None => MalForm::Nil(None),
+        }
     }
 }
 
 impl ToMalForm for MalKey {
     fn to_mal_form(&self) -> MalForm {
-        MalForm::Key(self.clone())
+        MalForm::Key(self.clone(), None)
     }
 }
 
 impl ToMalForm for str {
     fn to_mal_form(&self) -> MalForm {
-        MalForm::Key(MalKey::String(String::from(self)))
+        MalForm::Key(MalKey::String(String::from(self)), None)
     }
 }
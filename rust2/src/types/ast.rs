@@ -1,23 +1,66 @@
+use std::cmp::Ordering;
 use std::fmt;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use indexmap::{IndexMap, IndexSet};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use regex::Regex;
+
+use crate::numeric;
+
+/// Hash-map entries, kept in insertion order so that printing, `keys` and
+/// `vals` come out the same way every run.
+pub type MalMap = IndexMap<MalForm, MalForm>;
+
+/// Set elements, likewise in insertion order.
+pub type MalSet = IndexSet<MalForm>;
+
+/// Metadata attached with `with-meta`. It plays no part in equality.
+pub type Meta = Option<Rc<MalForm>>;
 
 #[derive(Debug, Clone)]
 pub enum MalForm {
-    NativeFn(String, MalNativeFn),
+    NativeFn(String, MalNativeFn, Meta),
     MalFn(Rc<MalFn>),
-    List(Vec<MalForm>),
-    Vector(Vec<MalForm>),
-    HashMap(HashMap<MalKey, MalForm>),
-    Key(MalKey),
-    Number(f64),
-    Symbol(String),
-    Bool(bool),
-    Nil,
+    List(Vec<MalForm>, Option<Rc<Span>>, Meta),
+    Vector(Vec<MalForm>, Option<Rc<Span>>, Meta),
+    HashMap(MalMap, Option<Rc<Span>>, Meta),
+    Set(MalSet, Option<Rc<Span>>, Meta),
+    Key(MalKey, Option<Rc<Span>>),
+    /// An exact integer, as read from a literal such as `42`.
+    Int(i64, Option<Rc<Span>>),
+    /// Only for ints outside the range of `i64`.
+    Bignum(Rc<BigInt>, Option<Rc<Span>>),
+    /// Never with a denominator of 1.
+    Ratio(Rc<BigRational>, Option<Rc<Span>>),
+    /// A float; arithmetic with an exact number promotes it to one.
+    Number(f64, Option<Rc<Span>>),
+    Symbol(String, Option<Rc<Span>>),
+    /// A compiled pattern, as read from `#"..."`; equal to another with the
+    /// same text.
+    Regex(Rc<Regex>, Option<Rc<Span>>),
+    Bool(bool, Option<Rc<Span>>),
+    Nil(Option<Rc<Span>>),
     Atom(Rc<RefCell<MalForm>>),
 }
 
+/// Location of a form in the source it was read from.
+///
+/// `line` and `column` are 1-based, `start` and `end` are byte offsets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: Option<Rc<str>>,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
 #[derive(Clone)]
 pub struct MalNativeFn(pub Rc<Fn(Vec<MalForm>, &Rc<RefCell<Env>>) -> MalResult<MalForm>>);
 
@@ -28,6 +71,12 @@ pub struct MalFn {
     pub env: Rc<RefCell<Env>>,
     pub is_macro: bool,
     pub fn_: MalNativeFn,
+    /// The name the function was first defined as.
+    pub name: Option<String>,
+    pub doc: Option<String>,
+    /// Where the `fn*` form was read from.
+    pub span: Option<Rc<Span>>,
+    pub meta: Meta,
 }
 
 impl MalFn {
@@ -42,6 +91,10 @@ impl MalFn {
 
                 eval(&body, &env)
             })),
+            name: None,
+            doc: None,
+            span: None,
+            meta: None,
         }
     }
 }
@@ -55,19 +108,119 @@ pub enum MalKey {
 impl MalForm {
     pub fn coerce_list(&self) -> Option<&Vec<MalForm>> {
         match self {
-            MalForm::List(v) => Some(v),
-            MalForm::Vector(v) => Some(v),
+            MalForm::List(v, _, _) => Some(v),
+            MalForm::Vector(v, _, _) => Some(v),
             _ => None,
         }
     }
 
     pub fn coerce_list_mut(&mut self) -> Option<&mut Vec<MalForm>> {
         match self {
-            MalForm::List(v) => Some(v),
-            MalForm::Vector(v) => Some(v),
+            MalForm::List(v, _, _) => Some(v),
+            MalForm::Vector(v, _, _) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        match self {
+            MalForm::Int(_, _) | MalForm::Bignum(_, _) | MalForm::Ratio(_, _) | MalForm::Number(_, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the form can be a hash-map key or a set element: anything
+    /// compared by value, so not atoms or functions, nor collections holding
+    /// them.
+    pub fn is_hashable(&self) -> bool {
+        self.unhashable().is_none()
+    }
+
+    /// What keeps the form from being a hash-map key, found in it or
+    /// anywhere inside it: "an atom" or "a function". Regexes are compared
+    /// by their pattern, so they can be keys.
+    pub fn unhashable(&self) -> Option<&'static str> {
+        match self {
+            MalForm::List(xs, _, _) | MalForm::Vector(xs, _, _) => xs.iter().filter_map(MalForm::unhashable).next(),
+            MalForm::HashMap(hm, _, _) => hm.values().filter_map(MalForm::unhashable).next(),
+            MalForm::NativeFn(..) | MalForm::MalFn(_) => Some("a function"),
+            MalForm::Atom(_) => Some("an atom"),
             _ => None,
         }
     }
+
+    /// The form as a hash-map key for `name`, or an error if it cannot be one.
+    pub fn to_key(&self, name: &str) -> MalResult<MalForm> {
+        self.hashable(name, "key")
+    }
+
+    /// The form as a set element for `name`, or an error if it cannot be one.
+    pub fn to_set_element(&self, name: &str) -> MalResult<MalForm> {
+        self.hashable(name, "set element")
+    }
+
+    fn hashable(&self, name: &str, what: &str) -> MalResult<MalForm> {
+        // The form itself is left out of the message, which names what is
+        // wrong with it instead.
+        match (self.unhashable(), self) {
+            (None, _) => Ok(self.clone()),
+            (Some(kind), MalForm::List(..)) | (Some(kind), MalForm::Vector(..)) | (Some(kind), MalForm::HashMap(..)) =>
+                Err(MalError::EvalError(format!("'{}': {} cannot contain {}", name, what, kind))),
+            (Some(kind), _) => Err(MalError::EvalError(format!("'{}': {} cannot be {}", name, what, kind))),
+        }
+    }
+
+    /// The form's metadata, nil if it has none.
+    pub fn meta(&self) -> MalResult<MalForm> {
+        let meta = match self {
+            MalForm::List(_, _, meta) | MalForm::Vector(_, _, meta) |
+            MalForm::HashMap(_, _, meta) | MalForm::Set(_, _, meta) |
+            MalForm::NativeFn(_, _, meta) => meta,
+            MalForm::MalFn(f) => &f.meta,
+            _ => return Err(MalError::EvalError(format!("'meta': {} cannot have metadata", self))),
+        };
+        Ok(meta.as_ref().map_or(MalForm::Nil(None), |m| (**m).clone()))
+    }
+
+    /// A copy of the form with `meta` as its metadata; the form itself is
+    /// left as it was.
+    pub fn with_meta(&self, meta: MalForm) -> MalResult<MalForm> {
+        let mut res = self.clone();
+        let slot = match &mut res {
+            MalForm::List(_, _, meta) | MalForm::Vector(_, _, meta) |
+            MalForm::HashMap(_, _, meta) | MalForm::Set(_, _, meta) |
+            MalForm::NativeFn(_, _, meta) => meta,
+            MalForm::MalFn(f) => &mut Rc::make_mut(f).meta,
+            _ => return Err(MalError::EvalError(format!("'with-meta': {} cannot have metadata", self))),
+        };
+        *slot = Some(Rc::new(meta));
+        Ok(res)
+    }
+
+    /// Source location the form was read from, if any.
+    pub fn span(&self) -> Option<&Rc<Span>> {
+        match self {
+            MalForm::List(_, span, _) | MalForm::Vector(_, span, _) |
+            MalForm::HashMap(_, span, _) | MalForm::Set(_, span, _) |
+            MalForm::Key(_, span) | MalForm::Int(_, span) | MalForm::Bignum(_, span) |
+            MalForm::Ratio(_, span) | MalForm::Number(_, span) | MalForm::Symbol(_, span) |
+            MalForm::Regex(_, span) | MalForm::Bool(_, span) | MalForm::Nil(span) => span.as_ref(),
+            MalForm::NativeFn(..) | MalForm::MalFn(_) | MalForm::Atom(_) => None,
+        }
+    }
+
+    /// The form with `span` as its source location, for forms that have one.
+    pub fn with_span(mut self, span: Rc<Span>) -> MalForm {
+        match &mut self {
+            MalForm::List(_, slot, _) | MalForm::Vector(_, slot, _) |
+            MalForm::HashMap(_, slot, _) | MalForm::Set(_, slot, _) |
+            MalForm::Key(_, slot) | MalForm::Int(_, slot) | MalForm::Bignum(_, slot) |
+            MalForm::Ratio(_, slot) | MalForm::Number(_, slot) | MalForm::Symbol(_, slot) |
+            MalForm::Regex(_, slot) | MalForm::Bool(_, slot) | MalForm::Nil(slot) => *slot = Some(span),
+            MalForm::NativeFn(..) | MalForm::MalFn(_) | MalForm::Atom(_) => (),
+        }
+        self
+    }
 }
 
 impl PartialEq<MalNativeFn> for MalNativeFn {
@@ -84,20 +237,90 @@ impl fmt::Debug for MalNativeFn {
 
 impl PartialEq<MalForm> for MalForm {
     fn eq(&self, other: &MalForm) -> bool {
-        if let ml1@Some(_) = self.coerce_list() {
-            return ml1 == other.coerce_list();
-        }
-
-        match (self, other) {
-            (MalForm::NativeFn(_, f1), MalForm::NativeFn(_, f2)) => f1 == f2,
-            (MalForm::HashMap(h1), MalForm::HashMap(h2)) => h1 == h2,
-            (MalForm::Key(a1), MalForm::Key(a2)) => a1 == a2,
-            (MalForm::Number(a1), MalForm::Number(a2)) => a1 == a2,
-            (MalForm::Symbol(a1), MalForm::Symbol(a2)) => a1 == a2,
-            (MalForm::Bool(a1), MalForm::Bool(a2)) => a1 == a2,
-            (MalForm::Nil, MalForm::Nil) => true,
+        equal(self, other, &mut vec![])
+    }
+}
+
+impl Eq for MalForm {}
+
+/// Consistent with `equal`: numbers hash by their exact value, lists and
+/// vectors alike, and maps and sets without regard to order.
+impl Hash for MalForm {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            MalForm::Nil(_) => 0.hash(state),
+            MalForm::Bool(b, _) => { 1.hash(state); b.hash(state) },
+            x if x.is_number() => {
+                2.hash(state);
+                numeric::hash(x, state);
+            },
+            MalForm::Key(k, _) => { 3.hash(state); k.hash(state) },
+            MalForm::Symbol(s, _) => { 4.hash(state); s.hash(state) },
+            MalForm::Regex(re, _) => { 9.hash(state); re.as_str().hash(state) },
+            MalForm::List(xs, _, _) | MalForm::Vector(xs, _, _) => {
+                5.hash(state);
+                xs.hash(state);
+            },
+            MalForm::HashMap(hm, _, _) => {
+                6.hash(state);
+                unordered_hash(hm.iter()).hash(state);
+            },
+            MalForm::Set(s, _, _) => {
+                8.hash(state);
+                unordered_hash(s.iter()).hash(state);
+            },
+            // never keys, see is_hashable
+            _ => 7.hash(state),
+        }
+    }
+}
+
+/// Sum of the items' hashes, which doesn't depend on their order.
+fn unordered_hash<T: Hash, I: Iterator<Item = T>>(items: I) -> u64 {
+    items.fold(0u64, |sum, x| {
+        let mut h = DefaultHasher::new();
+        x.hash(&mut h);
+        sum.wrapping_add(h.finish())
+    })
+}
+
+/// Atoms are equal when they hold equal values. A pair of atoms already
+/// being compared further out is taken to be equal, so comparing cyclic
+/// values ends.
+fn equal(a: &MalForm, b: &MalForm, comparing: &mut Vec<(*const RefCell<MalForm>, *const RefCell<MalForm>)>) -> bool {
+    if let (Some(l1), Some(l2)) = (a.coerce_list(), b.coerce_list()) {
+        return l1.len() == l2.len() && l1.iter().zip(l2).all(|(a, b)| equal(a, b, comparing));
+    }
+
+    match (a, b) {
+        (MalForm::NativeFn(_, f1, _), MalForm::NativeFn(_, f2, _)) => f1 == f2,
+        (MalForm::HashMap(h1, _, _), MalForm::HashMap(h2, _, _)) => {
+            h1.len() == h2.len() && h1.iter().all(|(k, v1)| match h2.get(k) {
+                Some(v2) => equal(v1, v2, comparing),
+                None => false,
+            })
+        }
+        (MalForm::Set(s1, _, _), MalForm::Set(s2, _, _)) => s1.len() == s2.len() && s1.is_subset(s2),
+        (MalForm::Key(a1, _), MalForm::Key(a2, _)) => a1 == a2,
+        (a, b) if a.is_number() && b.is_number() => match numeric::compare("=", a, b) {
+            Ok(Some(Ordering::Equal)) => true,
             _ => false,
+        },
+        (MalForm::Symbol(a1, _), MalForm::Symbol(a2, _)) => a1 == a2,
+        (MalForm::Regex(a1, _), MalForm::Regex(a2, _)) => a1.as_str() == a2.as_str(),
+        (MalForm::Bool(a1, _), MalForm::Bool(a2, _)) => a1 == a2,
+        (MalForm::Nil(_), MalForm::Nil(_)) => true,
+        (MalForm::Atom(a1), MalForm::Atom(a2)) => {
+            let pair = (&**a1 as *const RefCell<MalForm>, &**a2 as *const RefCell<MalForm>);
+            if Rc::ptr_eq(a1, a2) || comparing.contains(&pair) {
+                return true;
+            }
+            comparing.push(pair);
+            let res = equal(&a1.borrow(), &a2.borrow(), comparing);
+            comparing.pop();
+            res
         }
+        _ => false,
     }
 }
 
@@ -106,17 +329,89 @@ pub enum MalError {
     ParseError(lalrpop_util::ParseError<usize, (usize, String), &'static str>),
     EvalError(String),
     MalException(MalForm),
+    Located(Rc<Span>, Box<MalError>),
+    /// Everything wrong with some input, each error with its location.
+    ParseErrors(Vec<MalError>),
 }
 
 pub type MalResult<T> = Result<T, MalError>;
 
+impl MalError {
+    /// Attaches `span` to the error unless it already knows where it happened.
+    pub fn at(self, span: Option<&Rc<Span>>) -> MalError {
+        match (self, span) {
+            (err@MalError::Located(..), _) => err,
+            (err@MalError::ParseErrors(..), _) => err,
+            (err, Some(span)) => MalError::Located(span.clone(), Box::new(err)),
+            (err, None) => err,
+        }
+    }
+
+    /// The error with its location stripped.
+    pub fn unlocated(self) -> MalError {
+        match self {
+            MalError::Located(_, err) => *err,
+            err => err,
+        }
+    }
+}
+
+/// A parse error without lalrpop's byte offsets, which `Located` gives as a
+/// line and column instead, and with its expected tokens shortened.
+fn describe_parse_error(err: &lalrpop_util::ParseError<usize, (usize, String), &'static str>) -> String {
+    use lalrpop_util::ParseError;
+    match err {
+        ParseError::InvalidToken { .. } => "invalid token".to_string(),
+        ParseError::UnrecognizedToken { token: Some((_, (_, token), _)), expected } =>
+            format!("unexpected `{}`{}", token, describe_expected(expected)),
+        ParseError::UnrecognizedToken { token: None, expected } =>
+            format!("unexpected end of input{}", describe_expected(expected)),
+        ParseError::ExtraToken { token: (_, (_, token), _) } => format!("unexpected `{}`", token),
+        ParseError::User { error } => error.to_string(),
+    }
+}
+
+/// Closing brackets by name and "a form" for every token that can start one;
+/// whitespace and comments can go anywhere, so they aren't worth listing.
+fn describe_expected(expected: &[String]) -> String {
+    let mut names = Vec::new();
+    let mut form = false;
+    for token in expected {
+        match token.as_str() {
+            r#"")""# | r#""]""# | r#""}""# => names.push(format!("`{}`", token.trim_matches('"'))),
+            r###"r#";.*"#"### | r###"r#"[\\s,]+"#"### => (),
+            _ => form = true,
+        }
+    }
+    if form {
+        names.insert(0, "a form".to_string());
+    }
+    match names.len() {
+        0 => String::new(),
+        _ => format!(", expected {}", names.join(" or ")),
+    }
+}
+
 impl fmt::Display for MalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            MalError::ParseError(err) =>
-                write!(f, "{}", err.clone().map_token(|(_size,s)| s)),
+            MalError::ParseError(err) => write!(f, "{}", describe_parse_error(err)),
             MalError::EvalError(msg) => write!(f, "Evaluation Error: {}", msg),
             MalError::MalException(form) => write!(f, "Exception: {}", form),
+            MalError::Located(span, err) => write!(f, "{}: {}", span, err),
+            MalError::ParseErrors(errs) => {
+                let errs: Vec<String> = errs.iter().map(|err| err.to_string()).collect();
+                write!(f, "{}", errs.join("\n"))
+            },
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.line, self.column),
+            None => write!(f, "{}:{}", self.line, self.column),
         }
     }
 }
@@ -146,7 +441,7 @@ impl Env {
         while let Some((i, key)) = it.next() {
             if key == "&" {
                 let (_, next_key) = it.next().ok_or(MalError::EvalError(format!("& requires next argument")))?;
-                env.set(next_key.clone(), MalForm::List(Vec::from(&exprs[i ..])));
+                env.set(next_key.clone(), MalForm::List(Vec::from(&exprs[i ..]), None, None));
                 break;
             }
 
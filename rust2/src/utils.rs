@@ -1,18 +1,104 @@
-pub fn unescape(s: &str) -> String {
+use std::rc::Rc;
+use std::str::Chars;
+
+use crate::types::Span;
+
+const INVALID_UNICODE_ESCAPE: &str = "Invalid unicode escape";
+
+/// Reads the string literal `s`, quotes included, resolving its escapes. An
+/// error comes with the byte offset in `s` of the bad escape.
+pub fn unescape(s: &str) -> Result<String, (String, usize)> {
     let mut res = String::new();
 
-    let mut i = s[1 .. s.len()-1].chars();
+    let body = &s[1 .. s.len()-1];
+    let mut i = body.chars();
     while let Some(c) = i.next() {
         if c != '\\' {
             res.push(c);
         } else {
+            let at = 1 + body.len() - i.as_str().len() - 1;
             match i.next() {
                 Some('n') => res.push('\n'),
-                Some(c) => res.push(c),
-                None => (),
+                Some('t') => res.push('\t'),
+                Some('r') => res.push('\r'),
+                Some('0') => res.push('\0'),
+                Some('\\') => res.push('\\'),
+                Some('"') => res.push('"'),
+                Some('u') => res.push(unicode_escape(&mut i).ok_or((INVALID_UNICODE_ESCAPE.to_string(), at))?),
+                Some(c) => return Err((format!("Unknown escape '\\{}'", c), at)),
+                None => return Err(("Unknown escape '\\'".to_string(), at)),
             }
         }
     }
 
-    res
+    Ok(res)
+}
+
+/// Between 1 and `max` hex digits.
+fn parse_hex(hex: &str, max: usize) -> Option<u32> {
+    if hex.is_empty() || hex.len() > max || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// `\uXXXX`, joining surrogate pairs, or `\u{X...}`; `i` is just past the `\u`.
+fn unicode_escape(i: &mut Chars) -> Option<char> {
+    let rest = i.as_str();
+    let (code, len) = if rest.starts_with('{') {
+        let end = rest.find('}')?;
+        (parse_hex(&rest[1..end], 6)?, end + 1)
+    } else {
+        let hi = rest.get(..4).and_then(|h| parse_hex(h, 4))?;
+        let lo = rest.get(4..10)
+            .filter(|l| l.starts_with("\\u"))
+            .and_then(|l| parse_hex(&l[2..], 4));
+        match lo {
+            Some(lo) if (0xD800..0xDC00).contains(&hi) && (0xDC00..0xE000).contains(&lo) =>
+                (0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00), 10),
+            _ => (hi, 4),
+        }
+    };
+    *i = rest[len..].chars();
+    char::from_u32(code)
+}
+
+/// Text being read, with enough bookkeeping to turn byte offsets into spans.
+pub struct Source<'a> {
+    text: &'a str,
+    file: Option<Rc<str>>,
+    line_starts: Vec<usize>,
+}
+
+impl<'a> Source<'a> {
+    pub fn new(text: &'a str, file: Option<&str>) -> Source<'a> {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+
+        Source {
+            text,
+            file: file.map(Rc::from),
+            line_starts,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    pub fn span(&self, start: usize, end: usize) -> Rc<Span> {
+        let line = match self.line_starts.binary_search(&start) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let line_start = self.line_starts[line];
+
+        Rc::new(Span {
+            file: self.file.clone(),
+            start,
+            end,
+            line: line + 1,
+            column: self.text[line_start .. start].chars().count() + 1,
+        })
+    }
 }